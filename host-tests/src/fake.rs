@@ -0,0 +1,297 @@
+//! An in-memory connection between the client and a scripted broker.
+//!
+//! The broker side is driven by the test itself: it reads whatever the client sent with
+//! `expect()`, and answers with `send()` or one of the packet helpers.
+
+use std::cell::Cell;
+
+use embassy_futures::{
+    select::{select, Either},
+    yield_now,
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pipe::Pipe};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+
+use crate::mqtt::packet::{encode_publish, frame, Property, QualityOfService};
+
+pub const CONNECT: u8 = 1;
+pub const PUBLISH: u8 = 3;
+pub const PUBACK: u8 = 4;
+pub const PUBREC: u8 = 5;
+pub const PUBREL: u8 = 6;
+pub const PUBCOMP: u8 = 7;
+pub const SUBSCRIBE: u8 = 8;
+pub const PINGREQ: u8 = 12;
+pub const DISCONNECT: u8 = 14;
+
+type Buffer = Pipe<CriticalSectionRawMutex, 4096>;
+
+/// Both directions of the connection, plus switches to break it
+pub struct Link {
+    to_broker: Buffer,
+    to_client: Buffer,
+    /// the broker has gone away: reads return 0 once drained
+    closed: Cell<bool>,
+    /// writes from the client fail, e.g. the socket died under us
+    broken: Cell<bool>,
+}
+
+impl Default for Link {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Link {
+    pub fn new() -> Self {
+        Self {
+            to_broker: Pipe::new(),
+            to_client: Pipe::new(),
+            closed: Cell::new(false),
+            broken: Cell::new(false),
+        }
+    }
+
+    pub fn client(&self) -> ClientEnd<'_> {
+        ClientEnd { link: self }
+    }
+
+    pub fn broker(&self) -> Broker<'_> {
+        Broker { link: self, rx: Vec::new() }
+    }
+
+    pub fn close(&self) {
+        self.closed.set(true);
+    }
+
+    pub fn break_writes(&self) {
+        self.broken.set(true);
+    }
+
+    /// Fill the client -> broker direction with junk until only `free` bytes of room are left,
+    /// so the client's next write stalls part way. Returns how much junk the broker will read first.
+    pub fn clog(&self, free: usize) -> usize {
+        let junk = self.to_broker.free_capacity() - free;
+        let mut written = 0;
+        //the pipe is a ring, a write only fills up to its end
+        while written < junk {
+            written += self.to_broker.try_write(&vec![0xff; junk - written]).unwrap();
+        }
+        junk
+    }
+}
+
+#[derive(Debug)]
+pub struct LinkDown;
+
+impl embedded_io_async::Error for LinkDown {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::BrokenPipe
+    }
+}
+
+/// The transport handed to `MqttClient`
+pub struct ClientEnd<'a> {
+    link: &'a Link,
+}
+
+impl ErrorType for ClientEnd<'_> {
+    type Error = LinkDown;
+}
+
+impl Read for ClientEnd<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, LinkDown> {
+        loop {
+            match self.link.to_client.try_read(buf) {
+                Ok(read) => return Ok(read),
+                Err(_) if self.link.closed.get() => return Ok(0),
+                Err(_) => yield_now().await,
+            }
+        }
+    }
+}
+
+impl Write for ClientEnd<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, LinkDown> {
+        if self.link.broken.get() || self.link.closed.get() {
+            return Err(LinkDown);
+        }
+        Ok(self.link.to_broker.write(buf).await)
+    }
+}
+
+/// One whole packet as the broker received it
+#[derive(Debug, Clone)]
+pub struct Received {
+    pub kind: u8,
+    pub flags: u8,
+    pub bytes: Vec<u8>,
+}
+
+impl Received {
+    /// Packet id of a SUBSCRIBE/PUBACK/PUBREL... (the first two bytes after the fixed header)
+    pub fn pid(&self) -> u16 {
+        let (header_len, _) = frame(&self.bytes).unwrap().unwrap();
+        u16::from_be_bytes([self.bytes[header_len], self.bytes[header_len + 1]])
+    }
+
+    fn body(&self) -> &[u8] {
+        let (header_len, len) = frame(&self.bytes).unwrap().unwrap();
+        &self.bytes[header_len..header_len + len]
+    }
+
+    /// Topic and payload of a PUBLISH (properties are skipped, not parsed)
+    pub fn publish(&self) -> (String, Vec<u8>) {
+        let body = self.body();
+        let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+        let topic = String::from_utf8(body[2..2 + topic_len].to_vec()).unwrap();
+        let mut pos = 2 + topic_len;
+        if (self.flags >> 1) & 0x03 > 0 {
+            pos += 2;
+        }
+        //properties length is a varint, all ours are short enough for one byte
+        pos += 1 + body[pos] as usize;
+        (topic, body[pos..].to_vec())
+    }
+
+    /// The raw properties of a PUBLISH (feed to `Properties::from_raw`)
+    pub fn publish_properties(&self) -> Vec<u8> {
+        let body = self.body();
+        let mut pos = 2 + u16::from_be_bytes([body[0], body[1]]) as usize;
+        if (self.flags >> 1) & 0x03 > 0 {
+            pos += 2;
+        }
+        body[pos + 1..pos + 1 + body[pos] as usize].to_vec()
+    }
+
+    /// Packet id of a QoS1/QoS2 PUBLISH (it follows the topic)
+    pub fn publish_pid(&self) -> u16 {
+        let body = self.body();
+        let pos = 2 + u16::from_be_bytes([body[0], body[1]]) as usize;
+        u16::from_be_bytes([body[pos], body[pos + 1]])
+    }
+}
+
+pub struct Broker<'a> {
+    link: &'a Link,
+    rx: Vec<u8>,
+}
+
+impl Broker<'_> {
+    /// Wait for the next packet from the client
+    pub async fn next(&mut self) -> Received {
+        loop {
+            if let Ok(Some((header_len, len))) = frame(&self.rx) {
+                if self.rx.len() >= header_len + len {
+                    let bytes: Vec<u8> = self.rx.drain(..header_len + len).collect();
+                    return Received { kind: bytes[0] >> 4, flags: bytes[0] & 0x0f, bytes };
+                }
+            }
+            self.fill().await;
+        }
+    }
+
+    /// Wait for exactly `len` raw bytes from the client, packet boundaries or not
+    pub async fn read_exact(&mut self, len: usize) -> Vec<u8> {
+        while self.rx.len() < len {
+            self.fill().await;
+        }
+        self.rx.drain(..len).collect()
+    }
+
+    /// Wait for raw bytes from the client up to and including `end`
+    pub async fn read_until(&mut self, end: &[u8]) -> Vec<u8> {
+        loop {
+            if let Some(pos) = self.rx.windows(end.len()).position(|window| window == end) {
+                return self.rx.drain(..pos + end.len()).collect();
+            }
+            self.fill().await;
+        }
+    }
+
+    async fn fill(&mut self) {
+        let mut buf = [0u8; 256];
+        let read = self.link.to_broker.read(&mut buf).await;
+        self.rx.extend_from_slice(&buf[..read]);
+    }
+
+    /// Wait for the next packet, which must be of type `kind`
+    pub async fn expect(&mut self, kind: u8) -> Received {
+        let packet = self.next().await;
+        assert_eq!(packet.kind, kind, "expected packet type {} got {:?}", kind, packet);
+        packet
+    }
+
+    /// Let `duration` pass, answering pings as a real broker would (anything else is unexpected)
+    pub async fn idle(&mut self, duration: Duration) {
+        let until = Instant::now() + duration;
+        loop {
+            match select(self.next(), Timer::at(until)).await {
+                Either::First(packet) if packet.kind == PINGREQ => self.pingresp().await,
+                Either::First(packet) => panic!("unexpected packet while idle: {:?}", packet),
+                Either::Second(()) => return,
+            }
+        }
+    }
+
+    pub async fn send(&mut self, bytes: &[u8]) {
+        self.link.to_client.write_all(bytes).await;
+    }
+
+    /// CONNECT -> CONNACK, then SUBSCRIBE -> SUBACK granting everything
+    pub async fn accept(&mut self) -> Received {
+        let connect = self.expect(CONNECT).await;
+        self.connack(0).await;
+        self.grant_subscribe().await;
+        connect
+    }
+
+    /// `accept()`, with the CONNACK limiting how many QoS1/QoS2 publishes we may have in flight
+    pub async fn accept_limited(&mut self, receive_maximum: u16) -> Received {
+        let connect = self.expect(CONNECT).await;
+        let [high, low] = receive_maximum.to_be_bytes();
+        self.send(&[0x20, 6, 0, 0, 3, 0x21, high, low]).await;
+        self.grant_subscribe().await;
+        connect
+    }
+
+    async fn grant_subscribe(&mut self) {
+        let subscribe = self.expect(SUBSCRIBE).await;
+        self.send(&[0x90, 4, (subscribe.pid() >> 8) as u8, subscribe.pid() as u8, 0, 0x01]).await;
+    }
+
+    pub async fn connack(&mut self, reason: u8) {
+        self.send(&[0x20, 3, 0, reason, 0]).await;
+    }
+
+    pub async fn publish(&mut self, topic: &str, payload: &[u8], qos: QualityOfService, retain: bool, pid: u16) {
+        self.publish_with(topic, payload, qos, retain, pid, &[]).await;
+    }
+
+    pub async fn publish_with(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QualityOfService,
+        retain: bool,
+        pid: u16,
+        properties: &[Property<'_>],
+    ) {
+        let mut buf = [0u8; 1024];
+        let range = encode_publish(&mut buf, topic, payload, qos, retain, false, pid, properties).unwrap();
+        self.send(&buf[range]).await;
+    }
+
+    pub async fn ack(&mut self, kind: u8, pid: u16) {
+        self.send(&[kind << 4 | if kind == PUBREL { 0x02 } else { 0 }, 2, (pid >> 8) as u8, pid as u8]).await;
+    }
+
+    pub async fn pingresp(&mut self) {
+        self.send(&[0xd0, 0]).await;
+    }
+
+    pub async fn disconnect(&mut self, reason: u8) {
+        self.send(&[0xe0, 1, reason]).await;
+    }
+}
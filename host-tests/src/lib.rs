@@ -0,0 +1,53 @@
+//! The firmware's MQTT code (client, outbox, router, session...), built for the host so it
+//! can be exercised against a fake in-process broker or a real local one - and the
+//! hardware-free parts of provisioning (settings record, form, DHCP and DNS), of picking
+//! a Wi-Fi network, IPv6 addressing and network status, mDNS broker discovery, button gestures
+//! and signed command replay protection.
+
+#[path = "../../src/mqtt"]
+#[allow(dead_code)]
+pub mod mqtt {
+    pub mod client;
+    pub mod failover;
+    pub mod health;
+    pub mod limit;
+    pub mod outbox;
+    pub mod packet;
+    pub mod router;
+    pub mod session;
+    pub mod ws;
+}
+
+#[path = "../../src/input"]
+pub mod input {
+    pub mod gestures;
+}
+
+#[path = "../../src/mdns"]
+pub mod mdns {
+    pub mod browse;
+}
+
+#[path = "../../src/provision"]
+#[allow(dead_code)]
+pub mod provision {
+    pub mod dhcp;
+    pub mod dns;
+    pub mod form;
+    pub mod settings;
+}
+
+#[path = "../../src/signing"]
+pub mod signing {
+    pub mod replay;
+}
+
+#[path = "../../src/wireless"]
+pub mod wireless {
+    pub mod ipv6;
+    pub mod networks;
+    pub mod status;
+}
+
+pub mod fake;
+pub mod tcp;
@@ -0,0 +1,60 @@
+//! A plain TCP connection to a real broker (e.g. a local mosquitto), for `MqttClient`.
+
+use std::{
+    io::{self, Read as _, Write as _},
+    net::TcpStream,
+};
+
+use embassy_futures::yield_now;
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+
+pub struct Tcp(TcpStream);
+
+impl Tcp {
+    pub fn connect(addr: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        //never block the executor - a pending read yields so timers keep running
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(Self(stream))
+    }
+}
+
+#[derive(Debug)]
+pub struct TcpError(io::ErrorKind);
+
+impl embedded_io_async::Error for TcpError {
+    fn kind(&self) -> ErrorKind {
+        match self.0 {
+            io::ErrorKind::ConnectionReset => ErrorKind::ConnectionReset,
+            io::ErrorKind::BrokenPipe => ErrorKind::BrokenPipe,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl ErrorType for Tcp {
+    type Error = TcpError;
+}
+
+impl Read for Tcp {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, TcpError> {
+        loop {
+            match self.0.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => yield_now().await,
+                result => return result.map_err(|e| TcpError(e.kind())),
+            }
+        }
+    }
+}
+
+impl Write for Tcp {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, TcpError> {
+        loop {
+            match self.0.write(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => yield_now().await,
+                result => return result.map_err(|e| TcpError(e.kind())),
+            }
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! The firmware's broker session against a real broker, e.g. a local mosquitto:
+//!
+//! MQTT_TEST_BROKER=127.0.0.1:1883 cargo test --test broker
+//!
+//! Without MQTT_TEST_BROKER these tests pass without doing anything.
+
+use std::cell::RefCell;
+
+use critical_section::Mutex;
+use embassy_futures::block_on;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant};
+use mqtt_host_tests::{
+    mqtt::{
+        client::MqttClient,
+        health::HealthPolicy,
+        limit::Limiter,
+        outbox::{Outbox, Outgoing},
+        packet::{Connect, Property, QualityOfService},
+        router::{HandlerError, Message, Router},
+        session::{End, Event, Hooks, Rejection, Session},
+    },
+    tcp::Tcp,
+};
+
+thread_local! {
+    static HANDLED: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+fn record(msg: &Message<'_>) -> Result<(), HandlerError> {
+    HANDLED.with(|handled| handled.borrow_mut().push(msg.payload.into()));
+    Ok(())
+}
+
+fn reject(_: &Message<'_>) -> Result<(), HandlerError> {
+    Err(HandlerError::Rejected("not today"))
+}
+
+fn broker() -> Option<String> {
+    let broker = std::env::var("MQTT_TEST_BROKER").ok();
+    if broker.is_none() {
+        eprintln!("MQTT_TEST_BROKER not set, skipping");
+    }
+    broker
+}
+
+/// A topic prefix no other run (or test) will be using
+fn prefix(test: &str) -> String {
+    format!("host-tests/{}/{}", std::process::id(), test)
+}
+
+struct Until {
+    deadline: Instant,
+    received: usize,
+    rejected: Vec<Rejection>,
+    stop_after: usize,
+}
+
+impl Until {
+    fn new(stop_after: usize) -> Self {
+        Self { deadline: Instant::now() + Duration::from_secs(5), received: 0, rejected: Vec::new(), stop_after }
+    }
+}
+
+impl Hooks for Until {
+    fn event(&mut self, event: Event<'_>) {
+        match event {
+            Event::Received(_) => self.received += 1,
+            Event::Rejected { reason, .. } => self.rejected.push(reason),
+            Event::SubscriptionRefused(code) => panic!("broker refused subscription: 0x{:02x}", code),
+            _ => {},
+        }
+    }
+
+    fn stop(&mut self) -> bool {
+        self.received >= self.stop_after || Instant::now() > self.deadline
+    }
+}
+
+fn session_against(addr: &str, router: &Router<4>, outbox: &Mutex<RefCell<Outbox<8>>>, ping: Duration, hooks: &mut Until) -> End {
+    let signal = Signal::<CriticalSectionRawMutex, ()>::new();
+    let limiter = RefCell::new(Limiter::new());
+    let health = HealthPolicy { pong_timeout: Duration::from_secs(5), max_missed: 2 };
+    let session = Session { router, outbox, outbox_signal: &signal, ping_interval: ping, health, birth: &[], limiter: &limiter };
+
+    let client_id = format!("host-tests-{}", std::process::id());
+    let properties = [Property::ReceiveMaximum(8), Property::MaximumPacketSize(1024)];
+    let connect = Connect {
+        client_id: &client_id,
+        username: None,
+        password: None,
+        keep_alive: 60,
+        clean_start: true,
+        properties: &properties,
+        will: None,
+    };
+
+    let (mut tx, mut rx) = ([0u8; 1024], [0u8; 1024]);
+    let mut client = MqttClient::new(Tcp::connect(addr).expect("can't reach MQTT_TEST_BROKER"), &mut tx, &mut rx);
+    let end = block_on(session.run(&mut client, &connect, hooks));
+    _ = block_on(client.disconnect());
+    end
+}
+
+#[test]
+fn round_trip_through_broker() {
+    let Some(addr) = broker() else { return };
+    let prefix = prefix("round_trip");
+    let topic: &'static str = format!("{}/echo", prefix).leak();
+    let rejecting: &'static str = format!("{}/reject", prefix).leak();
+
+    let mut router = Router::new();
+    router.add(topic, QualityOfService::QoS1, record).unwrap();
+    router.add(rejecting, QualityOfService::QoS1, reject).unwrap();
+
+    //queued before connecting - replayed once subscribed, and comes straight back to us
+    let outbox = Mutex::new(RefCell::new(Outbox::new()));
+    critical_section::with(|cs| {
+        let mut outbox = outbox.borrow_ref_mut(cs);
+        outbox.push(Outgoing::new(rejecting, b"no", QualityOfService::QoS1, false).unwrap());
+        outbox.push(Outgoing::new(topic, b"hello", QualityOfService::QoS1, false).unwrap());
+    });
+
+    let mut hooks = Until::new(2);
+    let end = session_against(&addr, &router, &outbox, Duration::from_secs(30), &mut hooks);
+
+    assert_eq!(end, End::Stopped);
+    assert_eq!(hooks.rejected, vec![Rejection::Handler(HandlerError::Rejected("not today"))]);
+    assert_eq!(HANDLED.with(|handled| handled.borrow().clone()), vec![b"hello".to_vec()]);
+    //both PUBACKed by the broker
+    assert!(critical_section::with(|cs| outbox.borrow_ref(cs).is_empty()));
+}
+
+#[test]
+fn keeps_pinging() {
+    let Some(addr) = broker() else { return };
+    let topic: &'static str = format!("{}/quiet", prefix("ping")).leak();
+
+    let mut router = Router::new();
+    router.add(topic, QualityOfService::QoS0, record).unwrap();
+    let outbox = Mutex::new(RefCell::new(Outbox::new()));
+
+    //nothing is ever published, so only pings (and their responses) happen until the deadline
+    let mut hooks = Until::new(usize::MAX);
+    hooks.deadline = Instant::now() + Duration::from_millis(500);
+    let end = session_against(&addr, &router, &outbox, Duration::from_millis(50), &mut hooks);
+
+    assert_eq!(end, End::Stopped);
+    assert_eq!(hooks.received, 0);
+}
@@ -0,0 +1,66 @@
+//! The client on its own: acks for incoming publishes when `poll()` is dropped part way.
+
+use embassy_futures::{
+    block_on,
+    select::{select, Either},
+};
+use embassy_time::{Duration, Timer};
+use mqtt_host_tests::{
+    fake::{Link, PUBACK, PUBCOMP, PUBREC, PUBREL},
+    mqtt::{
+        client::MqttClient,
+        packet::{Packet, QualityOfService},
+    },
+};
+
+const STALL: Duration = Duration::from_millis(20);
+
+#[test]
+fn ack_cut_short_by_a_dropped_poll_is_finished() {
+    let link = Link::new();
+    let (mut tx, mut rx) = ([0u8; 256], [0u8; 256]);
+    let mut client = MqttClient::new(link.client(), &mut tx, &mut rx);
+    let mut broker = link.broker();
+
+    block_on(async {
+        broker.publish("test/light", b"{}", QualityOfService::QoS1, false, 7).await;
+        assert!(matches!(client.poll().await, Ok(Packet::Publish(publish)) if publish.pid == Some(7)));
+
+        //only half the PUBACK fits before the next poll loses to a timer
+        let junk = link.clog(2);
+        assert!(matches!(select(client.poll(), Timer::after(STALL)).await, Either::Second(())));
+
+        let ack = match select(client.poll(), async {
+            broker.read_exact(junk).await;
+            broker.expect(PUBACK).await
+        }).await {
+            Either::Second(ack) => ack,
+            Either::First(packet) => panic!("poll returned {:?} without a packet sent", packet),
+        };
+        assert_eq!(ack.bytes, [PUBACK << 4, 2, 0, 7]);
+    });
+}
+
+#[test]
+fn owed_ack_goes_out_before_anything_else() {
+    let link = Link::new();
+    let (mut tx, mut rx) = ([0u8; 256], [0u8; 256]);
+    let mut client = MqttClient::new(link.client(), &mut tx, &mut rx);
+    let mut broker = link.broker();
+
+    block_on(async {
+        broker.publish("test/light", b"{}", QualityOfService::QoS2, false, 9).await;
+        assert!(matches!(client.poll().await, Ok(Packet::Publish(_))));
+        //the next thing sent carries the PUBREC in front of it
+        client.ping().await.unwrap();
+        assert_eq!(broker.expect(PUBREC).await.pid(), 9);
+        broker.next().await;
+
+        //a resend isn't delivered twice, PUBREL is answered and neither comes out of poll
+        broker.publish("test/light", b"{}", QualityOfService::QoS2, false, 9).await;
+        broker.ack(PUBREL, 9).await;
+        assert!(matches!(select(client.poll(), Timer::after(STALL)).await, Either::Second(())));
+        assert_eq!(broker.expect(PUBREC).await.pid(), 9);
+        assert_eq!(broker.expect(PUBCOMP).await.pid(), 9);
+    });
+}
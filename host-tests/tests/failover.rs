@@ -0,0 +1,102 @@
+//! Broker list parsing and failover order.
+
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use mqtt_host_tests::mqtt::failover::{Broker, Failover, ParseError};
+
+fn brokers(urls: &[&'static str]) -> Vec<Broker<'static>, 4> {
+    urls.iter().map(|url| Broker::parse(url).unwrap()).collect()
+}
+
+fn host<'a, const N: usize>(failover: &Failover<'a, N>) -> Option<&'a str> {
+    failover.current().map(|broker| broker.host)
+}
+
+#[test]
+fn parses_broker_urls() {
+    assert_eq!(
+        Broker::parse("mqtt://user:p@ss:word@broker.lan:1884"),
+        Ok(Broker { host: "broker.lan", port: 1884, username: Some("user"), password: Some("p@ss:word"), tls: false, path: None })
+    );
+    assert_eq!(
+        Broker::parse(" mqtt://10.0.0.5 "),
+        Ok(Broker { host: "10.0.0.5", port: 1883, username: None, password: None, tls: false, path: None })
+    );
+    assert_eq!(
+        Broker::parse("mqtts://device@cloud.example.com"),
+        Ok(Broker { host: "cloud.example.com", port: 8883, username: Some("device"), password: None, tls: true, path: None })
+    );
+    assert_eq!(
+        Broker::parse("mqtt://user@[2001:db8::7]:1884"),
+        Ok(Broker { host: "2001:db8::7", port: 1884, username: Some("user"), password: None, tls: false, path: None })
+    );
+    assert_eq!(Broker::parse("mqtt://[fd00::1]").map(|broker| (broker.host, broker.port)), Ok(("fd00::1", 1883)));
+    assert_eq!(Broker::parse("mqtt://[fd00::1"), Err(ParseError::Host));
+    assert_eq!(Broker::parse("mqtt://[fd00::1]:"), Err(ParseError::Port));
+    assert_eq!(Broker::parse("mqtt://broker.lan:"), Err(ParseError::Port));
+    assert_eq!(Broker::parse("http://broker.lan"), Err(ParseError::Scheme));
+    assert_eq!(Broker::parse("mqtt://broker.lan:99999"), Err(ParseError::Port));
+    assert_eq!(Broker::parse("mqtt://user@"), Err(ParseError::Host));
+    assert_eq!(Broker::parse("mqtt://broker.lan/topic"), Err(ParseError::Host));
+}
+
+#[test]
+fn parses_websocket_urls() {
+    assert_eq!(
+        Broker::parse("ws://user:pass@broker.lan:8080/ws/mqtt"),
+        Ok(Broker {
+            host: "broker.lan",
+            port: 8080,
+            username: Some("user"),
+            password: Some("pass"),
+            tls: false,
+            path: Some("/ws/mqtt"),
+        })
+    );
+    assert_eq!(
+        Broker::parse("ws://broker.lan"),
+        Ok(Broker { host: "broker.lan", port: 80, username: None, password: None, tls: false, path: Some("/mqtt") })
+    );
+    assert_eq!(
+        Broker::parse("wss://cloud.example.com/"),
+        Ok(Broker { host: "cloud.example.com", port: 443, username: None, password: None, tls: true, path: Some("/") })
+    );
+    assert_eq!(Broker::parse("ws:///mqtt"), Err(ParseError::Host));
+}
+
+#[test]
+fn rotates_through_brokers_skipping_tls() {
+    let mut failover = Failover::new(
+        brokers(&["mqtt://primary", "mqtts://cloud", "mqtt://standby"]),
+        Duration::from_secs(300),
+    );
+    let now = Instant::from_secs(10);
+
+    assert_eq!(host(&failover), Some("primary"));
+    failover.failed(now);
+    assert_eq!(host(&failover), Some("standby"));
+    assert_eq!(failover.position(), 2);
+    failover.failed(now);
+    assert_eq!(host(&failover), Some("primary"));
+}
+
+#[test]
+fn returns_to_primary_after_a_while() {
+    let mut failover = Failover::new(brokers(&["mqtt://primary", "mqtt://standby"]), Duration::from_secs(300));
+    let left = Instant::from_secs(10);
+
+    failover.failed(left);
+    assert_eq!(host(&failover), Some("standby"));
+    assert!(!failover.primary_due(left + Duration::from_secs(299)));
+    assert!(failover.primary_due(left + Duration::from_secs(300)));
+
+    failover.back_to_primary();
+    assert_eq!(host(&failover), Some("primary"));
+    assert!(!failover.primary_due(left + Duration::from_secs(1000)));
+}
+
+#[test]
+fn nothing_usable_when_all_tls() {
+    let failover = Failover::new(brokers(&["mqtts://a", "mqtts://b"]), Duration::from_secs(300));
+    assert_eq!(failover.current(), None);
+}
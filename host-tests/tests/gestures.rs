@@ -0,0 +1,158 @@
+//! Button gestures: debounced presses and releases in, single/double/long/hold out.
+
+use mqtt_host_tests::input::gestures::{
+    Event, Gesture, Gestures, DEBOUNCE_MS, DOUBLE_GAP_MS, HOLD_MS, HOLD_REPEAT_MS, LONG_PRESS_MS, PROVISION_HOLD_MS,
+};
+
+/// The button as the task sees it: edges at given times, and the pin read once each has settled
+struct Button {
+    gestures: Gestures,
+    events: Vec<Event>,
+}
+
+impl Button {
+    fn new() -> Self {
+        Self { gestures: Gestures::default(), events: Vec::new() }
+    }
+
+    /// Let the deadline pass as often as it comes up until `now`
+    fn wait_until(&mut self, now: u64) {
+        self.wait_before(now + 1);
+    }
+
+    fn wait_before(&mut self, now: u64) {
+        while let Some(deadline) = self.gestures.deadline().filter(|deadline| *deadline < now) {
+            self.events.extend(self.gestures.timeout(deadline));
+        }
+    }
+
+    /// An edge at `at`, with the pin `pressed` once it has settled. An edge that comes in before
+    /// the timer goes off wins, even though the pin is read a little after the deadline.
+    fn edge(&mut self, at: u64, pressed: bool) {
+        self.wait_before(at);
+        assert_eq!(Gestures::settle_at(at), at + DEBOUNCE_MS);
+        self.events.extend(self.gestures.settled(pressed, at));
+    }
+
+    fn press(&mut self, down: u64, up: u64) {
+        self.edge(down, true);
+        self.edge(up, false);
+    }
+
+    fn gestures(&self) -> Vec<Gesture> {
+        self.events.iter().map(|(gesture, _, _)| *gesture).collect()
+    }
+}
+
+#[test]
+fn short_press_is_a_single_once_no_second_follows() {
+    let mut button = Button::new();
+    button.press(1000, 1100);
+    //still could become a double
+    button.wait_until(1100 + DOUBLE_GAP_MS - 1);
+    assert!(button.events.is_empty());
+    button.wait_until(1100 + DOUBLE_GAP_MS);
+    assert_eq!(button.events, [(Gesture::Single, 1000, 100)]);
+}
+
+#[test]
+fn two_quick_presses_are_a_double() {
+    let mut button = Button::new();
+    button.press(1000, 1100);
+    //the second press lands right on the end of the gap
+    button.press(1100 + DOUBLE_GAP_MS, 1500);
+    button.wait_until(5000);
+    assert_eq!(button.events, [(Gesture::Double, 1000, 500)]);
+
+    //one ms later it's two singles
+    let mut button = Button::new();
+    button.press(1000, 1100);
+    button.edge(1100 + DOUBLE_GAP_MS + 1, true);
+    assert_eq!(button.gestures(), [Gesture::Single]);
+    button.edge(1500, false);
+    button.wait_until(5000);
+    assert_eq!(button.events, [(Gesture::Single, 1000, 100), (Gesture::Single, 1401, 99)]);
+}
+
+#[test]
+fn long_press_starts_at_long_press_ms() {
+    let mut button = Button::new();
+    button.press(1000, 1000 + LONG_PRESS_MS - 1);
+    button.wait_until(5000);
+    assert_eq!(button.gestures(), [Gesture::Single]);
+
+    let mut button = Button::new();
+    button.press(1000, 1000 + LONG_PRESS_MS);
+    button.wait_until(5000);
+    assert_eq!(button.events, [(Gesture::Long, 1000, LONG_PRESS_MS)]);
+}
+
+#[test]
+fn holding_repeats_then_ends_as_long() {
+    let mut button = Button::new();
+    button.edge(1000, true);
+    button.wait_until(1000 + HOLD_MS - 1);
+    assert!(button.events.is_empty());
+    button.wait_until(1000 + HOLD_MS + 2 * HOLD_REPEAT_MS);
+    assert_eq!(button.events, [
+        (Gesture::Hold, 1000, HOLD_MS),
+        (Gesture::Hold, 1000, HOLD_MS + HOLD_REPEAT_MS),
+        (Gesture::Hold, 1000, HOLD_MS + 2 * HOLD_REPEAT_MS),
+    ]);
+    button.edge(3500, false);
+    assert_eq!(button.events.last(), Some(&(Gesture::Long, 1000, 2500)));
+    assert_eq!(button.gestures.deadline(), None);
+}
+
+#[test]
+fn bounces_inside_the_debounce_window_are_ignored() {
+    let mut button = Button::new();
+    //a glitch: down and back up before the pin was read, so it reads released
+    button.edge(1000, false);
+    assert_eq!(button.gestures.deadline(), None);
+
+    //contact bounce on release: the pin had already settled released, the later edges read the same
+    button.press(2000, 2100);
+    button.edge(2100 + DEBOUNCE_MS / 2, false);
+    button.edge(2100 + DEBOUNCE_MS, false);
+    button.wait_until(5000);
+    assert_eq!(button.events, [(Gesture::Single, 2000, 100)]);
+
+    //a press shorter than the window still counts if both reads caught it
+    let mut button = Button::new();
+    button.press(1000, 1000 + DEBOUNCE_MS);
+    button.wait_until(5000);
+    assert_eq!(button.events, [(Gesture::Single, 1000, DEBOUNCE_MS)]);
+}
+
+#[test]
+fn provisioning_asked_for_once_per_press() {
+    let mut button = Button::new();
+    button.edge(1000, true);
+    let mut asked = Vec::new();
+    while let Some(deadline) = button.gestures.deadline().filter(|deadline| *deadline <= 1000 + 3 * PROVISION_HOLD_MS) {
+        if let Some((Gesture::Hold, at, duration)) = button.gestures.timeout(deadline) {
+            if button.gestures.provision_due(at + duration) {
+                asked.push(duration);
+            }
+        }
+    }
+    assert_eq!(asked, [PROVISION_HOLD_MS]);
+
+    //and a fresh press can ask again
+    button.edge(1000 + 3 * PROVISION_HOLD_MS, false);
+    button.edge(100_000, true);
+    assert!(!button.gestures.provision_due(100_000 + PROVISION_HOLD_MS - 1));
+    assert!(button.gestures.provision_due(100_000 + PROVISION_HOLD_MS));
+    assert!(!button.gestures.provision_due(100_000 + PROVISION_HOLD_MS + 1));
+}
+
+#[test]
+fn gestures_serialise_by_name() {
+    let mut buf = [0u8; 16];
+    for gesture in [Gesture::Single, Gesture::Double, Gesture::Long, Gesture::Hold] {
+        let len = serde_json_core::to_slice(&gesture, &mut buf).unwrap();
+        assert_eq!(&buf[..len], format!("\"{}\"", gesture.name()).as_bytes());
+    }
+    assert_eq!(Gesture::Double.name(), "double");
+}
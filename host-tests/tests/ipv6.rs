@@ -0,0 +1,113 @@
+//! IPv6 addressing: the IPV6 setting, SLAAC addresses, and router advertisements/solicitations.
+
+use std::net::Ipv6Addr;
+
+use mqtt_host_tests::wireless::ipv6::{self, Mode, ModeError, RouterAdvert, StaticIpv6};
+
+const MAC: [u8; 6] = [0x40, 0x4c, 0xca, 0x12, 0x34, 0x56];
+const ROUTER: Ipv6Addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0x1, 0x2, 0x3, 0x4);
+const PREFIX: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0x1, 0x2, 0, 0, 0, 0);
+
+#[test]
+fn setting_picks_the_mode() {
+    assert_eq!(Mode::parse(None, None, None), Ok(Mode::Slaac));
+    assert_eq!(Mode::parse(Some("SLAAC"), Some("fe80::1"), None), Ok(Mode::Slaac));
+    assert_eq!(Mode::parse(Some("off"), None, None), Ok(Mode::Off));
+
+    assert_eq!(
+        Mode::parse(Some("2001:db8::50/64"), Some("2001:db8::1"), Some("2001:4860:4860::8888, 2606:4700:4700::1111")),
+        Ok(Mode::Static(StaticIpv6 {
+            address: "2001:db8::50".parse().unwrap(),
+            prefix_len: 64,
+            gateway: Some("2001:db8::1".parse().unwrap()),
+            dns_servers: ["2001:4860:4860::8888".parse().unwrap(), "2606:4700:4700::1111".parse().unwrap()].into_iter().collect(),
+        }))
+    );
+    assert_eq!(Mode::parse(Some("2001:db8::50"), None, None), Err(ModeError::Address));
+    assert_eq!(Mode::parse(Some("2001:db8::50/129"), None, None), Err(ModeError::Address));
+    assert_eq!(Mode::parse(Some("192.168.1.50/24"), None, None), Err(ModeError::Address));
+    assert_eq!(Mode::parse(Some("2001:db8::50/64"), Some("router"), None), Err(ModeError::Gateway));
+    assert_eq!(Mode::parse(Some("2001:db8::50/64"), None, Some("::1 ::2 ::3 ::4")), Err(ModeError::Dns));
+}
+
+#[test]
+fn slaac_address_is_the_prefix_and_modified_eui64() {
+    assert_eq!(ipv6::interface_id(MAC), [0x42, 0x4c, 0xca, 0xff, 0xfe, 0x12, 0x34, 0x56]);
+    assert_eq!(ipv6::slaac_address(PREFIX, MAC), "2001:db8:1:2:424c:caff:fe12:3456".parse::<Ipv6Addr>().unwrap());
+}
+
+/// A router advertisement from `source`, with `options` after the fixed part
+fn advert(source: Ipv6Addr, router_lifetime: u16, options: &[u8]) -> Vec<u8> {
+    let mut icmp = vec![134, 0, 0, 0, 64, 0];
+    icmp.extend_from_slice(&router_lifetime.to_be_bytes());
+    icmp.extend_from_slice(&[0; 8]);
+    icmp.extend_from_slice(options);
+
+    let mut packet = vec![0x60, 0, 0, 0];
+    packet.extend_from_slice(&(icmp.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&[58, 255]);
+    packet.extend_from_slice(&source.octets());
+    packet.extend_from_slice(&Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1).octets());
+    packet.extend_from_slice(&icmp);
+    packet
+}
+
+fn prefix_option(prefix: Ipv6Addr, len: u8, flags: u8, valid: u32) -> Vec<u8> {
+    let mut option = vec![3, 4, len, flags];
+    option.extend_from_slice(&valid.to_be_bytes());
+    option.extend_from_slice(&valid.to_be_bytes());
+    option.extend_from_slice(&[0; 4]);
+    option.extend_from_slice(&prefix.octets());
+    option
+}
+
+#[test]
+fn router_advertisements_give_prefix_router_and_dns() {
+    let dns: Ipv6Addr = "2001:db8:1:2::53".parse().unwrap();
+    let mut options = vec![1, 1, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01];
+    //on-link only (no autonomous flag), then the one to use
+    options.extend(prefix_option(Ipv6Addr::new(0x2001, 0xdb8, 0x9, 0, 0, 0, 0, 0), 64, 0x80, 3600));
+    options.extend(prefix_option(PREFIX, 64, 0xc0, 86400));
+    options.extend_from_slice(&[25, 3, 0, 0, 0, 0, 0x0e, 0x10]);
+    options.extend_from_slice(&dns.octets());
+
+    let parsed = RouterAdvert::parse(&advert(ROUTER, 1800, &options)).unwrap();
+    assert_eq!(parsed.router, ROUTER);
+    assert_eq!(parsed.router_lifetime, 1800);
+    assert_eq!(parsed.prefix, Some((PREFIX, 86400)));
+    assert_eq!(parsed.dns_servers.as_slice(), &[dns]);
+
+    //no prefix we can make an address on
+    let other_len = RouterAdvert::parse(&advert(ROUTER, 0, &prefix_option(PREFIX, 48, 0xc0, 86400))).unwrap();
+    assert_eq!(other_len.prefix, None);
+    let expired = RouterAdvert::parse(&advert(ROUTER, 0, &prefix_option(PREFIX, 64, 0xc0, 0))).unwrap();
+    assert_eq!(expired.prefix, None);
+}
+
+#[test]
+fn bad_router_advertisements_are_ignored() {
+    let options = prefix_option(PREFIX, 64, 0xc0, 86400);
+    assert!(RouterAdvert::parse(&advert(ROUTER, 1800, &options)).is_some());
+
+    //not from the link
+    assert_eq!(RouterAdvert::parse(&advert(PREFIX, 1800, &options)), None);
+    let mut forwarded = advert(ROUTER, 1800, &options);
+    forwarded[7] = 64;
+    assert_eq!(RouterAdvert::parse(&forwarded), None);
+    //a zero length option would never end
+    assert_eq!(RouterAdvert::parse(&advert(ROUTER, 1800, &[3, 0, 0, 0, 0, 0, 0, 0])), None);
+    //cut short
+    let whole = advert(ROUTER, 1800, &options);
+    assert_eq!(RouterAdvert::parse(&whole[..whole.len() - 1]), None);
+}
+
+#[test]
+fn router_solicitation_is_checksummed() {
+    let packet = ipv6::router_solicitation();
+    assert_eq!(packet.len(), ipv6::SOLICITATION_LEN);
+    assert_eq!(&packet[..8], &[0x60, 0, 0, 0, 0, 8, 58, 255]);
+    assert_eq!(&packet[8..24], &[0; 16]);
+    assert_eq!(packet[24..40], Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2).octets());
+    //known good: type 133, code 0, checksum over the pseudo-header
+    assert_eq!(&packet[40..], &[133, 0, 0x7b, 0xb8, 0, 0, 0, 0]);
+}
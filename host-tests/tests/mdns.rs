@@ -0,0 +1,225 @@
+//! mDNS/DNS-SD broker discovery: queries, and making sense of (untrusted) multicast answers.
+
+use std::net::Ipv4Addr;
+
+use mqtt_host_tests::mdns::browse::{build_query, instance_matches, Browse, TYPE_A, TYPE_PTR, TYPE_SRV};
+
+const PI4: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 7);
+
+/// `name` as uncompressed labels
+fn name(name: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for label in name.split('.') {
+        bytes.push(label.len() as u8);
+        bytes.extend_from_slice(label.as_bytes());
+    }
+    bytes.push(0);
+    bytes
+}
+
+/// A compression pointer to `offset`
+fn pointer(offset: u16) -> Vec<u8> {
+    vec![0xc0 | (offset >> 8) as u8, offset as u8]
+}
+
+/// A resource record: `owner` (already encoded), IN class with the cache-flush bit, a 120s ttl
+fn record(owner: &[u8], rtype: u16, rdata: &[u8]) -> Vec<u8> {
+    let mut bytes = owner.to_vec();
+    bytes.extend_from_slice(&rtype.to_be_bytes());
+    bytes.extend_from_slice(&[0x80, 1, 0, 0, 0, 120]);
+    bytes.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(rdata);
+    bytes
+}
+
+fn srv(priority: u16, port: u16, target: &[u8]) -> Vec<u8> {
+    let mut rdata = priority.to_be_bytes().to_vec();
+    rdata.extend_from_slice(&[0, 0]);
+    rdata.extend_from_slice(&port.to_be_bytes());
+    rdata.extend_from_slice(target);
+    rdata
+}
+
+/// A response carrying `answers` after any (already encoded) questions
+fn response(questions: &[&[u8]], answers: &[Vec<u8>]) -> Vec<u8> {
+    let mut pkt = vec![0, 0, 0x84, 0];
+    pkt.extend_from_slice(&(questions.len() as u16).to_be_bytes());
+    pkt.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+    pkt.extend_from_slice(&[0, 0, 0, 0]);
+    for question in questions {
+        pkt.extend_from_slice(question);
+    }
+    for answer in answers {
+        pkt.extend_from_slice(answer);
+    }
+    pkt
+}
+
+/// PTR, SRV and A for one broker, all names written out in full
+fn full_answer() -> Vec<u8> {
+    response(&[], &[
+        record(&name("_mqtt._tcp.local"), TYPE_PTR, &name("office broker._mqtt._tcp.local")),
+        record(&name("office broker._mqtt._tcp.local"), TYPE_SRV, &srv(0, 1884, &name("pi4.local"))),
+        record(&name("pi4.local"), TYPE_A, &PI4.octets()),
+    ])
+}
+
+fn best(browse: &Browse, pattern: &str) -> Option<(String, Ipv4Addr, u16)> {
+    browse.best(pattern).map(|(instance, addr, port)| (instance.label().into(), addr, port))
+}
+
+#[test]
+fn pieces_a_broker_together_from_ptr_srv_and_a() {
+    let mut browse = Browse::default();
+    browse.parse(&full_answer());
+    assert_eq!(best(&browse, ""), Some(("office broker".into(), PI4, 1884)));
+    assert_eq!(best(&browse, "OFFICE*"), Some(("office broker".into(), PI4, 1884)));
+    assert_eq!(best(&browse, "lab"), None);
+}
+
+#[test]
+fn follows_compressed_names() {
+    //the echoed question's name (at offset 12) is pointed back to throughout
+    let question = [name("_mqtt._tcp.local"), vec![0, TYPE_PTR as u8, 0, 1]].concat();
+    let service = 12;
+    let mut instance_name = vec![6];
+    instance_name.extend_from_slice(b"office");
+    instance_name.extend(pointer(service));
+    let ptr = record(&pointer(service), TYPE_PTR, &instance_name);
+    //the instance name as written in the PTR's rdata: after the question, the pointer owner and the fixed fields
+    let instance = (12 + question.len() + 2 + 10) as u16;
+    let srv_record = record(&pointer(instance), TYPE_SRV, &srv(5, 1883, &[&[3][..], b"pi4", &name("local")].concat()));
+    //"pi4.local" is the SRV's target, starting after its 6 fixed bytes
+    let target = instance + instance_name.len() as u16 + 2 + 10 + 6;
+    let a = record(&pointer(target), TYPE_A, &PI4.octets());
+
+    let mut browse = Browse::default();
+    browse.parse(&response(&[&question], &[ptr, srv_record, a]));
+    assert_eq!(best(&browse, "office"), Some(("office".into(), PI4, 1883)));
+    assert_eq!(browse.instances[0].name, "office._mqtt._tcp.local");
+}
+
+#[test]
+fn lowest_srv_priority_wins() {
+    let mut browse = Browse::default();
+    browse.parse(&response(&[], &[
+        record(&name("_mqtt._tcp.local"), TYPE_PTR, &name("backup._mqtt._tcp.local")),
+        record(&name("_mqtt._tcp.local"), TYPE_PTR, &name("main._mqtt._tcp.local")),
+        record(&name("backup._mqtt._tcp.local"), TYPE_SRV, &srv(10, 1883, &name("b.local"))),
+        record(&name("main._mqtt._tcp.local"), TYPE_SRV, &srv(1, 1883, &name("m.local"))),
+        record(&name("b.local"), TYPE_A, &[10, 0, 0, 2]),
+        record(&name("M.LOCAL"), TYPE_A, &[10, 0, 0, 1]),
+    ]));
+    assert_eq!(best(&browse, "*"), Some(("main".into(), Ipv4Addr::new(10, 0, 0, 1), 1883)));
+    assert_eq!(best(&browse, "back*"), Some(("backup".into(), Ipv4Addr::new(10, 0, 0, 2), 1883)));
+}
+
+#[test]
+fn asks_for_what_the_answers_left_out() {
+    let mut browse = Browse::default();
+    browse.parse(&response(&[], &[record(&name("_mqtt._tcp.local"), TYPE_PTR, &name("office._mqtt._tcp.local"))]));
+    assert_eq!(browse.missing(""), Some(("office._mqtt._tcp.local", TYPE_SRV)));
+
+    browse.parse(&response(&[], &[record(&name("office._mqtt._tcp.local"), TYPE_SRV, &srv(0, 1883, &name("pi4.local")))]));
+    assert_eq!(browse.missing(""), Some(("pi4.local", TYPE_A)));
+    assert_eq!(browse.missing("lab"), None);
+
+    browse.parse(&response(&[], &[record(&name("pi4.local"), TYPE_A, &PI4.octets())]));
+    assert_eq!(browse.missing(""), None);
+    assert_eq!(best(&browse, ""), Some(("office".into(), PI4, 1883)));
+}
+
+#[test]
+fn truncated_packets_keep_only_whole_records() {
+    let pkt = full_answer();
+    for len in 0..pkt.len() {
+        let mut browse = Browse::default();
+        browse.parse(&pkt[..len]);
+        assert_eq!(best(&browse, ""), None, "found a broker in {} of {} bytes", len, pkt.len());
+    }
+
+    //cut inside the A record: the PTR and SRV before it still count
+    let mut browse = Browse::default();
+    browse.parse(&pkt[..pkt.len() - 2]);
+    assert_eq!(browse.instances.len(), 1);
+    assert_eq!(browse.instances[0].port, Some(1884));
+    assert!(browse.hosts.is_empty());
+}
+
+#[test]
+fn pointer_loops_and_bad_pointers_are_ignored() {
+    //a name that points at itself, two that point at each other, one past the end of the packet
+    for owner in [pointer(12), [pointer(14), pointer(12)].concat(), pointer(0x3fff)] {
+        let mut browse = Browse::default();
+        browse.parse(&response(&[], &[record(&owner, TYPE_A, &PI4.octets())]));
+        assert!(browse.hosts.is_empty());
+    }
+
+    //a pointer with only one of its two bytes
+    let mut browse = Browse::default();
+    browse.parse(&[0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0xc0]);
+    assert!(browse.hosts.is_empty());
+
+    //a loop in the PTR's rdata stops that record, but not what came before it
+    let mut browse = Browse::default();
+    //the PTR's rdata (after the header, the 25 byte A record, and its own owner and fixed fields) points at itself
+    let looped = record(&name("_mqtt._tcp.local"), TYPE_PTR, &pointer(12 + 25 + 18 + 10));
+    browse.parse(&response(&[], &[record(&name("pi4.local"), TYPE_A, &PI4.octets()), looped]));
+    assert_eq!(browse.hosts.len(), 1);
+    assert!(browse.instances.is_empty());
+}
+
+#[test]
+fn malformed_records_are_ignored() {
+    let mut browse = Browse::default();
+    browse.parse(&response(&[], &[
+        //an address that isn't 4 bytes
+        record(&name("short.local"), TYPE_A, &[10, 0, 0]),
+        //a label length running off the end of the rdata and packet
+        record(&name("_mqtt._tcp.local"), TYPE_PTR, &[60, b'x']),
+    ]));
+    assert!(browse.hosts.is_empty());
+    assert!(browse.instances.is_empty());
+
+    //not valid utf-8
+    let mut browse = Browse::default();
+    browse.parse(&response(&[], &[record(&[2, 0xff, 0xfe, 0], TYPE_A, &PI4.octets())]));
+    assert!(browse.hosts.is_empty());
+
+    //a name longer than we keep
+    let long = ["a"; 70].join(".");
+    let mut browse = Browse::default();
+    browse.parse(&response(&[], &[record(&name(&long), TYPE_A, &PI4.octets())]));
+    assert!(browse.hosts.is_empty());
+
+    //an rdata length past the end of the packet
+    let mut a = record(&name("pi4.local"), TYPE_A, &PI4.octets());
+    let rdlen = a.len() - 6;
+    a[rdlen..rdlen + 2].copy_from_slice(&40u16.to_be_bytes());
+    let mut browse = Browse::default();
+    browse.parse(&response(&[], &[a]));
+    assert!(browse.hosts.is_empty());
+}
+
+#[test]
+fn builds_queries() {
+    let mut buf = [0u8; 64];
+    let len = build_query(&mut buf, "_mqtt._tcp.local.", TYPE_PTR).unwrap();
+    assert_eq!(&buf[..len], &[&[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0][..], &name("_mqtt._tcp.local"), &[0, 12, 0, 1]].concat()[..]);
+
+    assert_eq!(build_query(&mut buf, "a..local", TYPE_A), None);
+    assert_eq!(build_query(&mut buf, &format!("{}.local", "x".repeat(64)), TYPE_A), None);
+    assert_eq!(build_query(&mut buf[..20], "_mqtt._tcp.local", TYPE_PTR), None);
+}
+
+#[test]
+fn instance_patterns() {
+    assert!(instance_matches("", "anything"));
+    assert!(instance_matches("*", "anything"));
+    assert!(instance_matches("Office*", "office broker"));
+    assert!(instance_matches("office broker", "OFFICE BROKER"));
+    assert!(!instance_matches("office", "office broker"));
+    assert!(!instance_matches("office*", "off"));
+    //a prefix that would split a multi-byte character doesn't match (or panic)
+    assert!(!instance_matches("a*", "éa"));
+}
@@ -0,0 +1,49 @@
+//! Choosing which known Wi-Fi network to join from a scan.
+
+use mqtt_host_tests::wireless::networks::{parse_list, rank, Candidate, Network};
+
+const OFFICE: Network = Network { ssid: "Office", password: "office-pass", priority: 1, ipv4: None };
+const LAB: Network = Network { ssid: "Lab", password: "lab-password", priority: 1, ipv4: None };
+const PHONE: Network = Network { ssid: "Phone hotspot", password: "", priority: 0, ipv4: None };
+
+fn order<'a>(candidates: &[Candidate<'a>]) -> Vec<(&'a str, Option<i8>)> {
+    candidates.iter().map(|candidate| (candidate.network.ssid, candidate.rssi)).collect()
+}
+
+#[test]
+fn parses_network_lists() {
+    let list = parse_list::<4>("Office:office-pass:1, Lab:pass:with:colons:2\nOpen Cafe\n,Guest:guest-pass");
+    assert_eq!(list.as_slice(), &[
+        Network { ssid: "Office", password: "office-pass", priority: 1, ipv4: None },
+        Network { ssid: "Lab", password: "pass:with:colons", priority: 2, ipv4: None },
+        Network { ssid: "Open Cafe", password: "", priority: 0, ipv4: None },
+        Network { ssid: "Guest", password: "guest-pass", priority: 0, ipv4: None },
+    ]);
+    //a trailing non-number is part of the password
+    assert_eq!(Network::parse("Home:abc:def"), Some(Network { ssid: "Home", password: "abc:def", priority: 0, ipv4: None }));
+    assert_eq!(Network::parse(":nameless"), None);
+    assert_eq!(parse_list::<1>("A,B").len(), 1);
+}
+
+#[test]
+fn strongest_of_equal_priority_wins() {
+    let seen = [("Neighbours", -40), ("Lab", -70), ("Office", -55), ("Lab", -60)];
+    let ranked = rank::<4>(&[OFFICE, LAB, PHONE], seen);
+    //the lab's best access point counts, and the unseen hotspot is still tried last
+    assert_eq!(order(&ranked), [("Office", Some(-55)), ("Lab", Some(-60)), ("Phone hotspot", None)]);
+}
+
+#[test]
+fn priority_beats_signal() {
+    let seen = [("Phone hotspot", -30), ("Lab", -80)];
+    assert_eq!(order(&rank::<4>(&[PHONE, LAB], seen)), [("Lab", Some(-80)), ("Phone hotspot", Some(-30))]);
+}
+
+#[test]
+fn unseen_networks_are_tried_by_priority_after_seen_ones() {
+    let ranked = rank::<4>(&[PHONE, OFFICE, LAB], [("Phone hotspot", -50)]);
+    assert_eq!(order(&ranked), [("Phone hotspot", Some(-50)), ("Office", None), ("Lab", None)]);
+
+    //nothing seen (or the scan failed): priority, then the order they were given in
+    assert_eq!(order(&rank::<4>(&[PHONE, LAB, OFFICE], [])), [("Lab", None), ("Office", None), ("Phone hotspot", None)]);
+}
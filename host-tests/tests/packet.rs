@@ -0,0 +1,198 @@
+//! MQTT v5 packet encoding and decoding: what goes on the wire, and what a broker's bytes
+//! (untrusted) decode to - or fail to.
+
+use mqtt_host_tests::mqtt::packet::{
+    decode, encode_ack, encode_connect, encode_disconnect, encode_pingreq, encode_publish, encode_subscribe,
+    encode_unsubscribe, frame, Connect, Error, Packet, Properties, Property, QualityOfService, Will, PUBACK, PUBREL,
+};
+
+fn publish_bytes(topic: &str, payload: &[u8], qos: QualityOfService, pid: u16, properties: &[Property]) -> Vec<u8> {
+    let mut buf = [0u8; 1024];
+    let range = encode_publish(&mut buf, topic, payload, qos, false, false, pid, properties).unwrap();
+    buf[range].to_vec()
+}
+
+fn properties(properties: Properties<'_>) -> Vec<Property<'_>> {
+    properties.iter().collect()
+}
+
+#[test]
+fn connect_is_encoded_to_spec() {
+    let mut buf = [0u8; 128];
+    let connect = Connect {
+        client_id: "dev",
+        username: Some("u"),
+        password: Some(b"pw"),
+        keep_alive: 120,
+        clean_start: true,
+        properties: &[Property::ReceiveMaximum(8), Property::SessionExpiry(3600)],
+        will: Some(Will {
+            topic: "w",
+            payload: b"x",
+            qos: QualityOfService::QoS1,
+            retain: true,
+            properties: &[],
+        }),
+    };
+    let range = encode_connect(&mut buf, &connect).unwrap();
+    #[rustfmt::skip]
+    assert_eq!(&buf[range], &[
+        0x10, 38,
+        0, 4, b'M', b'Q', b'T', b'T', 5,
+        //username, password, will retain, will QoS1, will, clean start
+        0b1110_1110,
+        0, 120,
+        8, 0x21, 0, 8, 0x11, 0, 0, 0x0e, 0x10,
+        0, 3, b'd', b'e', b'v',
+        0, 0, 1, b'w', 0, 1, b'x',
+        0, 1, b'u',
+        0, 2, b'p', b'w',
+    ]);
+}
+
+#[test]
+fn subscribe_unsubscribe_and_small_packets_are_encoded_to_spec() {
+    let mut buf = [0u8; 64];
+    let range = encode_subscribe(&mut buf, 0x8001, &[("a/+", QualityOfService::QoS1), ("b", QualityOfService::QoS0)], &[]).unwrap();
+    assert_eq!(&buf[range], &[0x82, 13, 0x80, 0x01, 0, 0, 3, b'a', b'/', b'+', 1, 0, 1, b'b', 0]);
+
+    let range = encode_unsubscribe(&mut buf, 7, &["a/+"]).unwrap();
+    assert_eq!(&buf[range], &[0xa2, 8, 0, 7, 0, 0, 3, b'a', b'/', b'+']);
+
+    let range = encode_ack(&mut buf, PUBREL, 0x1234, 0).unwrap();
+    assert_eq!(&buf[range], &[0x62, 2, 0x12, 0x34]);
+    let range = encode_ack(&mut buf, PUBACK, 1, 0x87).unwrap();
+    assert_eq!(&buf[range], &[0x40, 3, 0, 1, 0x87]);
+
+    let range = encode_pingreq(&mut buf).unwrap();
+    assert_eq!(&buf[range], &[0xc0, 0]);
+    let range = encode_disconnect(&mut buf, 0).unwrap();
+    assert_eq!(&buf[range], &[0xe0, 1, 0]);
+}
+
+#[test]
+fn publish_round_trips_with_every_kind_of_property() {
+    let all = [
+        Property::PayloadFormat(1),
+        Property::MessageExpiry(60),
+        Property::ContentType("application/json"),
+        Property::ResponseTopic("test/rpc/reply"),
+        Property::CorrelationData(&[0, 1, 0xff]),
+        Property::SubscriptionId(300),
+        Property::TopicAlias(2),
+        Property::UserProperty("color", "red"),
+        Property::UserProperty("font", ""),
+    ];
+    let bytes = publish_bytes("test/eink", b"hello", QualityOfService::QoS2, 42, &all);
+    match decode(&bytes).unwrap() {
+        Packet::Publish(publish) => {
+            assert_eq!((publish.topic, publish.payload), ("test/eink", &b"hello"[..]));
+            assert_eq!((publish.qos, publish.pid, publish.retain, publish.dup), (QualityOfService::QoS2, Some(42), false, false));
+            assert_eq!(properties(publish.properties), all);
+        },
+        packet => panic!("decoded as {:?}", packet),
+    }
+
+    //QoS0 carries no packet id, and an empty payload is fine
+    let bytes = publish_bytes("t", b"", QualityOfService::QoS0, 42, &[]);
+    assert_eq!(bytes, [0x30, 4, 0, 1, b't', 0]);
+    assert!(matches!(decode(&bytes), Ok(Packet::Publish(publish)) if publish.pid.is_none() && publish.payload.is_empty()));
+}
+
+#[test]
+fn broker_packets_are_decoded() {
+    let connack = [0x20, 14, 1, 0, 11, 0x13, 0, 30, 0x21, 0, 4, 0x27, 0, 0, 4, 0];
+    match decode(&connack).unwrap() {
+        Packet::ConnAck { session_present, reason, properties: props } => {
+            assert!(session_present);
+            assert_eq!(reason, 0);
+            assert_eq!(properties(props), [Property::ServerKeepAlive(30), Property::ReceiveMaximum(4), Property::MaximumPacketSize(1024)]);
+        },
+        packet => panic!("decoded as {:?}", packet),
+    }
+    //v5 lets a CONNACK leave its properties out altogether
+    assert!(matches!(decode(&[0x20, 2, 0, 0x87]), Ok(Packet::ConnAck { session_present: false, reason: 0x87, .. })));
+
+    assert!(matches!(decode(&[0x40, 2, 0, 9]), Ok(Packet::PubAck { pid: 9, reason: 0 })));
+    assert!(matches!(decode(&[0x50, 3, 0, 9, 0x10]), Ok(Packet::PubRec { pid: 9, reason: 0x10 })));
+    assert!(matches!(decode(&[0x62, 2, 0, 9]), Ok(Packet::PubRel { pid: 9, .. })));
+    assert!(matches!(decode(&[0x70, 2, 0, 9]), Ok(Packet::PubComp { pid: 9, .. })));
+    assert!(matches!(decode(&[0x90, 5, 0x80, 1, 0, 1, 0x80]), Ok(Packet::SubAck { pid: 0x8001, reasons: [1, 0x80] })));
+    assert!(matches!(decode(&[0xb0, 3, 0, 7, 0]), Ok(Packet::UnsubAck { pid: 7 })));
+    assert!(matches!(decode(&[0xd0, 0]), Ok(Packet::PingResp)));
+    assert!(matches!(decode(&[0xe0, 0]), Ok(Packet::Disconnect { reason: 0 })));
+    assert!(matches!(decode(&[0xe0, 1, 0x8e]), Ok(Packet::Disconnect { reason: 0x8e })));
+}
+
+#[test]
+fn frame_finds_packet_boundaries() {
+    //not enough for a length yet
+    assert_eq!(frame(&[]), Ok(None));
+    assert_eq!(frame(&[0x30]), Ok(None));
+    assert_eq!(frame(&[0x30, 0x80]), Ok(None));
+    assert_eq!(frame(&[0x30, 0xff, 0xff, 0xff]), Ok(None));
+
+    //the length is there - the body needn't be, and anything after it isn't counted
+    assert_eq!(frame(&[0xd0, 0]), Ok(Some((2, 0))));
+    assert_eq!(frame(&[0x30, 5, 0]), Ok(Some((2, 5))));
+    assert_eq!(frame(&[0x30, 0x80, 0x01]), Ok(Some((3, 128))));
+    assert_eq!(frame(&[0x30, 0xff, 0xff, 0xff, 0x7f]), Ok(Some((5, 268_435_455))));
+    assert_eq!(frame(&[0xd0, 0, 0xd0, 0]), Ok(Some((2, 0))));
+
+    //a fifth length byte is never valid
+    assert_eq!(frame(&[0x30, 0x80, 0x80, 0x80, 0x80]), Err(Error::Malformed));
+    assert_eq!(frame(&[0x30, 0xff, 0xff, 0xff, 0xff, 0x01]), Err(Error::Malformed));
+}
+
+#[test]
+fn truncated_and_overlong_packets_are_malformed() {
+    let bytes = publish_bytes("test/eink", b"hello", QualityOfService::QoS1, 1, &[Property::ContentType("text/plain")]);
+    //every cut short of the whole packet, and one byte too many
+    for len in 0..bytes.len() {
+        assert!(decode(&bytes[..len]).is_err(), "decoded {} of {} bytes", len, bytes.len());
+    }
+    let mut long = bytes.clone();
+    long.push(0);
+    assert_eq!(decode(&long).err(), Some(Error::Malformed));
+
+    //lengths inside the packet that run past its end
+    assert_eq!(decode(&[0x30, 3, 0, 9, b't']).err(), Some(Error::Malformed));
+    assert_eq!(decode(&[0x30, 4, 0, 1, b't', 5]).err(), Some(Error::Malformed));
+    assert_eq!(decode(&[0x30, 7, 0, 1, b't', 3, 0x03, 0, 9]).err(), Some(Error::Malformed));
+    assert_eq!(decode(&[0x20, 3, 0, 0, 4]).err(), Some(Error::Malformed));
+    assert_eq!(decode(&[0x40, 1, 0]).err(), Some(Error::Malformed));
+    assert_eq!(decode(&[0x90, 2, 0, 1]).err(), Some(Error::Malformed));
+}
+
+#[test]
+fn bad_contents_are_rejected() {
+    //topic isn't utf-8
+    assert_eq!(decode(&[0x30, 4, 0, 1, 0xff, 0]).err(), Some(Error::Malformed));
+    //QoS 3
+    assert_eq!(decode(&[0x36, 6, 0, 1, b't', 0, 1, 0]).err(), Some(Error::Malformed));
+    //unknown property id
+    assert_eq!(decode(&[0x30, 6, 0, 1, b't', 2, 0x7f, 0]).err(), Some(Error::Malformed));
+    //content type that isn't utf-8
+    assert_eq!(decode(&[0x30, 8, 0, 1, b't', 4, 0x03, 0, 1, 0xc3]).err(), Some(Error::Malformed));
+    //packets only a client sends
+    assert_eq!(decode(&[0x10, 0]).err(), Some(Error::Protocol));
+    assert_eq!(decode(&[0x80, 0]).err(), Some(Error::Protocol));
+    assert_eq!(decode(&[0xc0, 0]).err(), Some(Error::Protocol));
+}
+
+#[test]
+fn packets_too_big_for_the_buffer_are_refused() {
+    let mut small = [0u8; 16];
+    assert_eq!(
+        encode_publish(&mut small, "test/eink", b"a payload that will never fit", QualityOfService::QoS0, false, false, 0, &[]),
+        Err(Error::BufferTooSmall)
+    );
+    //not even room for the fixed header
+    assert_eq!(encode_pingreq(&mut small[..1]), Err(Error::BufferTooSmall));
+
+    //a payload needing a multi-byte remaining length still frames and round trips
+    let payload = [0x5a; 300];
+    let bytes = publish_bytes("t", &payload, QualityOfService::QoS0, 0, &[]);
+    assert_eq!(frame(&bytes), Ok(Some((3, bytes.len() - 3))));
+    assert!(matches!(decode(&bytes), Ok(Packet::Publish(publish)) if publish.payload == payload));
+}
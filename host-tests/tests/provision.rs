@@ -0,0 +1,325 @@
+//! Provisioning: the settings record kept in flash, the settings form, and the DHCP and DNS
+//! answers that get a phone onto the setup network and to the form.
+
+use heapless::String;
+use mqtt_host_tests::provision::{
+    dhcp::{self, Server},
+    dns,
+    form::{self, FormError, Notice, Request},
+    settings::{NetworkSettings, Settings, SettingsError, StaticIpv4, MAX_DNS_SERVERS, MAX_NETWORKS, RECORD_LEN},
+};
+
+fn network(ssid: &str, password: &str, priority: u8) -> NetworkSettings {
+    NetworkSettings { ssid: String::try_from(ssid).unwrap(), password: String::try_from(password).unwrap(), priority, ipv4: None }
+}
+
+fn static_ipv4(address: [u8; 4], prefix_len: u8, gateway: Option<[u8; 4]>, dns_servers: &[[u8; 4]]) -> StaticIpv4 {
+    StaticIpv4 { address, prefix_len, gateway, dns_servers: dns_servers.iter().copied().collect() }
+}
+
+fn settings() -> Settings {
+    Settings {
+        networks: [network("Home; \"Wi-Fi\"", "correct horse", 0)].into_iter().collect(),
+        mqtt_host: String::try_from("broker.lan").unwrap(),
+        mqtt_port: 1884,
+        mqtt_user: String::try_from("eink").unwrap(),
+        mqtt_password: String::try_from("p@ss:w&rd").unwrap(),
+    }
+}
+
+#[test]
+fn settings_survive_the_round_trip() {
+    let mut record = [0xffu8; RECORD_LEN];
+    assert_eq!(Settings::decode(&record), Err(SettingsError::Empty));
+
+    let len = settings().encode(&mut record).unwrap();
+    assert_eq!(Settings::decode(&record[..len]), Ok(settings()));
+    //the rest of the sector is still erased
+    assert_eq!(Settings::decode(&record), Ok(settings()));
+
+    //the largest possible record fits
+    let full = Settings {
+        networks: (0..MAX_NETWORKS)
+            .map(|n| NetworkSettings {
+                ipv4: Some(static_ipv4([10, 0, n as u8, 2], 24, Some([10, 0, n as u8, 1]), &[[10, 0, 0, 53]; MAX_DNS_SERVERS])),
+                ..network(&n.to_string().repeat(32), &"p".repeat(63), n as u8)
+            })
+            .collect(),
+        mqtt_host: String::try_from("h".repeat(64).as_str()).unwrap(),
+        mqtt_port: 65535,
+        mqtt_user: String::try_from("u".repeat(32).as_str()).unwrap(),
+        mqtt_password: String::try_from("q".repeat(64).as_str()).unwrap(),
+    };
+    let len = full.encode(&mut record).unwrap();
+    assert_eq!(len, RECORD_LEN);
+    assert_eq!(Settings::decode(&record), Ok(full));
+
+    //static addressing, with and without a gateway and DNS servers
+    let mut fixed = settings();
+    fixed.networks[0].ipv4 = Some(static_ipv4([192, 168, 1, 50], 24, Some([192, 168, 1, 1]), &[[1, 1, 1, 1], [9, 9, 9, 9]]));
+    _ = fixed.networks.push(NetworkSettings { ipv4: Some(static_ipv4([10, 1, 2, 3], 8, None, &[])), ..network("Lab", "", 1) });
+    let len = fixed.encode(&mut record).unwrap();
+    assert_eq!(Settings::decode(&record[..len]), Ok(fixed));
+}
+
+#[test]
+fn damaged_settings_are_refused() {
+    let mut record = [0xffu8; RECORD_LEN];
+    let len = settings().encode(&mut record).unwrap();
+
+    let mut flipped = record;
+    flipped[len - 3] ^= 0x01;
+    assert_eq!(Settings::decode(&flipped), Err(SettingsError::Corrupt));
+
+    let mut foreign = record;
+    foreign[..4].copy_from_slice(b"NVS\0");
+    assert_eq!(Settings::decode(&foreign), Err(SettingsError::Corrupt));
+
+    assert_eq!(Settings::decode(&record[..len - 1]), Err(SettingsError::Corrupt));
+    assert_eq!(settings().encode(&mut [0u8; 16]), Err(SettingsError::TooLong));
+}
+
+#[test]
+fn requests_are_read_until_complete() {
+    assert_eq!(form::request(b"GET /generate_204 HTTP/1.1\r\nHost: connectivitycheck"), Request::Incomplete);
+    assert_eq!(form::request(b"GET /generate_204 HTTP/1.1\r\nHost: connectivitycheck\r\n\r\n"), Request::Page);
+
+    let post = b"POST /save HTTP/1.1\r\nHost: 192.168.4.1\r\ncontent-length: 9\r\n\r\nssid=Home";
+    assert_eq!(form::request(&post[..post.len() - 2]), Request::Incomplete);
+    assert_eq!(form::request(post), Request::Save(b"ssid=Home"));
+
+    assert_eq!(form::request(b"POST /save HTTP/1.1\r\n\r\nssid=Home"), Request::Invalid);
+    assert_eq!(form::request(b"DELETE / HTTP/1.1\r\n\r\n"), Request::Invalid);
+}
+
+#[test]
+fn form_is_decoded_and_checked() {
+    let nothing = Settings::default();
+    let body = b"ssid=Home%3B+%22Wi-Fi%22&password=correct+horse&priority=&mqtt_host=+broker.lan+&mqtt_port=1884\
+        &mqtt_user=eink&mqtt_password=p%40ss%3Aw%26rd&save=Save";
+    assert_eq!(form::parse(body, &nothing), Ok(settings()));
+
+    let open = form::parse(b"ssid=Cafe&password=&priority=3&mqtt_host=&mqtt_port=", &nothing).unwrap();
+    assert_eq!(open.networks.as_slice(), &[network("Cafe", "", 3)]);
+    assert_eq!(open.mqtt_host, "");
+    assert_eq!(open.mqtt_port, form::DEFAULT_MQTT_PORT);
+
+    assert_eq!(form::parse(b"ssid=&password=whatever1", &nothing), Err(FormError::NoSsid));
+    assert_eq!(form::parse(b"ssid=Home&password=short", &nothing), Err(FormError::Password));
+    assert_eq!(form::parse(b"ssid=Home&priority=256", &nothing), Err(FormError::Priority));
+    assert_eq!(form::parse(b"ssid=Home&mqtt_port=0", &nothing), Err(FormError::Port));
+    assert_eq!(form::parse(b"ssid=Home&mqtt_port=65536", &nothing), Err(FormError::Port));
+    assert_eq!(form::parse(b"ssid=Home%2", &nothing), Err(FormError::Encoding));
+    assert_eq!(form::parse(b"ssid=%ff", &nothing), Err(FormError::Encoding));
+    let long = format!("ssid={}", "x".repeat(33));
+    assert_eq!(form::parse(long.as_bytes(), &nothing), Err(FormError::TooLong("The network name")));
+}
+
+#[test]
+fn form_adds_replaces_and_forgets_networks() {
+    let saved = form::parse(b"ssid=Office&password=office-pass&priority=2&mqtt_user=eink&mqtt_password=secret", &Settings::default()).unwrap();
+
+    //added alongside, and the mqtt password left blank is kept
+    let both = form::parse(b"ssid=Lab&password=lab-password&mqtt_user=eink&mqtt_password=", &saved).unwrap();
+    assert_eq!(both.networks.as_slice(), &[network("Office", "office-pass", 2), network("Lab", "lab-password", 0)]);
+    assert_eq!(both.mqtt_password, "secret");
+
+    //the same name replaces it, nothing new just changes the rest
+    let replaced = form::parse(b"ssid=Office&password=new-office&priority=1&mqtt_user=eink", &both).unwrap();
+    assert_eq!(replaced.networks.as_slice(), &[network("Lab", "lab-password", 0), network("Office", "new-office", 1)]);
+    let forgotten = form::parse(b"forget=Lab&ssid=&mqtt_user=", &replaced).unwrap();
+    assert_eq!(forgotten.networks.as_slice(), &[network("Office", "new-office", 1)]);
+    assert_eq!(forgotten.mqtt_password, "");
+
+    assert_eq!(form::parse(b"forget=Office&ssid=", &forgotten), Err(FormError::NoSsid));
+    let mut full = forgotten;
+    for ssid in ["A", "B", "C"] {
+        full = form::parse(format!("ssid={}", ssid).as_bytes(), &full).unwrap();
+    }
+    assert_eq!(form::parse(b"ssid=D", &full), Err(FormError::TooManyNetworks));
+    assert!(form::parse(b"forget=A&ssid=D", &full).is_ok());
+}
+
+#[test]
+fn form_sets_static_addressing() {
+    let nothing = Settings::default();
+    let body = b"ssid=Lab&ip_address=192.168.1.50%2F24&ip_gateway=192.168.1.1&ip_dns=1.1.1.1%2C+9.9.9.9";
+    let lab = form::parse(body, &nothing).unwrap();
+    assert_eq!(lab.networks[0].ipv4, Some(static_ipv4([192, 168, 1, 50], 24, Some([192, 168, 1, 1]), &[[1, 1, 1, 1], [9, 9, 9, 9]])));
+
+    //a blank address is DHCP, whatever else is filled in
+    let dhcp = form::parse(b"ssid=Lab&ip_address=+&ip_gateway=192.168.1.1", &nothing).unwrap();
+    assert_eq!(dhcp.networks[0].ipv4, None);
+    let bare = form::parse(b"ssid=Lab&ip_address=10.0.0.9%2F8&ip_gateway=&ip_dns=", &nothing).unwrap();
+    assert_eq!(bare.networks[0].ipv4, Some(static_ipv4([10, 0, 0, 9], 8, None, &[])));
+
+    let mut page = std::string::String::new();
+    form::page(&mut page, "esp32c6-v1", &lab, Notice::None).unwrap();
+    assert!(page.contains("<li>Lab (priority 0, static 192.168.1.50/24)"));
+
+    assert_eq!(form::parse(b"ssid=Lab&ip_address=192.168.1.50", &nothing), Err(FormError::Address));
+    assert_eq!(form::parse(b"ssid=Lab&ip_address=192.168.1.50%2F33", &nothing), Err(FormError::Address));
+    assert_eq!(form::parse(b"ssid=Lab&ip_address=192.168.1.500%2F24", &nothing), Err(FormError::Address));
+    assert_eq!(form::parse(b"ssid=Lab&ip_address=192.168.1.50%2F24&ip_gateway=192.168.2.1", &nothing), Err(FormError::Gateway));
+    assert_eq!(form::parse(b"ssid=Lab&ip_address=192.168.1.50%2F24&ip_gateway=192.168.1.50", &nothing), Err(FormError::Gateway));
+    assert_eq!(form::parse(b"ssid=Lab&ip_address=192.168.1.50%2F24&ip_dns=dns.lan", &nothing), Err(FormError::Dns));
+    assert_eq!(form::parse(b"ssid=Lab&ip_address=192.168.1.50%2F24&ip_dns=1.1.1.1+1.0.0.1+8.8.8.8+9.9.9.9", &nothing), Err(FormError::Dns));
+}
+
+#[test]
+fn page_is_filled_in_without_passwords() {
+    let mut page = std::string::String::new();
+    form::page(&mut page, "esp32c6-v1", &settings(), Notice::Error(FormError::Port)).unwrap();
+    assert!(page.contains("Not saved: the MQTT port should be a number"));
+    assert!(page.contains("<li>Home; &quot;Wi-Fi&quot; (priority 0, DHCP)"));
+    assert!(page.contains("name=\"forget\" value=\"Home; &quot;Wi-Fi&quot;\""));
+    assert!(page.contains("name=\"mqtt_port\" type=\"number\" value=\"1884\""));
+    assert!(!page.contains("correct horse"));
+    assert!(!page.contains("p@ss"));
+    assert!(page.contains(&format!("action=\"{}\"", form::SAVE_PATH)));
+
+    //the firmware renders into a fixed buffer - the worst case has to fit
+    let crowded = Settings {
+        networks: (0..MAX_NETWORKS)
+            .map(|_| NetworkSettings {
+                ipv4: Some(static_ipv4([255; 4], 32, None, &[])),
+                ..network(&"\"".repeat(32), "", 255)
+            })
+            .collect(),
+        mqtt_host: String::try_from("&".repeat(64).as_str()).unwrap(),
+        mqtt_port: 65535,
+        mqtt_user: String::try_from("<".repeat(32).as_str()).unwrap(),
+        ..Settings::default()
+    };
+    let mut fixed = heapless::String::<{ form::PAGE_LEN }>::new();
+    form::page(&mut fixed, "esp32c6-v1", &crowded, Notice::Error(FormError::TooManyNetworks)).unwrap();
+
+    let mut saved = std::string::String::new();
+    form::page(&mut saved, "esp32c6-v1", &settings(), Notice::Saved).unwrap();
+    assert!(saved.contains("Saved."));
+    assert!(!saved.contains("<form"));
+}
+
+/// A DHCP message from `mac` of `kind`, with a requested address if given
+fn dhcp_message(mac: [u8; 6], kind: u8, requested: Option<[u8; 4]>, server: Option<[u8; 4]>) -> Vec<u8> {
+    let mut message = vec![0u8; 240];
+    message[..4].copy_from_slice(&[1, 1, 6, 0]);
+    message[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+    message[10] = 0x80;
+    message[28..34].copy_from_slice(&mac);
+    message[236..240].copy_from_slice(&[99, 130, 83, 99]);
+    message.extend_from_slice(&[53, 1, kind]);
+    if let Some(requested) = requested {
+        message.extend_from_slice(&[50, 4]);
+        message.extend_from_slice(&requested);
+    }
+    if let Some(server) = server {
+        message.extend_from_slice(&[54, 4]);
+        message.extend_from_slice(&server);
+    }
+    message.push(255);
+    message
+}
+
+/// (message type, offered address) from a reply
+fn dhcp_reply(reply: &[u8]) -> (u8, [u8; 4]) {
+    assert_eq!(reply[0], 2);
+    assert_eq!(&reply[4..8], &[0xde, 0xad, 0xbe, 0xef]);
+    let kind = reply[240..].windows(3).find(|w| w[0] == 53 && w[1] == 1).map(|w| w[2]).unwrap();
+    (kind, reply[16..20].try_into().unwrap())
+}
+
+const AP: [u8; 4] = [192, 168, 4, 1];
+const PHONE: [u8; 6] = [2, 0, 0, 0, 0, 1];
+const LAPTOP: [u8; 6] = [2, 0, 0, 0, 0, 2];
+
+#[test]
+fn dhcp_offers_and_acks_a_lease() {
+    let mut server = Server::<2>::new(AP, 2, 3600);
+    let mut reply = [0u8; dhcp::MAX_REPLY_LEN];
+
+    let len = server.reply(&dhcp_message(PHONE, 1, None, None), &mut reply).unwrap();
+    assert_eq!(dhcp_reply(&reply[..len]), (2, [192, 168, 4, 2]));
+    //router and dns both point back at us
+    assert!(reply[240..].windows(6).any(|w| w == [3, 4, 192, 168, 4, 1]));
+    assert!(reply[240..].windows(6).any(|w| w == [6, 4, 192, 168, 4, 1]));
+
+    let len = server.reply(&dhcp_message(PHONE, 3, Some([192, 168, 4, 2]), Some(AP)), &mut reply).unwrap();
+    assert_eq!(dhcp_reply(&reply[..len]), (5, [192, 168, 4, 2]));
+
+    //a second client gets its own address, the first keeps its
+    let len = server.reply(&dhcp_message(LAPTOP, 1, None, None), &mut reply).unwrap();
+    assert_eq!(dhcp_reply(&reply[..len]), (2, [192, 168, 4, 3]));
+    let len = server.reply(&dhcp_message(PHONE, 1, None, None), &mut reply).unwrap();
+    assert_eq!(dhcp_reply(&reply[..len]), (2, [192, 168, 4, 2]));
+}
+
+#[test]
+fn dhcp_naks_addresses_that_arent_theirs() {
+    let mut server = Server::<2>::new(AP, 2, 3600);
+    let mut reply = [0u8; dhcp::MAX_REPLY_LEN];
+
+    //remembered from another network
+    let len = server.reply(&dhcp_message(PHONE, 3, Some([10, 0, 0, 7]), None), &mut reply).unwrap();
+    assert_eq!(dhcp_reply(&reply[..len]).0, 6);
+
+    //took another server's offer
+    assert_eq!(server.reply(&dhcp_message(PHONE, 3, Some([192, 168, 4, 2]), Some([192, 168, 4, 9])), &mut reply), None);
+    //not a client's message at all
+    let mut bogus = dhcp_message(PHONE, 1, None, None);
+    bogus[0] = 2;
+    assert_eq!(server.reply(&bogus, &mut reply), None);
+}
+
+#[test]
+fn dhcp_reuses_the_least_recently_used_lease_when_full() {
+    let mut server = Server::<2>::new(AP, 2, 3600);
+    let mut reply = [0u8; dhcp::MAX_REPLY_LEN];
+
+    server.reply(&dhcp_message(PHONE, 1, None, None), &mut reply).unwrap();
+    server.reply(&dhcp_message(LAPTOP, 1, None, None), &mut reply).unwrap();
+    server.reply(&dhcp_message(PHONE, 1, None, None), &mut reply).unwrap();
+
+    let len = server.reply(&dhcp_message([2, 0, 0, 0, 0, 3], 1, None, None), &mut reply).unwrap();
+    assert_eq!(dhcp_reply(&reply[..len]), (2, [192, 168, 4, 3]));
+}
+
+/// A standard query (recursion desired) for `name` of `qtype`
+fn dns_query(name: &str, qtype: u16) -> Vec<u8> {
+    let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in name.split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes());
+    query
+}
+
+#[test]
+fn dns_answers_everything_with_our_address() {
+    let mut answer = [0u8; dns::MAX_MESSAGE_LEN];
+    let query = dns_query("connectivitycheck.gstatic.com", 1);
+    let len = dns::answer(&query, AP, &mut answer).unwrap();
+
+    assert_eq!(&answer[..2], &[0x12, 0x34]);
+    //a response, recursion desired and available, no error
+    assert_eq!(&answer[2..4], &[0x81, 0x80]);
+    assert_eq!(&answer[4..8], &[0, 1, 0, 1]);
+    assert_eq!(&answer[12..query.len()], &query[12..]);
+    assert_eq!(&answer[len - 4..len], &AP);
+    assert_eq!(len, query.len() + 16);
+
+    //AAAA gets no answers, so the client sticks to IPv4
+    let query = dns_query("example.com", 28);
+    let len = dns::answer(&query, AP, &mut answer).unwrap();
+    assert_eq!(&answer[6..8], &[0, 0]);
+    assert_eq!(len, query.len());
+
+    //responses aren't answered
+    let mut response = dns_query("example.com", 1);
+    response[2] |= 0x80;
+    assert_eq!(dns::answer(&response, AP, &mut answer), None);
+    assert_eq!(dns::answer(&query[..query.len() - 2], AP, &mut answer), None);
+}
@@ -0,0 +1,726 @@
+//! The firmware's broker session run against a scripted in-process broker.
+
+use std::{cell::RefCell, future::Future};
+
+use critical_section::Mutex;
+use embassy_futures::{block_on, join::join};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use mqtt_host_tests::{
+    fake::{Broker, Link, CONNECT, PINGREQ, PUBACK, PUBCOMP, PUBLISH, PUBREC, PUBREL, SUBSCRIBE},
+    mqtt::{
+        client::MqttClient,
+        health::{HealthFigures, HealthPolicy},
+        limit::{Limit, Limiter, Policy},
+        outbox::{Outbox, Outgoing},
+        packet::{Connect, Error, Properties, Property, QualityOfService},
+        router::{HandlerError, Message, Router},
+        session::{End, Event, Hooks, Rejection, Session},
+    },
+};
+use serde::Deserialize;
+
+const PING: Duration = Duration::from_millis(50);
+const PONG_TIMEOUT: Duration = Duration::from_millis(30);
+
+thread_local! {
+    //each test runs on its own thread, so handlers can't see each other's messages
+    static HANDLED: RefCell<Vec<(String, Vec<u8>)>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct Rgb {
+    r: u8,
+    g: u8,
+    b: u8,
+}
+
+/// like led::handle_light - json in, malformed if it isn't an RGB
+fn light(msg: &Message<'_>) -> Result<(), HandlerError> {
+    serde_json_core::from_slice::<Rgb>(msg.payload).map_err(|_| HandlerError::Malformed)?;
+    record(msg)
+}
+
+fn record(msg: &Message<'_>) -> Result<(), HandlerError> {
+    HANDLED.with(|handled| handled.borrow_mut().push((msg.topic.into(), msg.payload.into())));
+    Ok(())
+}
+
+fn handled() -> Vec<(String, Vec<u8>)> {
+    HANDLED.with(|handled| handled.borrow().clone())
+}
+
+fn router() -> Router<4> {
+    let mut router = Router::new();
+    router.add("test/light", QualityOfService::QoS1, light).unwrap();
+    router.add("test/sensors/+", QualityOfService::QoS1, record).unwrap();
+    router
+}
+
+fn connect() -> Connect<'static> {
+    Connect {
+        client_id: "host-test",
+        username: None,
+        password: None,
+        keep_alive: 60,
+        clean_start: true,
+        properties: &[],
+        will: None,
+    }
+}
+
+/// Hooks that remember what happened, optionally stopping the session after `stop_after` received messages
+#[derive(Default)]
+struct Recorder {
+    connected: bool,
+    received: Vec<String>,
+    rejected: Vec<(Option<String>, Rejection)>,
+    stop_after: Option<usize>,
+    /// the topic whose bodies are allowed to be binary
+    binary: Option<&'static str>,
+    /// payloads that fail `verify`
+    untrusted: Option<&'static [u8]>,
+    deferred: Vec<String>,
+    session_present: Option<bool>,
+    health: Vec<HealthFigures>,
+}
+
+impl Hooks for Recorder {
+    fn event(&mut self, event: Event<'_>) {
+        match event {
+            Event::Connected(connack) => {
+                self.connected = true;
+                self.session_present = Some(connack.session_present);
+            },
+            Event::Received(msg) => self.received.push(msg.topic.into()),
+            Event::Rejected { topic, reason, .. } => self.rejected.push((topic.map(Into::into), reason)),
+            Event::Deferred(topic) => self.deferred.push(topic.into()),
+            Event::Health(figures) => self.health.push(figures),
+            _ => {},
+        }
+    }
+
+    fn binary(&mut self, msg: &Message<'_>) -> bool {
+        self.binary == Some(msg.topic)
+    }
+
+    fn verify(&mut self, msg: &Message<'_>) -> Result<(), &'static str> {
+        match self.untrusted {
+            Some(payload) if payload == msg.payload => Err("untrusted"),
+            _ => Ok(()),
+        }
+    }
+
+    fn stop(&mut self) -> bool {
+        self.stop_after.is_some_and(|count| self.received.len() + self.rejected.len() >= count)
+    }
+}
+
+struct Fixture {
+    router: Router<4>,
+    outbox: Mutex<RefCell<Outbox<8>>>,
+    signal: Signal<CriticalSectionRawMutex, ()>,
+    limiter: RefCell<Limiter<4>>,
+}
+
+impl Fixture {
+    fn new() -> Self {
+        Self {
+            router: router(),
+            outbox: Mutex::new(RefCell::new(Outbox::new())),
+            signal: Signal::new(),
+            limiter: RefCell::new(Limiter::new()),
+        }
+    }
+
+    fn limited(limit: Limit) -> Self {
+        let fixture = Self::new();
+        fixture.limiter.borrow_mut().add(limit).unwrap();
+        fixture
+    }
+
+    fn session(&self) -> Session<'_, 4, 8> {
+        Session {
+            router: &self.router,
+            outbox: &self.outbox,
+            outbox_signal: &self.signal,
+            ping_interval: PING,
+            health: HealthPolicy { pong_timeout: PONG_TIMEOUT, max_missed: 2 },
+            birth: &[],
+            limiter: &self.limiter,
+        }
+    }
+
+    fn queue(&self, topic: &str, payload: &[u8], qos: QualityOfService) {
+        let msg = Outgoing::new(topic, payload, qos, false).unwrap();
+        critical_section::with(|cs| self.outbox.borrow_ref_mut(cs).push(msg));
+        self.signal.signal(());
+    }
+
+    fn outbox_len(&self) -> usize {
+        critical_section::with(|cs| self.outbox.borrow_ref(cs).len())
+    }
+
+    fn outbox_room(&self) -> usize {
+        critical_section::with(|cs| self.outbox.borrow_ref(cs).room())
+    }
+
+    fn outbox_dropped(&self) -> u32 {
+        critical_section::with(|cs| self.outbox.borrow_ref(cs).dropped())
+    }
+}
+
+/// Run a session on `link` alongside the broker `script`
+fn run<'a, F: Future<Output = ()>>(
+    fixture: &Fixture,
+    link: &'a Link,
+    hooks: &mut Recorder,
+    script: impl FnOnce(Broker<'a>) -> F,
+) -> End {
+    let (mut tx, mut rx) = ([0u8; 512], [0u8; 512]);
+    let mut client = MqttClient::new(link.client(), &mut tx, &mut rx);
+    let session = fixture.session();
+    let (end, ()) = block_on(join(session.run(&mut client, &connect(), hooks), script(link.broker())));
+    end
+}
+
+#[test]
+fn dispatches_to_matching_handlers() {
+    let (fixture, link, mut hooks) = (&Fixture::new(), &Link::new(), Recorder::default());
+
+    let end = run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept().await;
+        broker.publish("test/light", br#"{"r": 1, "g": 2, "b": 3}"#, QualityOfService::QoS0, false, 0).await;
+        broker.publish("test/sensors/kitchen", b"21.5", QualityOfService::QoS1, false, 7).await;
+        //QoS1 gets acked by the client
+        assert_eq!(broker.expect(PUBACK).await.pid(), 7);
+        broker.disconnect(0).await;
+    });
+
+    assert_eq!(end, End::Disconnected(0));
+    assert!(hooks.connected);
+    assert_eq!(
+        handled(),
+        vec![
+            ("test/light".into(), br#"{"r": 1, "g": 2, "b": 3}"#.to_vec()),
+            ("test/sensors/kitchen".into(), b"21.5".to_vec()),
+        ]
+    );
+}
+
+#[test]
+fn rejects_bad_payloads_and_keeps_going() {
+    let (fixture, link, mut hooks) = (&Fixture::new(), &Link::new(), Recorder::default());
+
+    let end = run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept().await;
+        broker.publish("test/light", b"{\"r\": 1", QualityOfService::QoS0, false, 0).await;
+        broker.publish("test/light", &[0xff, 0xfe], QualityOfService::QoS0, false, 0).await;
+        broker.publish("test/unknown", b"x", QualityOfService::QoS0, false, 0).await;
+        broker.publish("test/light", br#"{"r": 0, "g": 0, "b": 0}"#, QualityOfService::QoS0, false, 0).await;
+        broker.disconnect(0).await;
+    });
+
+    assert_eq!(end, End::Disconnected(0));
+    assert_eq!(
+        hooks.rejected,
+        vec![
+            (Some("test/light".into()), Rejection::Handler(HandlerError::Malformed)),
+            (Some("test/light".into()), Rejection::NotUtf8),
+            (Some("test/unknown".into()), Rejection::NoRoute),
+        ]
+    );
+    //the good message after the bad ones still got through
+    assert_eq!(handled().len(), 1);
+}
+
+#[test]
+fn binary_body_dispatched_when_hooks_allow() {
+    let (fixture, link) = (&Fixture::new(), &Link::new());
+    let mut hooks = Recorder { binary: Some("test/sensors/raw"), ..Default::default() };
+
+    let end = run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept().await;
+        broker.publish("test/sensors/raw", &[0xa1, 0x61, 0x72, 0xff], QualityOfService::QoS0, false, 0).await;
+        broker.publish("test/sensors/text", &[0xff], QualityOfService::QoS0, false, 0).await;
+        broker.disconnect(0).await;
+    });
+
+    assert_eq!(end, End::Disconnected(0));
+    assert_eq!(handled(), vec![("test/sensors/raw".into(), vec![0xa1, 0x61, 0x72, 0xff])]);
+    assert_eq!(hooks.rejected, vec![(Some("test/sensors/text".into()), Rejection::NotUtf8)]);
+}
+
+#[test]
+fn unverified_message_is_not_dispatched() {
+    let (fixture, link) = (&Fixture::new(), &Link::new());
+    let mut hooks = Recorder { untrusted: Some(b"evil"), ..Default::default() };
+
+    let end = run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept().await;
+        broker.publish("test/sensors/a", b"evil", QualityOfService::QoS0, false, 0).await;
+        broker.publish("test/sensors/b", b"fine", QualityOfService::QoS0, false, 0).await;
+        broker.disconnect(0).await;
+    });
+
+    assert_eq!(end, End::Disconnected(0));
+    assert_eq!(handled(), vec![("test/sensors/b".into(), b"fine".to_vec())]);
+    assert_eq!(hooks.rejected, vec![(Some("test/sensors/a".into()), Rejection::Unverified("untrusted"))]);
+}
+
+fn sensors_limit(burst: u8, every_ms: u64, policy: Policy) -> Limit {
+    Limit { filter: "test/sensors/+", burst, every: Duration::from_millis(every_ms), policy }
+}
+
+#[test]
+fn rate_limit_drops_the_excess() {
+    let (fixture, link, mut hooks) = (&Fixture::limited(sensors_limit(2, 10_000, Policy::Drop)), &Link::new(), Recorder::default());
+
+    let end = run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept().await;
+        for payload in [b"1", b"2", b"3", b"4"] {
+            broker.publish("test/sensors/a", payload, QualityOfService::QoS0, false, 0).await;
+        }
+        //other topics aren't limited
+        broker.publish("test/light", br#"{"r": 1, "g": 2, "b": 3}"#, QualityOfService::QoS0, false, 0).await;
+        broker.disconnect(0).await;
+    });
+
+    assert_eq!(end, End::Disconnected(0));
+    assert_eq!(handled().iter().map(|(_, payload)| payload.clone()).collect::<Vec<_>>(), vec![
+        b"1".to_vec(),
+        b"2".to_vec(),
+        br#"{"r": 1, "g": 2, "b": 3}"#.to_vec()
+    ]);
+    assert_eq!(hooks.rejected, vec![(Some("test/sensors/a".into()), Rejection::RateLimited); 2]);
+}
+
+#[test]
+fn rate_limit_coalesces_to_latest() {
+    let (fixture, link, mut hooks) = (&Fixture::limited(sensors_limit(1, 100, Policy::Coalesce)), &Link::new(), Recorder::default());
+
+    let end = run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept().await;
+        for payload in [b"1", b"2", b"3"] {
+            broker.publish("test/sensors/a", payload, QualityOfService::QoS0, false, 0).await;
+        }
+        broker.idle(Duration::from_millis(300)).await;
+        broker.disconnect(0).await;
+    });
+
+    assert_eq!(end, End::Disconnected(0));
+    //"2" was replaced by "3" while waiting for a token
+    assert_eq!(handled(), vec![("test/sensors/a".into(), b"1".to_vec()), ("test/sensors/a".into(), b"3".to_vec())]);
+    assert_eq!(hooks.rejected, vec![(Some("test/sensors/a".into()), Rejection::RateLimited)]);
+    assert_eq!(hooks.deferred.len(), 2);
+}
+
+#[test]
+fn rate_limit_queues_in_order() {
+    let (fixture, link, mut hooks) = (&Fixture::limited(sensors_limit(1, 50, Policy::Queue)), &Link::new(), Recorder::default());
+
+    let started = Instant::now();
+    let end = run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept().await;
+        for payload in [b"1", b"2", b"3"] {
+            broker.publish("test/sensors/a", payload, QualityOfService::QoS0, false, 0).await;
+        }
+        broker.idle(Duration::from_millis(300)).await;
+        broker.disconnect(0).await;
+    });
+
+    assert_eq!(end, End::Disconnected(0));
+    assert_eq!(handled().iter().map(|(_, payload)| payload.clone()).collect::<Vec<_>>(), vec![
+        b"1".to_vec(),
+        b"2".to_vec(),
+        b"3".to_vec()
+    ]);
+    assert!(hooks.rejected.is_empty());
+    assert_eq!(hooks.deferred.len(), 2);
+    assert!(started.elapsed() >= Duration::from_millis(100));
+}
+
+#[test]
+fn oversize_packet_is_skipped() {
+    let (fixture, link, mut hooks) = (&Fixture::new(), &Link::new(), Recorder::default());
+
+    let end = run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept().await;
+        broker.publish("test/sensors/big", &[b'x'; 900], QualityOfService::QoS0, false, 0).await;
+        broker.publish("test/sensors/small", b"1", QualityOfService::QoS0, false, 0).await;
+        broker.disconnect(0).await;
+    });
+
+    assert_eq!(end, End::Disconnected(0));
+    assert!(matches!(hooks.rejected.as_slice(), [(None, Rejection::TooLarge(_))]));
+    assert_eq!(handled(), vec![("test/sensors/small".into(), b"1".to_vec())]);
+}
+
+#[test]
+fn refused_connect() {
+    let (fixture, link, mut hooks) = (&Fixture::new(), &Link::new(), Recorder::default());
+
+    let end = run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.next().await;
+        //not authorised
+        broker.connack(0x87).await;
+    });
+
+    assert_eq!(end, End::ConnectFailed(Error::Refused(0x87)));
+    assert!(!hooks.connected);
+}
+
+#[test]
+fn broker_disconnect() {
+    let (fixture, link, mut hooks) = (&Fixture::new(), &Link::new(), Recorder::default());
+
+    let end = run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept().await;
+        //session taken over
+        broker.disconnect(0x8e).await;
+    });
+
+    assert_eq!(end, End::Disconnected(0x8e));
+}
+
+#[test]
+fn connection_dropped() {
+    let (fixture, link, mut hooks) = (&Fixture::new(), &Link::new(), Recorder::default());
+
+    let end = run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept().await;
+        link.close();
+    });
+
+    assert_eq!(end, End::Failed(Error::Closed));
+}
+
+#[test]
+fn pings_when_idle() {
+    let (fixture, link, mut hooks) = (&Fixture::new(), &Link::new(), Recorder::default());
+
+    let end = run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept().await;
+        let idle = Instant::now();
+        broker.expect(PINGREQ).await;
+        assert!(idle.elapsed() >= PING - Duration::from_millis(5));
+        broker.pingresp().await;
+        broker.expect(PINGREQ).await;
+        broker.disconnect(0).await;
+    });
+
+    assert_eq!(end, End::Disconnected(0));
+}
+
+#[test]
+fn ping_round_trip_measured() {
+    let (fixture, link, mut hooks) = (&Fixture::new(), &Link::new(), Recorder::default());
+
+    let end = run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept().await;
+        broker.expect(PINGREQ).await;
+        Timer::after_millis(10).await;
+        broker.pingresp().await;
+        //and the schedule carries on
+        broker.expect(PINGREQ).await;
+        broker.disconnect(0).await;
+    });
+
+    assert_eq!(end, End::Disconnected(0));
+    let [figures] = hooks.health[..] else { panic!("{:?}", hooks.health) };
+    assert!(figures.rtt.unwrap() >= Duration::from_millis(10));
+    assert_eq!((figures.rtt_avg, figures.rtt_max), (figures.rtt, figures.rtt));
+    assert_eq!((figures.pings, figures.missed), (1, 0));
+}
+
+#[test]
+fn unanswered_pings_end_session() {
+    let (fixture, link, mut hooks) = (&Fixture::new(), &Link::new(), Recorder::default());
+
+    //a half-open connection: our writes go through, nothing ever comes back
+    let end = run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept().await;
+        broker.expect(PINGREQ).await;
+        broker.expect(PINGREQ).await;
+    });
+
+    assert_eq!(end, End::Unresponsive);
+    let missed: Vec<_> = hooks.health.iter().map(|figures| figures.missed_in_row).collect();
+    assert_eq!(missed, [1, 2]);
+}
+
+#[test]
+fn late_pong_keeps_connection() {
+    let (fixture, link, mut hooks) = (&Fixture::new(), &Link::new(), Recorder::default());
+
+    let end = run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept().await;
+        //the first ping goes unanswered, the retry is answered in time
+        broker.expect(PINGREQ).await;
+        broker.expect(PINGREQ).await;
+        broker.pingresp().await;
+        broker.disconnect(0).await;
+    });
+
+    assert_eq!(end, End::Disconnected(0));
+    let missed: Vec<_> = hooks.health.iter().map(|figures| (figures.missed, figures.missed_in_row)).collect();
+    assert_eq!(missed, [(1, 1), (1, 0)]);
+}
+
+#[test]
+fn ping_failure_ends_session() {
+    let (fixture, link, mut hooks) = (&Fixture::new(), &Link::new(), Recorder::default());
+
+    let end = run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept().await;
+        link.break_writes();
+    });
+
+    assert!(matches!(end, End::PingFailed(_)));
+}
+
+#[test]
+fn outbox_replayed_and_acked() {
+    let (fixture, link, mut hooks) = (&Fixture::new(), &Link::new(), Recorder::default());
+    //queued while "offline"
+    fixture.queue("test/switch", b"queued", QualityOfService::QoS1);
+
+    let end = run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept().await;
+        let publish = broker.expect(PUBLISH).await;
+        assert_eq!(publish.publish(), ("test/switch".into(), b"queued".to_vec()));
+        broker.ack(PUBACK, publish.publish_pid()).await;
+
+        //and one queued while connected goes straight out
+        fixture.queue("test/switch", b"live", QualityOfService::QoS0);
+        let publish = broker.expect(PUBLISH).await;
+        assert_eq!(publish.publish().1, b"live");
+        broker.disconnect(0).await;
+    });
+
+    assert_eq!(end, End::Disconnected(0));
+    assert_eq!(fixture.outbox_len(), 0);
+}
+
+#[test]
+fn outbox_room_comes_back_as_publishes_are_acked() {
+    let (fixture, link, mut hooks) = (&Fixture::new(), &Link::new(), Recorder::default());
+    for payload in [b"1", b"2"] {
+        fixture.queue("test/switch", payload, QualityOfService::QoS1);
+    }
+    assert_eq!(fixture.outbox_room(), 6);
+
+    run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept().await;
+        let first = broker.expect(PUBLISH).await;
+        broker.expect(PUBLISH).await;
+        //sent isn't enough, they stay until the broker has them
+        assert_eq!(fixture.outbox_room(), 6);
+        broker.ack(PUBACK, first.publish_pid()).await;
+        broker.idle(PING / 2).await;
+        assert_eq!(fixture.outbox_room(), 7);
+        broker.disconnect(0).await;
+    });
+    assert_eq!(fixture.outbox_room(), 7);
+}
+
+#[test]
+fn in_flight_publishes_kept_to_receive_maximum() {
+    let (fixture, link, mut hooks) = (&Fixture::new(), &Link::new(), Recorder::default());
+    for payload in [b"1", b"2", b"3"] {
+        fixture.queue("test/switch", payload, QualityOfService::QoS1);
+    }
+
+    run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept_limited(2).await;
+        let first = broker.expect(PUBLISH).await;
+        assert_eq!(broker.expect(PUBLISH).await.publish().1, b"2");
+        //the third waits for a slot
+        broker.idle(PING * 2).await;
+        broker.ack(PUBACK, first.publish_pid()).await;
+        assert_eq!(broker.expect(PUBLISH).await.publish().1, b"3");
+        broker.disconnect(0).await;
+    });
+    assert_eq!(fixture.outbox_len(), 2);
+}
+
+#[test]
+fn full_outbox_of_in_flight_publishes_drops_the_new_one() {
+    let (fixture, link, mut hooks) = (&Fixture::new(), &Link::new(), Recorder::default());
+    for n in 0..8u8 {
+        fixture.queue("test/switch", &[b'0' + n], QualityOfService::QoS2);
+    }
+
+    run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept().await;
+        let mut pids = Vec::new();
+        for _ in 0..8 {
+            pids.push(broker.expect(PUBLISH).await.publish_pid());
+        }
+        //the broker holds QoS2 state for all 8, none of them can make way
+        fixture.queue("test/switch", b"late", QualityOfService::QoS1);
+        assert_eq!((fixture.outbox_len(), fixture.outbox_dropped()), (8, 1));
+
+        //so every ack still finds its message, and no id went to anything else
+        for pid in pids {
+            broker.ack(PUBREC, pid).await;
+            assert_eq!(broker.expect(PUBREL).await.pid(), pid);
+            broker.ack(PUBCOMP, pid).await;
+        }
+        broker.idle(PING / 2).await;
+        assert_eq!(fixture.outbox_len(), 0);
+        broker.disconnect(0).await;
+    });
+}
+
+#[test]
+fn full_outbox_drops_the_oldest_unsent_publish() {
+    let (fixture, link, mut hooks) = (&Fixture::new(), &Link::new(), Recorder::default());
+    for n in 0..8u8 {
+        fixture.queue("test/switch", &[b'0' + n], QualityOfService::QoS2);
+    }
+
+    run(fixture, link, &mut hooks, |mut broker| async move {
+        //7 go out, the 8th waits for a slot
+        broker.accept_limited(7).await;
+        let first = broker.expect(PUBLISH).await;
+        for _ in 1..7 {
+            broker.expect(PUBLISH).await;
+        }
+        fixture.queue("test/switch", b"late", QualityOfService::QoS2);
+        assert_eq!((fixture.outbox_len(), fixture.outbox_dropped()), (8, 1));
+
+        //the one the broker never saw made way, the in-flight ones carry on
+        broker.ack(PUBREC, first.publish_pid()).await;
+        assert_eq!(broker.expect(PUBREL).await.pid(), first.publish_pid());
+        broker.ack(PUBCOMP, first.publish_pid()).await;
+        assert_eq!(broker.expect(PUBLISH).await.publish().1, b"late");
+        broker.disconnect(0).await;
+    });
+    assert_eq!(fixture.outbox_len(), 7);
+}
+
+#[test]
+fn outgoing_properties_are_sent() {
+    let (fixture, link, mut hooks) = (&Fixture::new(), &Link::new(), Recorder::default());
+    let msg = Outgoing::new("test/state/eink", br#"{"data":"hi"}"#, QualityOfService::QoS0, true)
+        .unwrap()
+        .with_content_type("application/json")
+        .with_message_expiry(60)
+        .with_user_property("color", "black")
+        .unwrap();
+    critical_section::with(|cs| fixture.outbox.borrow_ref_mut(cs).push(msg));
+
+    run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept().await;
+        let publish = broker.expect(PUBLISH).await;
+        let raw = publish.publish_properties();
+        let properties: Vec<_> = Properties::from_raw(&raw).iter().collect();
+        assert_eq!(
+            properties,
+            [
+                Property::ContentType("application/json"),
+                Property::MessageExpiry(60),
+                Property::UserProperty("color", "black"),
+            ]
+        );
+        broker.disconnect(0).await;
+    });
+}
+
+#[test]
+fn unacked_publish_survives_reconnect() {
+    let fixture = &Fixture::new();
+    fixture.queue("test/switch", b"once", QualityOfService::QoS1);
+
+    //first connection goes away before the PUBACK
+    let (link, mut hooks) = (&Link::new(), Recorder::default());
+    run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept().await;
+        broker.expect(PUBLISH).await;
+        link.close();
+    });
+    assert_eq!(fixture.outbox_len(), 1);
+
+    //so it's sent again, flagged as a duplicate
+    let (link, mut hooks) = (&Link::new(), Recorder::default());
+    run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept().await;
+        let publish = broker.expect(PUBLISH).await;
+        assert_eq!(publish.flags & 0x08, 0x08);
+        broker.ack(PUBACK, publish.publish_pid()).await;
+        broker.disconnect(0).await;
+    });
+    assert_eq!(fixture.outbox_len(), 0);
+}
+
+#[test]
+fn stop_hook_ends_session() {
+    let (fixture, link) = (&Fixture::new(), &Link::new());
+    let mut hooks = Recorder { stop_after: Some(1), ..Default::default() };
+
+    let end = run(fixture, link, &mut hooks, |mut broker| async move {
+        broker.accept().await;
+        broker.publish("test/sensors/x", b"1", QualityOfService::QoS0, false, 0).await;
+    });
+
+    assert_eq!(end, End::Stopped);
+}
+
+#[test]
+fn birth_messages_follow_subscribe() {
+    let (fixture, link, mut hooks) = (&Fixture::new(), &Link::new(), Recorder::default());
+    fixture.queue("test/switch", b"queued", QualityOfService::QoS0);
+
+    let (mut tx, mut rx) = ([0u8; 512], [0u8; 512]);
+    let mut client = MqttClient::new(link.client(), &mut tx, &mut rx);
+    let session = Session { birth: &[("dev/$state", "ready"), ("dev/$name", "Test")], ..fixture.session() };
+
+    let script = async {
+        let mut broker = link.broker();
+        broker.accept().await;
+        for (topic, payload) in [("dev/$state", "ready"), ("dev/$name", "Test")] {
+            let publish = broker.expect(PUBLISH).await;
+            //retained
+            assert_eq!(publish.flags & 0x01, 0x01);
+            assert_eq!(publish.publish(), (topic.into(), payload.as_bytes().to_vec()));
+        }
+        //then the outbox
+        assert_eq!(broker.expect(PUBLISH).await.publish().1, b"queued");
+        broker.disconnect(0).await;
+    };
+    let (end, ()) = block_on(join(session.run(&mut client, &connect(), &mut hooks), script));
+
+    assert_eq!(end, End::Disconnected(0));
+}
+
+#[test]
+fn resumed_session_delivers_queued_messages() {
+    let (fixture, link, mut hooks) = (&Fixture::new(), &Link::new(), Recorder::default());
+
+    let (mut tx, mut rx) = ([0u8; 512], [0u8; 512]);
+    let mut client = MqttClient::new(link.client(), &mut tx, &mut rx);
+    let properties = [Property::SessionExpiry(300)];
+    let connect = Connect { clean_start: false, properties: &properties, ..connect() };
+
+    let script = async {
+        let mut broker = link.broker();
+        let connect = broker.expect(CONNECT).await;
+        //clean start bit clear, session expiry 300s
+        assert_eq!(connect.bytes[9] & 0x02, 0);
+        assert!(connect.bytes.windows(5).any(|w| w == [0x11, 0, 0, 0x01, 0x2c]));
+        //session present
+        broker.send(&[0x20, 3, 0x01, 0, 0]).await;
+        //the broker hands over what it queued for us straight away, before our SUBSCRIBE is answered
+        broker.publish("test/sensors/queued", b"1", QualityOfService::QoS1, false, 3).await;
+        broker.expect(SUBSCRIBE).await;
+        assert_eq!(broker.expect(PUBACK).await.pid(), 3);
+        broker.disconnect(0).await;
+    };
+    let (end, ()) = block_on(join(fixture.session().run(&mut client, &connect, &mut hooks), script));
+
+    assert_eq!(end, End::Disconnected(0));
+    assert_eq!(hooks.session_present, Some(true));
+    assert_eq!(handled(), vec![("test/sensors/queued".into(), b"1".to_vec())]);
+}
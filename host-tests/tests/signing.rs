@@ -0,0 +1,47 @@
+//! Replay protection for signed commands, within one boot and across a restart.
+
+use heapless::String;
+use mqtt_host_tests::signing::replay::{Seen, NONCES, REPLAY_WINDOW};
+
+fn accept(seen: &mut Seen, ts: u64, nonce: &str) -> Result<(), &'static str> {
+    seen.check(ts, nonce)?;
+    seen.remember(ts, String::try_from(nonce).unwrap());
+    Ok(())
+}
+
+#[test]
+fn nonce_is_only_accepted_once() {
+    let mut seen = Seen::restarted(0);
+    assert_eq!(accept(&mut seen, 1_000, "a"), Ok(()));
+    assert_eq!(accept(&mut seen, 1_000, "a"), Err("replayed"));
+    assert_eq!(accept(&mut seen, 1_000, "b"), Ok(()));
+}
+
+#[test]
+fn older_than_the_window_is_stale() {
+    let mut seen = Seen::restarted(0);
+    assert_eq!(accept(&mut seen, 10_000, "a"), Ok(()));
+    assert_eq!(accept(&mut seen, 10_000 - REPLAY_WINDOW, "b"), Ok(()));
+    assert_eq!(accept(&mut seen, 10_000 - REPLAY_WINDOW - 1, "c"), Err("stale"));
+}
+
+#[test]
+fn forgotten_nonces_take_their_timestamps_with_them() {
+    let mut seen = Seen::restarted(0);
+    for n in 0..NONCES as u64 + 1 {
+        accept(&mut seen, 1_000 + n, &n.to_string()).unwrap();
+    }
+    //"0" is forgotten, but can't come back
+    assert_eq!(accept(&mut seen, 1_000, "0"), Err("stale"));
+}
+
+#[test]
+fn command_accepted_before_a_restart_is_refused_after_it() {
+    let mut seen = Seen::restarted(0);
+    assert_eq!(accept(&mut seen, 1_000, "reboot"), Ok(()));
+
+    //what's kept through the restart
+    let mut seen = Seen::restarted(seen.newest());
+    assert_eq!(accept(&mut seen, 1_000, "reboot"), Err("stale"));
+    assert_eq!(accept(&mut seen, 1_001, "next"), Ok(()));
+}
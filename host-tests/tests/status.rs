@@ -0,0 +1,65 @@
+//! Network status: the events between one look at the network and the next, and the display line.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use mqtt_host_tests::wireless::status::{Addressing, Cidr, NetEvent, NetStatus};
+
+const LEASE: Cidr<Ipv4Addr> = Cidr { address: Ipv4Addr::new(192, 168, 0, 206), prefix_len: 16 };
+const SLAAC: Cidr<Ipv6Addr> = Cidr { address: Ipv6Addr::new(0x2001, 0xdb8, 0x1, 0x2, 0x424c, 0xcaff, 0xfe12, 0x3456), prefix_len: 64 };
+
+fn joined() -> NetStatus {
+    NetStatus { wifi: true, link: true, ..NetStatus::DOWN }
+}
+
+#[test]
+fn coming_up_is_wifi_then_link_then_addresses() {
+    let up = NetStatus { ipv4: Some(LEASE), ipv6: Some(SLAAC), ..joined() };
+    assert_eq!(
+        NetStatus::DOWN.changes(&up).as_slice(),
+        &[NetEvent::WifiConnected, NetEvent::LinkUp, NetEvent::Ipv4Up(LEASE, Addressing::Dhcp), NetEvent::Ipv6Up(SLAAC)]
+    );
+    assert!(up.is_up());
+    assert!(up.changes(&up).is_empty());
+
+    //and going down is the reverse
+    assert_eq!(
+        up.changes(&NetStatus::DOWN).as_slice(),
+        &[NetEvent::WifiDisconnected, NetEvent::LinkDown, NetEvent::Ipv4Down, NetEvent::Ipv6Down]
+    );
+    assert!(!NetStatus::DOWN.is_up());
+}
+
+#[test]
+fn lease_changes_are_events() {
+    let leased = NetStatus { ipv4: Some(LEASE), ..joined() };
+    assert_eq!(joined().changes(&leased).as_slice(), &[NetEvent::Ipv4Up(LEASE, Addressing::Dhcp)]);
+    assert_eq!(leased.changes(&joined()).as_slice(), &[NetEvent::Ipv4Down]);
+
+    let moved = Cidr { address: Ipv4Addr::new(192, 168, 0, 7), ..LEASE };
+    let renumbered = NetStatus { ipv4: Some(moved), ..leased };
+    assert_eq!(leased.changes(&renumbered).as_slice(), &[NetEvent::Ipv4Up(moved, Addressing::Dhcp)]);
+
+    //the same address, now static, on another network
+    let fixed = NetStatus { addressing: Addressing::Static, ..leased };
+    assert_eq!(leased.changes(&fixed).as_slice(), &[NetEvent::Ipv4Up(LEASE, Addressing::Static)]);
+    //where the next address will come from doesn't matter until there is one
+    assert!(joined().changes(&NetStatus { addressing: Addressing::Static, ..joined() }).is_empty());
+
+    assert!(NetEvent::Ipv4Down.is_address());
+    assert!(!NetEvent::LinkDown.is_address());
+}
+
+#[test]
+fn summary_prefers_ipv4() {
+    assert_eq!(NetStatus::DOWN.summary(), "No Wi-Fi");
+    assert_eq!(joined().summary(), "None");
+    assert_eq!(NetStatus { ipv6: Some(SLAAC), ..joined() }.summary(), "2001:db8:1:2:424c:caff:fe12:3456/64");
+    let both = NetStatus { ipv4: Some(LEASE), ipv6: Some(SLAAC), addressing: Addressing::Static, ..joined() };
+    assert_eq!(both.summary(), "192.168.0.206/16 static");
+    assert_eq!(LEASE.text(), "192.168.0.206/16");
+
+    //the longest there can be
+    let longest = Cidr { address: Ipv6Addr::new(0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff), prefix_len: 128 };
+    assert_eq!(longest.text().len(), 43);
+    assert_eq!(NetStatus { ipv6: Some(longest), ..joined() }.summary().len(), 43);
+}
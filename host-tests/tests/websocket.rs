@@ -0,0 +1,159 @@
+//! The WebSocket transport against a scripted server on the fake link.
+
+use embassy_futures::{block_on, join::join, select::select};
+use embedded_io_async::{Read, Write};
+use mqtt_host_tests::{
+    fake::{Broker, Link},
+    mqtt::ws::{base64, sha1, WebSocket, WsError},
+};
+
+/// Answer the client's upgrade request, returning the request
+async fn upgrade(server: &mut Broker<'_>) -> String {
+    let request = String::from_utf8(server.read_until(b"\r\n\r\n").await).unwrap();
+    let key = request
+        .lines()
+        .find_map(|line| line.strip_prefix("Sec-WebSocket-Key: "))
+        .unwrap();
+    let mut accept = [0u8; 28];
+    base64(&sha1(&[key.as_bytes(), b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11"]), &mut accept);
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nsec-websocket-accept: {}\r\n\r\n",
+        std::str::from_utf8(&accept).unwrap()
+    );
+    server.send(response.as_bytes()).await;
+    request
+}
+
+/// Read one masked frame from the client: (opcode, unmasked payload)
+async fn client_frame(server: &mut Broker<'_>) -> (u8, Vec<u8>) {
+    let head = server.read_exact(2).await;
+    assert_eq!(head[1] & 0x80, 0x80, "client frames must be masked");
+    let len = match head[1] & 0x7f {
+        126 => u16::from_be_bytes(server.read_exact(2).await.try_into().unwrap()) as usize,
+        127 => u64::from_be_bytes(server.read_exact(8).await.try_into().unwrap()) as usize,
+        len => len as usize,
+    };
+    let mask = server.read_exact(4).await;
+    let payload = server.read_exact(len).await.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]).collect();
+    (head[0] & 0x0f, payload)
+}
+
+#[test]
+fn sha1_and_base64_match_rfc_6455() {
+    //the worked example from section 1.3
+    let mut accept = [0u8; 28];
+    base64(&sha1(&[b"dGhlIHNhbXBsZSBub25jZQ==", b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11"]), &mut accept);
+    assert_eq!(&accept, b"s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+    let mut short = [0u8; 8];
+    base64(b"hiya", &mut short);
+    assert_eq!(&short, b"aGl5YQ==");
+}
+
+#[test]
+fn handshake_then_binary_frames_both_ways() {
+    let link = &Link::new();
+    let mut ws = WebSocket::new(link.client(), 1234);
+
+    let client = async {
+        ws.handshake("broker.example:8080", "/mqtt").await.unwrap();
+        ws.write_all(&[0x10; 300]).await.unwrap();
+
+        let mut buf = [0u8; 16];
+        ws.read_exact(&mut buf[..5]).await.unwrap();
+        assert_eq!(&buf[..5], b"hello");
+        //the ping is skipped over, then the next frame carries on the stream
+        ws.read_exact(&mut buf[..3]).await.unwrap();
+        assert_eq!(&buf[..3], b"mqt");
+        //the pong goes out ahead of the next write
+        ws.write_all(b"ack").await.unwrap();
+        //close ends the stream, and writing after it only sends the close back
+        assert_eq!(ws.read(&mut buf).await, Ok(0));
+        assert_eq!(ws.write_all(b"late").await, Err(WsError::Network));
+    };
+    let server = async {
+        let mut server = link.broker();
+        let request = upgrade(&mut server).await;
+        assert!(request.starts_with("GET /mqtt HTTP/1.1\r\n"));
+        assert!(request.contains("Host: broker.example:8080\r\n"));
+        assert!(request.contains("Sec-WebSocket-Protocol: mqtt\r\n"));
+
+        assert_eq!(client_frame(&mut server).await, (0x2, vec![0x10; 300]));
+
+        server.send(&[0x82, 5]).await;
+        server.send(b"hello").await;
+        server.send(&[0x89, 2, b'h', b'i']).await;
+        server.send(&[0x02, 3, b'm', b'q', b't']).await;
+        server.send(&[0x88, 2, 0x03, 0xe8]).await;
+
+        assert_eq!(client_frame(&mut server).await, (0xa, b"hi".to_vec()));
+        assert_eq!(client_frame(&mut server).await, (0x2, b"ack".to_vec()));
+        assert_eq!(client_frame(&mut server).await, (0x8, vec![0x03, 0xe8]));
+    };
+    block_on(join(client, server));
+}
+
+#[test]
+fn refused_upgrade_is_an_error() {
+    let link = &Link::new();
+    let mut ws = WebSocket::new(link.client(), 99);
+
+    let server = async {
+        let mut server = link.broker();
+        server.read_until(b"\r\n\r\n").await;
+        server.send(b"HTTP/1.1 403 Forbidden\r\n\r\n").await;
+    };
+    let (result, ()) = block_on(join(ws.handshake("broker", "/mqtt"), server));
+    assert_eq!(result, Err(WsError::Handshake("upgrade refused")));
+}
+
+#[test]
+fn wrong_accept_is_an_error() {
+    let link = &Link::new();
+    let mut ws = WebSocket::new(link.client(), 99);
+
+    let server = async {
+        let mut server = link.broker();
+        server.read_until(b"\r\n\r\n").await;
+        server.send(b"HTTP/1.1 101 Switching Protocols\r\nSec-WebSocket-Accept: nope\r\n\r\n").await;
+    };
+    let (result, ()) = block_on(join(ws.handshake("broker", "/mqtt"), server));
+    assert_eq!(result, Err(WsError::Handshake("bad Sec-WebSocket-Accept")));
+}
+
+#[test]
+fn masked_server_frame_is_a_protocol_error() {
+    let link = &Link::new();
+    let mut ws = WebSocket::new(link.client(), 7);
+
+    let client = async {
+        ws.handshake("broker", "/mqtt").await.unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(ws.read(&mut buf).await, Err(WsError::Protocol));
+    };
+    let server = async {
+        let mut server = link.broker();
+        upgrade(&mut server).await;
+        server.send(&[0x82, 0x81, 1, 2, 3, 4, 5]).await;
+    };
+    block_on(join(client, server));
+}
+
+#[test]
+fn read_dropped_mid_header_loses_nothing() {
+    let link = &Link::new();
+    let mut ws = WebSocket::new(link.client(), 5);
+    let mut server = link.broker();
+    block_on(join(ws.handshake("broker", "/mqtt"), upgrade(&mut server))).0.unwrap();
+
+    block_on(async {
+        //half a header, then the read loses a race (as poll() does to the ping timer)
+        server.send(&[0x82]).await;
+        let mut buf = [0u8; 8];
+        select(ws.read(&mut buf), core::future::ready(())).await;
+
+        server.send(&[3, b'a', b'b', b'c']).await;
+        ws.read_exact(&mut buf[..3]).await.unwrap();
+        assert_eq!(&buf[..3], b"abc");
+    });
+}
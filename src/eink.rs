@@ -1,20 +1,22 @@
 //use core::ops::Deref;
 
-use core::str::FromStr;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use alloc::format;
 use display_interface_spi::SPIInterface;
-use embassy_futures::select::{select3, Either3};
-use embedded_graphics::{mono_font::{MonoFont, MonoTextStyle}, prelude::{Point, Size}, primitives::{Circle, Line, PrimitiveStyle, Rectangle, StyledDrawable}, text::{Text, TextStyle}, Drawable};
+use embassy_futures::select::{select, select4, Either, Either4};
+use embedded_graphics::{mono_font::{MonoFont, MonoTextStyle}, prelude::{Point, Size}, primitives::{Circle, Line, PrimitiveStyle, Rectangle, StyledDrawable}, text::{Baseline, Text, TextStyle, TextStyleBuilder}, Drawable};
+use embassy_time::{Duration, Instant, Timer};
 use embedded_hal_bus::spi::ExclusiveDevice;
 use esp_hal::{gpio::{self, AnyPin, Input, Level, Output, Pull}, peripherals::SPI2, spi::master::Spi, spi::master::Config as SpiConfig, time::RateExtU32 };
 use esp_println::{print, println};
 use heapless::{String, Vec};
+use qrcodegen_no_heap::{QrCode, QrCodeEcc, Version};
 use profont::{PROFONT_10_POINT, PROFONT_12_POINT, PROFONT_14_POINT, PROFONT_18_POINT, PROFONT_24_POINT, PROFONT_7_POINT, PROFONT_9_POINT};
-use serde:: Deserialize;
+use serde::{Deserialize, Serialize};
 use weact_studio_epd::{graphics::{Display, Display290TriColor, DisplayRotation}, DisplayDriver, TriColor, WeActStudio290TriColorDriver};
 
-use crate::{read_ip_addr, read_mqtt_addr,  IP_UP_CHANNEL, MQTT_UP_CHANNEL, MSG_CHANNEL};
+use crate::{payload::{self, JSON_CONTENT_TYPE}, provision::{AccessPoint, AP_URL}, mqtt::{outbox::{Outgoing, OutboxError}, queue_outgoing, router::{HandlerError, Message}, Property}, telemetry::record_refresh, read_display_msg, read_mqtt_addr, read_net_status, rpc::send_screenshot, write_display_msg, NET_CHANNEL, MQTT_MAX_QOS, MQTT_UP_SIGNAL, MSG_CHANNEL, SCREENSHOT_SIGNAL};
 
 #[embassy_executor::task]
 pub async fn eink(
@@ -31,18 +33,21 @@ pub async fn eink(
     
     const MSG_TITLE_PT: Point = Point::new(MQTT_PT.x, SEP_LINE_Y + 15);
     const MSG_CONT_PT: Point = Point::new(MSG_TITLE_PT.x,MSG_TITLE_PT.y+20);
+    //message lines are drawn from the top of the area down, as many as fit
+    const MSG_AREA_TOP: i32 = MSG_CONT_PT.y - 13;
+    const MSG_AREA_WIDTH: u32 = 296 - 2 * MSG_CONT_PT.x as u32;
+    const DISPLAY_HEIGHT: i32 = 128;
     
     //UI Fonts
     const IP_TITLE_FONT: MonoTextStyle<TriColor> = MonoTextStyle::new(&PROFONT_12_POINT, TriColor::Black);
     const IP_ADDR_FONT: MonoTextStyle<TriColor> = MonoTextStyle::new(&PROFONT_12_POINT, TriColor::Red);
     const IP_DEL_FONT: MonoTextStyle<TriColor> = MonoTextStyle::new(&PROFONT_12_POINT, TriColor::White);
-    
-
-    const MAX_MSG_WIDTH: usize = 35;
+    const URGENT_TITLE_FONT: MonoTextStyle<TriColor> = MonoTextStyle::new(&PROFONT_12_POINT, TriColor::Red);
 
-    let ip_up_recv = IP_UP_CHANNEL.receiver();
-    let mqtt_up_recv = MQTT_UP_CHANNEL.receiver();
+    let mut net_events = NET_CHANNEL.subscriber().expect("too many NET_CHANNEL subscribers");
     let mqtt_msg_recv = MSG_CHANNEL.receiver();
+    //when the message showing lapses (its Message Expiry Interval), if it does
+    let mut expires: Option<Instant> = None;
 
     
    // clear display
@@ -70,47 +75,58 @@ pub async fn eink(
 
 
     loop {
-        let fut_a = ip_up_recv.receive();
-        let fut_b = mqtt_up_recv.receive();
+        //only address changes show - the radio and link underneath don't on their own
+        let fut_a = async {
+            loop {
+                if net_events.next_message_pure().await.is_address() {
+                    break;
+                }
+            }
+        };
+        let fut_b = MQTT_UP_SIGNAL.wait();
         let fut_c = mqtt_msg_recv.receive();
+        let fut_d = select(Timer::at(expires.unwrap_or(Instant::MAX)), SCREENSHOT_SIGNAL.wait());
         //when network is up - obtain/write IP addr
-        match select3(
+        let msg = match select4(
             fut_a, 
             fut_b, 
-            fut_c
+            fut_c,
+            fut_d
         ).await {
             //IP addr change
-             Either3::First(ip_up) => {
+             Either4::First(()) => {
+                //a lease lost and another got come together - one refresh shows where it ended up
+                while net_events.try_next_message_pure().is_some() {}
+                let status = read_net_status();
                 //clear prior circle
                 _ = Circle::with_center(Point { x: 296-20, y: 20 },15)
                         .draw_styled(&PrimitiveStyle::with_fill(TriColor::White), &mut display);
 
                 _ = Text::with_text_style("None",IP_ADDR_PT,IP_DEL_FONT,TextStyle::default()).draw(&mut display);
+                //the address line, up to the status circle (the mode after the address changes its length)
+                _ = Rectangle::new(Point { x: IP_ADDR_PT.x, y: 0 }, Size::new(296 - 40 - IP_ADDR_PT.x as u32, IP_ADDR_PT.y as u32 + 4))
+                        .draw_styled(&PrimitiveStyle::with_fill(TriColor::White), &mut display);
 
-                let text = match read_ip_addr() {
-                    Some(text) => text,
-                    None => String::<21>::from_str("None").unwrap(),     
-                };
+                let text = status.summary();
                 _ = Text::with_text_style(&text,IP_ADDR_PT,IP_ADDR_FONT,TextStyle::default()).draw(&mut display);
-                if ip_up {
+                if status.is_up() {
                     _ = Circle::with_center(Point { x: 296-20, y: 20 },15)
                         .draw_styled(&PrimitiveStyle::with_stroke(TriColor::White, 2), &mut display);
                 }
+                let started = Instant::now();
                 _ = driver.full_update(&mut display).await;
-
+                record_refresh(started);
+                continue;
 
             }, 
             //Mqtt connection change
-            Either3::Second(mqtt_up) => {
+            Either4::Second(mqtt_up) => {
             /*     _ = Rectangle::new(Point { x: MQTT_ADDR_PT.x - 1, y: MQTT_ADDR_PT.y - 1 }, Size::new(296-MQTT_ADDR_PT.x as u32 + 1, 14))
                     .draw_styled(&PrimitiveStyle::with_fill(TriColor::White),&mut display); */
                 _ = Text::with_text_style("None",IP_ADDR_PT,IP_DEL_FONT,TextStyle::default()).draw(&mut display);
                 _ = Text::with_text_style("Unconnected!",MQTT_ADDR_PT,IP_DEL_FONT,TextStyle::default()).draw(&mut display);
 
-                let ip_addr = match read_ip_addr() {
-                    Some(text) => text,
-                    None => String::<21>::from_str("None").unwrap(),     
-                };
+                let ip_addr = read_net_status().summary();
                 let mqtt_addr = match read_mqtt_addr() {
                     Some(text) => format!("{}:{}",text.0, text.1),
                     None => format!("Unconnected!"),
@@ -128,111 +144,334 @@ pub async fn eink(
                             .draw_styled(&PrimitiveStyle::with_fill(TriColor::White), &mut display);
                 }
 
+                let started = Instant::now();
                 _ = driver.full_update(&mut display).await;
+                record_refresh(started);
+                continue;
 
             },
 
             //Mqtt msg incoming
-            // TODO: replace vec with deque for msg for faster popping of 1st element of msg
-            Either3::Third(msg) => {
+            Either4::Third(msg) => {
                 println!("received eink bundle");
-   
-                //draw white rectangle over the "message" area (basically deletes a prior message without having to redraw all the other UI stuff)
-                _ = Rectangle::new(
-                    Point { x: MSG_CONT_PT.x - 5, y: MSG_CONT_PT.y - 15 }, 
-                    Size::new(296 - 5, 80)
-                    ).draw_styled(
-                        &PrimitiveStyle::with_fill(TriColor::White), 
-                        &mut display);
-
-                //split msg into lines of, at most, MAX_MSG_WIDTH chars (including spaces)
-                let lines = msg_to_vec_lines(msg, MAX_MSG_WIDTH);
+                msg
+            },
+            //the message showing has lapsed - clear it
+            Either4::Fourth(Either::First(())) => {
+                println!("eink message expired");
+                Msg::empty()
+            },
+            //rpc screenshot - every change is drawn and refreshed in one go, so the buffer is what's on the panel
+            Either4::Fourth(Either::Second(screenshot)) => {
+                send_screenshot(&screenshot, [("bw", display.bw_buffer()), ("red", display.red_buffer())]).await;
+                continue;
+            },
+        };
+        expires = msg.expires;
+
+        //draw white rectangles over the title and "message" area (basically deletes a prior message without having to redraw all the other UI stuff)
+        _ = Rectangle::new(
+            Point { x: MSG_TITLE_PT.x - 5, y: SEP_LINE_Y + 1 }, 
+            Size::new(296 - 5, (MSG_AREA_TOP - SEP_LINE_Y - 1) as u32)
+            ).draw_styled(
+                &PrimitiveStyle::with_fill(TriColor::White), 
+                &mut display);
+        _ = Rectangle::new(
+            Point { x: MSG_CONT_PT.x - 5, y: MSG_AREA_TOP }, 
+            Size::new(296 - 5, (DISPLAY_HEIGHT - MSG_AREA_TOP) as u32)
+            ).draw_styled(
+                &PrimitiveStyle::with_fill(TriColor::White), 
+                &mut display);
+
+        let title = match msg.style.priority {
+            Priority::High => Text::with_text_style("Urgent:",MSG_TITLE_PT,URGENT_TITLE_FONT,TextStyle::default()),
+            _ => Text::with_text_style("Message:",MSG_TITLE_PT,IP_TITLE_FONT,TextStyle::default()),
+        };
+        _ = title.draw(&mut display);
+
+        //remember what's on screen for anyone asking (e.g. rpc get_state),
+        //and leave it retained on the broker so it can be put back after a reboot
+        write_display_msg(Some(msg.clone()));
+        publish_state(&msg);
+
+        //split msg into lines as wide as the area allows in its font
+        let font: MonoFont<'static> = msg.style.font.into();
+        let char_width = font.character_size.width + font.character_spacing;
+        let line_height = font.character_size.height as i32;
+        let lines = msg_to_vec_lines(msg.text(), (MSG_AREA_WIDTH / char_width) as usize);
+        let text_style: MonoTextStyle<TriColor> = MonoTextStyle::new(&font, msg.style.color.into());
+        let top_left = TextStyleBuilder::new().baseline(Baseline::Top).build();
+
+        let mut y = MSG_AREA_TOP;
+
+        //send each line to the display, moving down a line each time, until the panel runs out
+        for line in lines {
+            if y + line_height > DISPLAY_HEIGHT {
+                break;
+            }
+            _ = Text::with_text_style(
+                &line,
+                Point::new(MSG_CONT_PT.x, y),
+                text_style,
+                top_left
+                    ).draw(&mut display);
 
+            y += line_height;
 
-                let mut y_offset = 0;
+        }
 
-                //send each line to the display, increasing the y_offset each time
-                for line in lines {
-                    _ = Text::with_text_style(
-                        &line,
-                        Point::new(MSG_CONT_PT.x, MSG_CONT_PT.y + y_offset),
-                        IP_ADDR_FONT,
-                        TextStyle::default()
-                            ).draw(&mut display);
+        //update display to show the finalised msg
+        let started = Instant::now();
+        _ = driver.full_update(&mut display).await;
+        record_refresh(started);
+ 
+    } //loop
 
-                    y_offset += 15;
+}
 
+/* 
+* -------------------------------------------------------------------------------------------------
+*
+*                   Provisioning screen:
+*
+*           How to reach the settings page - a QR code that joins the setup
+*           network on the left, and the same spelled out on the right. Drawn
+*           once, it stays put on the panel.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+#[embassy_executor::task]
+pub async fn provisioning(
+    mut driver: DisplayDriver<SPIInterface<ExclusiveDevice<Spi<'static, esp_hal::Async>, gpio::Output<'static>, embassy_time::Delay>, gpio::Output<'static>>, Input<'static>, gpio::Output<'static>, embassy_time::Delay, 128, 128, 296, TriColor>, 
+    mut display:  Display<128, 296, 9472, TriColor>,
+    ap: &'static AccessPoint){
+
+    //the code fills the panel's height, modules as big as will fit
+    const QR_AREA: i32 = 128;
+    //modules of white round the code - scanners want a margin
+    const QR_QUIET: i32 = 2;
+    const QR_MAX_VERSION: u8 = 6;
+    const TEXT_X: i32 = QR_AREA + 6;
+    const LINE_HEIGHT: i32 = 16;
+
+    const TITLE_FONT: MonoTextStyle<TriColor> = MonoTextStyle::new(&PROFONT_12_POINT, TriColor::Red);
+    const LABEL_FONT: MonoTextStyle<TriColor> = MonoTextStyle::new(&PROFONT_12_POINT, TriColor::Black);
+    const VALUE_FONT: MonoTextStyle<TriColor> = MonoTextStyle::new(&PROFONT_12_POINT, TriColor::Red);
+
+    display.clear(TriColor::White);
+
+    let join_code = ap.join_code();
+    let mut qr_buffer = [0u8; Version::new(QR_MAX_VERSION).buffer_len()];
+    let mut temp_buffer = [0u8; Version::new(QR_MAX_VERSION).buffer_len()];
+    match QrCode::encode_text(&join_code, &mut temp_buffer, &mut qr_buffer, QrCodeEcc::Low, Version::MIN, Version::new(QR_MAX_VERSION), None, true) {
+        Ok(qr) => {
+            let size = qr.size();
+            let scale = QR_AREA / (size + 2 * QR_QUIET);
+            let origin = (QR_AREA - size * scale) / 2;
+            for y in 0..size {
+                for x in 0..size {
+                    if qr.get_module(x, y) {
+                        _ = Rectangle::new(Point::new(origin + x * scale, origin + y * scale), Size::new_equal(scale as u32))
+                            .draw_styled(&PrimitiveStyle::with_fill(TriColor::Black), &mut display);
+                    }
                 }
+            }
+        },
+        Err(e) => println!("Can't make a QR code of {:?}: {:?}", join_code, e),
+    }
 
-                //update display to show the finalised msg
-                _ = driver.full_update(&mut display).await;
+    let top_left = TextStyleBuilder::new().baseline(Baseline::Top).build();
+    let lines = [
+        ("Setup mode", TITLE_FONT),
+        ("Join Wi-Fi:", LABEL_FONT),
+        (ap.ssid.as_str(), VALUE_FONT),
+        ("Password:", LABEL_FONT),
+        (ap.password.as_str(), VALUE_FONT),
+        ("then browse to", LABEL_FONT),
+        (AP_URL, VALUE_FONT),
+    ];
+    for (line, (text, font)) in lines.into_iter().enumerate() {
+        _ = Text::with_text_style(text, Point::new(TEXT_X, 4 + line as i32 * LINE_HEIGHT), font, top_left).draw(&mut display);
+    }
 
-            },
-        };
- 
-    } //loop
+    let started = Instant::now();
+    _ = driver.full_update(&mut display).await;
+    record_refresh(started);
+}
+
+
+/// longest message text we keep
+pub const MSG_LEN: usize = 300;
+/// largest "test/eink" payload we accept: the text plus json framing and a few escapes
+pub const MAX_PAYLOAD: usize = MSG_LEN + 64;
+
+/// where the message actually showing is published (retained), and restored from at boot
+pub const MSG_STATE_TOPIC: &str = "test/state/eink";
+
+/// set once a message has been sent to the display since boot, so a stale state message can't override it
+static MSG_SET: AtomicBool = AtomicBool::new(false);
+
+/// longest line of text - 7pt is the smallest font, and the narrowest
+const LINE_LEN: usize = 64;
+/// lines a message is wrapped into, at most (however many the font leaves room for are shown)
+const MAX_LINES: usize = 12;
+
+/// "test/eink" handler: {"data": "message text"} (or the same map in cbor, see payload.rs).
+///
+/// MQTT v5 properties on the message (none of them needed):
+///  - Message Expiry Interval clears the message again once it lapses
+///  - user properties `color` (black/red), `font` (7/9/10/12/14/18/24 point)
+///    and `priority` (low/normal/high) set how it's drawn without touching the json
+pub fn handle_msg(msg: &Message<'_>) -> Result<(), HandlerError> {
+    let new_msg = parse_msg(msg)?;
+    //a retained command comes round again on every (re)subscribe - don't redraw the panel for nothing
+    //(its expiry is counted from when it arrived, so that's left out of the comparison)
+    let showing = read_display_msg().is_some_and(|showing| showing.text() == new_msg.text() && showing.style == new_msg.style);
+    if msg.retain && MSG_SET.load(Ordering::Relaxed) && showing {
+        return Ok(());
+    }
+    show_msg(new_msg)
+}
+
+/// "test/state/eink" handler: puts back the message we last published, if nothing has replaced it since boot
+#[cfg_attr(feature = "signed", allow(dead_code))]
+pub fn restore_msg(msg: &Message<'_>) -> Result<(), HandlerError> {
+    //only the retained copy delivered on subscribe matters, not live echoes of our own state publishes
+    if !msg.retain || MSG_SET.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    println!("restoring eink message");
+    show_msg(parse_msg(msg)?)
+}
 
+fn parse_msg(msg: &Message<'_>) -> Result<Msg, HandlerError> {
+    let mut new_msg = payload::decode::<Msg>(msg)?;
+    new_msg.style = Style::from_properties(msg)?;
+    new_msg.expires = msg.properties.iter().find_map(|prop| match prop {
+        Property::MessageExpiry(secs) => Some(Instant::now() + Duration::from_secs(secs as u64)),
+        _ => None,
+    });
+    Ok(new_msg)
 }
 
+/// Send `msg` to the display - unless a more important message is showing, which only
+/// a message of at least its priority (or clearing the display) replaces
+pub fn show_msg(msg: Msg) -> Result<(), HandlerError> {
+    let outranked = read_display_msg()
+        .is_some_and(|showing| !showing.text().is_empty() && showing.style.priority > msg.style.priority);
+    if outranked && !msg.text().is_empty() {
+        return Err(HandlerError::Rejected("a higher priority message is showing"));
+    }
+    MSG_CHANNEL.try_send(msg).map_err(|_| HandlerError::Busy)?;
+    MSG_SET.store(true, Ordering::Relaxed);
+    Ok(())
+}
 
-fn msg_to_vec_lines<'a>(msg: Msg, max_len: usize) -> Vec<String<35>, 5> {
+/// Publish `msg` (retained) as the message currently on the display, with its style as user
+/// properties and whatever is left of its expiry, so a restore after reboot puts back the same thing
+fn publish_state(msg: &Msg) {
+    let mut buf = [0u8; MAX_PAYLOAD];
+    match serde_json_core::to_slice(msg, &mut buf) {
+        Ok(len) => queue_outgoing(MSG_STATE_TOPIC, state_message(msg, &buf[..len])),
+        Err(e) => println!("eink state serialisation error: {}", e),
+    }
+    #[cfg(feature = "homie")]
+    crate::homie::publish_message(msg);
+}
 
-    let mut msg = msg.data.split_ascii_whitespace().collect::<Vec<&str, 30>>();
-              
-    //vec to hold words from current line and a variable to hold character length of that line (including spaces)
-    let mut line_length = 0;
-    //TODO: keep this as vec as we are pushing to back, so deque gives no advantage
-    let mut line: Vec<&str, 30> = Vec::new();
-    let mut op: Vec<String<35>, 5> = Vec::new();
+fn state_message(msg: &Msg, payload: &[u8]) -> Result<Outgoing, OutboxError> {
+    let mut state = Outgoing::new(MSG_STATE_TOPIC, payload, MQTT_MAX_QOS, true)?.with_content_type(JSON_CONTENT_TYPE);
+    for (name, value) in msg.style.user_properties() {
+        state = state.with_user_property(name, value)?;
+    }
+    if let Some(expires) = msg.expires {
+        //the broker drops it when it lapses, so a reboot can't bring back an expired message
+        let left = expires.saturating_duration_since(Instant::now()).as_secs().max(1);
+        state = state.with_message_expiry(left.min(u32::MAX as u64) as u32);
+    }
+    Ok(state)
+}
 
-    
-    //loop while msg still has words left in it
-    loop {
-        
-        //get char length of current word (the 1st word in msg vector)
-        let word_len = 
-            if let Some(word) = msg.iter().nth(0) {
-                word.len()
-            } else {
-                0
-            };
-
-        //if current line length plus the next word length is less than width of display
-        //and we haven't already emptied msg, then:
-        // 1) remove the word from msg    
-        // 2) add word to current line
-        // 3) increase current line character length by the word length plus 1 (for the space character)
-        if line_length + word_len + 1 < max_len && !msg.is_empty(){
-            line.push(msg.remove(0)).unwrap();
-            line_length += word_len + 1;
-        //line is finsihed (as in adding more words exceeds display width, or no more words left to process in msg)
-        } else {
-            //add back spaces and collect current line vec into a string
-            let cur_line = line.join(" ");
-            //convert to String<35>
-            let cur_line = String::<35>::from_str(&cur_line).unwrap();
-
-            op.push(cur_line).unwrap();
-            
-            //if msg is empty, break out of loop to get driver to actually update the screen
-            if msg.is_empty() {
-                break;
-            //msg has words left to process, reset variables for next iteration of loop
-            } else {
-                //reset current line vec and length variables for next loop        
-                line.clear();
-                line_length = 0;
+/// Word wrap `text` into lines of at most `max_len` chars - a word longer than that is cut up,
+/// and anything past MAX_LINES is left off
+fn msg_to_vec_lines(text: &str, max_len: usize) -> Vec<String<LINE_LEN>, MAX_LINES> {
+    let max_len = max_len.clamp(1, LINE_LEN);
+    let mut lines: Vec<String<LINE_LEN>, MAX_LINES> = Vec::new();
+    let mut line: String<LINE_LEN> = String::new();
+    let mut line_chars = 0;
+
+    for word in text.split_whitespace() {
+        let word_chars = word.chars().count();
+        //start a new line if the word doesn't fit on what's left of this one
+        if line_chars > 0 && line_chars + 1 + word_chars > max_len {
+            if lines.push(core::mem::take(&mut line)).is_err() {
+                return lines;
+            }
+            line_chars = 0;
+        }
+        if line_chars > 0 {
+            _ = line.push(' ');
+            line_chars += 1;
+        }
+        for c in word.chars() {
+            //only a word longer than a whole line fills one up part way through
+            if line_chars == max_len {
+                if lines.push(core::mem::take(&mut line)).is_err() {
+                    return lines;
+                }
+                line_chars = 0;
             }
+            //multi-byte chars could overrun LINE_LEN bytes - those are dropped
+            _ = line.push(c);
+            line_chars += 1;
         }
     }
-    
-    op
-
+    if !line.is_empty() {
+        _ = lines.push(line);
+    }
+    lines
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Msg {
-    data: String<300>
+    data: String<MSG_LEN>,
+    /// how it's drawn - from the message's user properties, not the json
+    #[serde(skip)]
+    pub style: Style,
+    /// when it clears itself (from its Message Expiry Interval)
+    #[serde(skip)]
+    pub expires: Option<Instant>,
+}
+
+impl Msg {
+    /// `None` if `text` is longer than MSG_LEN
+    pub fn new(text: &str) -> Option<Self> {
+        String::try_from(text).ok().map(|data| Self { data, style: Style::default(), expires: None })
+    }
+
+    /// a message that clears the message area
+    pub fn empty() -> Self {
+        Self { data: String::new(), style: Style::default(), expires: None }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.data
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl<'b> payload::FromCbor<'b> for Msg {
+    fn from_cbor(buf: &'b [u8]) -> Result<Self, minicbor::decode::Error> {
+        let mut msg = None;
+        payload::cbor_map(buf, |key, d| {
+            match key {
+                "data" => msg = Some(Msg::new(d.str()?).ok_or(minicbor::decode::Error::message("data too long"))?),
+                _ => d.skip()?,
+            }
+            Ok(())
+        })?;
+        msg.ok_or(minicbor::decode::Error::message("expected data"))
+    }
 }
 /* 
 #[derive(Debug, Clone,  Deserialize)]
@@ -243,13 +482,88 @@ pub struct EINK {
     fontsize: MyFontSize
 } */
 
-#[derive(Debug, Clone, Copy, Deserialize)]
+/// How a message is drawn
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Style {
+    pub color: MyColor,
+    pub font: MyFontSize,
+    pub priority: Priority,
+}
+
+impl Style {
+    /// From the `color`, `font` and `priority` user properties - defaults for any left out
+    fn from_properties(msg: &Message<'_>) -> Result<Self, HandlerError> {
+        let mut style = Self::default();
+        for prop in msg.properties.iter() {
+            match prop {
+                Property::UserProperty("color", value) => {
+                    style.color = MyColor::from_name(value).ok_or(HandlerError::Rejected("unknown color"))?;
+                },
+                Property::UserProperty("font", value) => {
+                    style.font = MyFontSize::from_points(value).ok_or(HandlerError::Rejected("unknown font size"))?;
+                },
+                Property::UserProperty("priority", value) => {
+                    style.priority = Priority::from_name(value).ok_or(HandlerError::Rejected("unknown priority"))?;
+                },
+                _ => {},
+            }
+        }
+        Ok(style)
+    }
+
+    /// The user properties `from_properties` would turn back into this style
+    fn user_properties(&self) -> [(&'static str, &'static str); 3] {
+        [("color", self.color.name()), ("font", self.font.points()), ("priority", self.priority.name())]
+    }
+}
+
+/// Which message wins when two want the display
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    /// drawn under a red "Urgent:" title
+    High,
+}
+
+impl Priority {
+    fn from_name(name: &str) -> Option<Self> {
+        [Self::Low, Self::Normal, Self::High].into_iter().find(|priority| priority.name().eq_ignore_ascii_case(name.trim()))
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Low => "low",
+            Self::Normal => "normal",
+            Self::High => "high",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
 pub enum MyColor {
     Black,
     White,
+    #[default]
     Red
 }
 
+impl MyColor {
+    /// Text colours a message can ask for (white wouldn't show up)
+    fn from_name(name: &str) -> Option<Self> {
+        [Self::Black, Self::Red].into_iter().find(|color| color.name().eq_ignore_ascii_case(name.trim()))
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Black => "black",
+            Self::White => "white",
+            Self::Red => "red",
+        }
+    }
+}
+
 impl Into<TriColor> for MyColor {
     fn into(self) -> TriColor {
         match self {
@@ -290,17 +604,41 @@ impl Into<Point> for MyPoint {
     
 }
 
-#[derive(Debug, Clone, Copy, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
 pub enum MyFontSize {
     Pf24pt,
     Pf18pt,
     Pf14pt,
+    #[default]
     Pf12pt,
     Pf10pt,
     Pf9pt,
     Pf7pt
 }
 
+impl MyFontSize {
+    const ALL: [Self; 7] = [Self::Pf24pt, Self::Pf18pt, Self::Pf14pt, Self::Pf12pt, Self::Pf10pt, Self::Pf9pt, Self::Pf7pt];
+
+    /// From a point size ("12", or "12pt")
+    fn from_points(points: &str) -> Option<Self> {
+        let points = points.trim();
+        let points = points.strip_suffix("pt").unwrap_or(points);
+        Self::ALL.into_iter().find(|font| font.points() == points)
+    }
+
+    fn points(self) -> &'static str {
+        match self {
+            Self::Pf24pt => "24",
+            Self::Pf18pt => "18",
+            Self::Pf14pt => "14",
+            Self::Pf12pt => "12",
+            Self::Pf10pt => "10",
+            Self::Pf9pt => "9",
+            Self::Pf7pt => "7",
+        }
+    }
+}
+
 pub async fn display_init(disp_pins: DispPins) -> (
                     DisplayDriver<
                         SPIInterface<
@@ -0,0 +1,131 @@
+//Homie 4.0 device mode (cargo feature "homie"), alongside the plain "test/..." json topics
+
+use core::fmt::Write;
+
+use esp_println::println;
+use heapless::String;
+
+use crate::{
+    eink::{self, Msg},
+    input::Gesture,
+    led::{self, RGB},
+    mqtt::{queue_publish, router::{HandlerError, Message}},
+    payload::TEXT_CONTENT_TYPE,
+    MQTT_MAX_QOS,
+};
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   Homie device:
+*
+*           homie/<MQTT_ID>/            the device (MQTT_ID must be a valid homie id: a-z, 0-9, -)
+*               light/color             rgb "r,g,b", settable
+*               display/message         string, settable
+*               button/event            enum single,double,long,hold - not retained
+*
+*           The attributes below are published (retained) on every connect, framed by
+*           $state init/ready. The broker publishes $state "lost" for us if we vanish.
+*           Values are published whenever the hardware changes, and commands arrive
+*           on <property>/set.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+/// "homie/<MQTT_ID>/a/b..."
+macro_rules! topic {
+    ($($part:literal),+) => {
+        concat!("homie/", env!("MQTT_ID") $(, "/", $part)+)
+    };
+}
+
+pub const STATE_TOPIC: &str = topic!("$state");
+pub const COLOR_TOPIC: &str = topic!("light", "color");
+pub const COLOR_SET_TOPIC: &str = topic!("light", "color", "set");
+pub const MESSAGE_TOPIC: &str = topic!("display", "message");
+pub const MESSAGE_SET_TOPIC: &str = topic!("display", "message", "set");
+pub const EVENT_TOPIC: &str = topic!("button", "event");
+
+/// Everything a controller needs to discover the device, in publishing order
+pub const BIRTH: &[(&str, &str)] = &[
+    (STATE_TOPIC, "init"),
+    (topic!("$homie"), "4.0"),
+    (topic!("$name"), env!("MQTT_ID")),
+    (topic!("$nodes"), "light,display,button"),
+    (topic!("$extensions"), ""),
+
+    (topic!("light", "$name"), "Light"),
+    (topic!("light", "$type"), "ws2812"),
+    (topic!("light", "$properties"), "color"),
+    (topic!("light", "color", "$name"), "Colour"),
+    (topic!("light", "color", "$datatype"), "color"),
+    (topic!("light", "color", "$format"), "rgb"),
+    (topic!("light", "color", "$settable"), "true"),
+    (topic!("light", "color", "$retained"), "true"),
+
+    (topic!("display", "$name"), "Display"),
+    (topic!("display", "$type"), "eink"),
+    (topic!("display", "$properties"), "message"),
+    (topic!("display", "message", "$name"), "Message"),
+    (topic!("display", "message", "$datatype"), "string"),
+    (topic!("display", "message", "$settable"), "true"),
+    (topic!("display", "message", "$retained"), "true"),
+
+    (topic!("button", "$name"), "Button"),
+    (topic!("button", "$type"), "button"),
+    (topic!("button", "$properties"), "event"),
+    (topic!("button", "event", "$name"), "Event"),
+    (topic!("button", "event", "$datatype"), "enum"),
+    (topic!("button", "event", "$format"), "single,double,long,hold"),
+    (topic!("button", "event", "$settable"), "false"),
+    (topic!("button", "event", "$retained"), "false"),
+
+    (STATE_TOPIC, "ready"),
+];
+
+/// our last will, and what we publish ourselves before a clean disconnect
+pub const LOST: &[u8] = b"lost";
+pub const DISCONNECTED: &[u8] = b"disconnected";
+
+/// "light/color/set" handler: "r,g,b"
+pub fn handle_color(msg: &Message<'_>) -> Result<(), HandlerError> {
+    //commands to /set are never meant to be retained - don't replay a stale one on every reconnect
+    if msg.retain {
+        return Ok(());
+    }
+    let text = core::str::from_utf8(msg.payload).map_err(|_| HandlerError::Malformed)?;
+    let mut parts = text.trim().split(',').map(|part| part.trim().parse::<u8>());
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(r)), Some(Ok(g)), Some(Ok(b)), None) => led::set_light(RGB { r, g, b }),
+        _ => {
+            println!("malformed homie colour: {}", text);
+            Err(HandlerError::Malformed)
+        },
+    }
+}
+
+/// "display/message/set" handler: the raw message text
+pub fn handle_message(msg: &Message<'_>) -> Result<(), HandlerError> {
+    if msg.retain {
+        return Ok(());
+    }
+    let text = core::str::from_utf8(msg.payload).map_err(|_| HandlerError::Malformed)?;
+    let new_msg = Msg::new(text).ok_or(HandlerError::Rejected("message too long"))?;
+    eink::show_msg(new_msg)
+}
+
+pub fn publish_color(rgb: RGB) {
+    let mut payload: String<12> = String::new();
+    //"255,255,255" always fits
+    _ = write!(payload, "{},{},{}", rgb.r, rgb.g, rgb.b);
+    queue_publish(COLOR_TOPIC, payload.as_bytes(), TEXT_CONTENT_TYPE, MQTT_MAX_QOS, true);
+}
+
+pub fn publish_message(msg: &Msg) {
+    queue_publish(MESSAGE_TOPIC, msg.text().as_bytes(), TEXT_CONTENT_TYPE, MQTT_MAX_QOS, true);
+}
+
+pub fn publish_event(gesture: Gesture) {
+    queue_publish(EVENT_TOPIC, gesture.name().as_bytes(), TEXT_CONTENT_TYPE, MQTT_MAX_QOS, false);
+}
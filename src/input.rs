@@ -1,7 +1,44 @@
-//use embassy_time::{Duration, WithTimeout};
+use embassy_futures::select::{select, Either};
+use embassy_time::{Instant, Timer};
 use esp_hal::{handler,ram};
-//use esp_println::println;
-use super::BUTTON;
+use esp_println::println;
+use serde::Serialize;
+use super::{BUTTON, BTN_CHANNEL, MQTT_MAX_QOS};
+use crate::{mqtt::queue_publish, payload::JSON_CONTENT_TYPE};
+
+pub mod gestures;
+pub use gestures::Gesture;
+use gestures::{Event, Gestures};
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   Button gestures:
+*
+*           The interrupt fires on both edges and just timestamps them. button_task
+*           debounces (waits for the contact to settle, then reads the pin) and
+*           runs the result through a small state machine that turns presses into:
+*
+*               single  - a short press, with no second one following quickly
+*               double  - two short presses in quick succession
+*               long    - released after being held down for LONG_PRESS_MS or more
+*               hold    - still held down: first after HOLD_MS, then every HOLD_REPEAT_MS
+*                         (the release that ends a hold is reported as "long")
+*
+*           Each one is published on "test/switch" as
+*           {"button":9,"event":"single","at":12345,"duration":140}
+*           where `at` is when the gesture started and `duration` how long it has
+*           lasted, both in ms since boot.
+*
+*           Holding it down for PROVISION_HOLD_MS restarts into provisioning mode
+*           (see provision.rs) to change the Wi-Fi/MQTT settings.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+const BUTTON_PIN: u8 = 9;
+pub const SWITCH_TOPIC: &str = "test/switch";
 
 #[handler]
 #[ram]
@@ -15,8 +52,8 @@ pub fn gpio_int_handler() {
             .unwrap()
             .is_interrupt_set()
     }) {
-       // println!("GPIO9 triggered an interrupt");
-        matches!(sender.try_send(9), Ok(())); //sender.try_send is usable in non-async fn
+        //if the channel is full we're mid-bounce anyway, the pin gets read once it settles
+        _ = sender.try_send(Instant::now()); //sender.try_send is usable in non-async fn
     } 
 
     //clear the interrupt
@@ -27,4 +64,66 @@ pub fn gpio_int_handler() {
             .unwrap()
             .clear_interrupt()
     });
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ButtonEvent {
+    button: u8,
+    event: Gesture,
+    /// ms since boot the gesture started
+    at: u64,
+    /// ms it has lasted so far
+    duration: u64,
+}
+
+fn button_pressed() -> bool {
+    critical_section::with(|cs| BUTTON.borrow_ref(cs).as_ref().is_some_and(|btn| btn.is_low()))
+}
+
+/// Turns button interrupts into gesture messages on "test/switch" in the outbox.
+/// Runs independently of the mqtt connection so presses made while it's down still get sent later.
+#[embassy_executor::task]
+pub async fn button_task() {
+    let receiver = BTN_CHANNEL.receiver();
+    let mut gestures = Gestures::default();
+
+    loop {
+        let gesture = match gestures.deadline() {
+            Some(deadline) => match select(receiver.receive(), Timer::at(Instant::from_millis(deadline))).await {
+                Either::First(edge) => debounce(&mut gestures, edge).await,
+                Either::Second(()) => gestures.timeout(Instant::now().as_millis()),
+            },
+            None => {
+                let edge = receiver.receive().await;
+                debounce(&mut gestures, edge).await
+            },
+        };
+
+        if let Some((event, at, duration)) = gesture {
+            //once per press - holding on past it doesn't ask again
+            if event == Gesture::Hold && gestures.provision_due(at + duration) {
+                crate::provision::request("button held down");
+            }
+            publish(ButtonEvent { button: BUTTON_PIN, event, at, duration });
+        }
+    }
+}
+
+/// Let the contact settle, swallow the bounces, then see where the pin ended up
+async fn debounce(gestures: &mut Gestures, edge: Instant) -> Option<Event> {
+    let edge = edge.as_millis();
+    Timer::at(Instant::from_millis(Gestures::settle_at(edge))).await;
+    while BTN_CHANNEL.try_receive().is_ok() {}
+    gestures.settled(button_pressed(), edge)
+}
+
+fn publish(event: ButtonEvent) {
+    println!("button: {:?}", event);
+    let mut buf = [0u8; 96];
+    match serde_json_core::to_slice(&event, &mut buf) {
+        Ok(len) => queue_publish(SWITCH_TOPIC, &buf[..len], JSON_CONTENT_TYPE, MQTT_MAX_QOS, false),
+        Err(e) => println!("button event serialisation error: {}", e),
+    }
+    #[cfg(feature = "homie")]
+    crate::homie::publish_event(event.event);
+}
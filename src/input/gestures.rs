@@ -0,0 +1,157 @@
+use serde::{Serialize, Serializer};
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   Gesture state machine:
+*
+*           No hardware in here - button_task feeds it the pin once it has settled
+*           after an edge, and calls `timeout` when `deadline` passes. Times are ms
+*           since boot.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+/// edges closer together than this are contact bounce
+pub const DEBOUNCE_MS: u64 = 30;
+/// longest gap between two short presses that still counts as a double press
+pub const DOUBLE_GAP_MS: u64 = 300;
+pub const LONG_PRESS_MS: u64 = 500;
+pub const HOLD_MS: u64 = 1000;
+pub const HOLD_REPEAT_MS: u64 = 1000;
+pub const PROVISION_HOLD_MS: u64 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    Single,
+    Double,
+    Long,
+    Hold,
+}
+
+impl Gesture {
+    /// As published - in the JSON event and on its own (homie)
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Single => "single",
+            Self::Double => "double",
+            Self::Long => "long",
+            Self::Hold => "hold",
+        }
+    }
+}
+
+impl Serialize for Gesture {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+/// A gesture, when it started and how long it has lasted
+pub type Event = (Gesture, u64, u64);
+
+#[derive(Debug, Clone, Copy, Default)]
+enum State {
+    #[default]
+    Idle,
+    /// pressed at `since`, `holds` hold events sent so far, and whether it has asked for provisioning yet
+    Down { since: u64, holds: u64, provisioned: bool },
+    /// a short press ended at `released` - waiting to see if another follows
+    Released { since: u64, released: u64 },
+    /// second press of a double, the first went down at `since`
+    SecondDown { since: u64 },
+}
+
+impl State {
+    fn down(since: u64) -> Self {
+        Self::Down { since, holds: 0, provisioned: false }
+    }
+}
+
+/// Turns debounced press/release changes into gestures
+#[derive(Debug, Default)]
+pub struct Gestures {
+    state: State,
+    /// where the pin settled last time
+    pressed: bool,
+}
+
+impl Gestures {
+    /// When to read the pin after an edge at `edge` - anything before then is contact bounce
+    pub fn settle_at(edge: u64) -> u64 {
+        edge + DEBOUNCE_MS
+    }
+
+    /// The pin as read at `settle_at(edge)`. A bounce that ended where it started changes nothing.
+    pub fn settled(&mut self, pressed: bool, edge: u64) -> Option<Event> {
+        if pressed == self.pressed {
+            return None;
+        }
+        self.pressed = pressed;
+        self.change(pressed, edge)
+    }
+
+    /// The button went down (`pressed`) or up at `now`
+    fn change(&mut self, pressed: bool, now: u64) -> Option<Event> {
+        let (state, gesture) = match (self.state, pressed) {
+            (State::Idle, true) => (State::down(now), None),
+            (State::Down { since, holds, .. }, false) => {
+                let duration = now - since;
+                if holds > 0 || duration >= LONG_PRESS_MS {
+                    (State::Idle, Some((Gesture::Long, since, duration)))
+                } else {
+                    (State::Released { since, released: now }, None)
+                }
+            },
+            (State::Released { since, released }, true) if now - released <= DOUBLE_GAP_MS => {
+                (State::SecondDown { since }, None)
+            },
+            //too late to be a double (the timeout lost the race) - the first was a single
+            (State::Released { since, released }, true) => {
+                (State::down(now), Some((Gesture::Single, since, released - since)))
+            },
+            (State::SecondDown { since }, false) => (State::Idle, Some((Gesture::Double, since, now - since))),
+            //a missed edge - go with what the pin says now
+            (_, true) => (State::down(now), None),
+            (_, false) => (State::Idle, None),
+        };
+        self.state = state;
+        gesture
+    }
+
+    /// When `timeout` next needs calling, if at all
+    pub fn deadline(&self) -> Option<u64> {
+        match self.state {
+            State::Down { since, holds, .. } => Some(since + HOLD_MS + holds * HOLD_REPEAT_MS),
+            State::Released { released, .. } => Some(released + DOUBLE_GAP_MS),
+            State::Idle | State::SecondDown { .. } => None,
+        }
+    }
+
+    /// Call once `deadline` has passed
+    pub fn timeout(&mut self, now: u64) -> Option<Event> {
+        match self.state {
+            State::Down { since, holds, provisioned } => {
+                self.state = State::Down { since, holds: holds + 1, provisioned };
+                Some((Gesture::Hold, since, now - since))
+            },
+            State::Released { since, released } => {
+                self.state = State::Idle;
+                Some((Gesture::Single, since, released - since))
+            },
+            State::Idle | State::SecondDown { .. } => None,
+        }
+    }
+
+    /// Whether the press has now been held for PROVISION_HOLD_MS - true only the first time it's asked after that
+    pub fn provision_due(&mut self, now: u64) -> bool {
+        match &mut self.state {
+            State::Down { since, provisioned, .. } if !*provisioned && now - *since >= PROVISION_HOLD_MS => {
+                *provisioned = true;
+                true
+            },
+            _ => false,
+        }
+    }
+}
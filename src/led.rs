@@ -1,20 +1,104 @@
 //gpio8 for ws2812 LED
 
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use esp_hal_smartled::SmartLedsAdapter;
 use esp_println::println;
 use smart_leds::SmartLedsWrite;
 use smart_leds::RGB8;
-use serde::Deserialize;
-use crate::LED_CHANNEL;
+use serde::{Deserialize, Serialize};
+use embassy_futures::select::{select3, Either3};
+use crate::{payload, mqtt::{queue_publish, router::{HandlerError, Message}}, read_led_state, read_net_status, write_led_state, wireless::status::NetEvent, LED_BROKER_SIGNAL, LED_CHANNEL, MQTT_MAX_QOS, NET_CHANNEL};
 
 
-#[derive(Debug, Copy, Clone, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct RGB {
     pub r: u8,
     pub g: u8,
     pub b: u8,
 }
 
+#[cfg(feature = "cbor")]
+impl<'b> payload::FromCbor<'b> for RGB {
+    fn from_cbor(buf: &'b [u8]) -> Result<Self, minicbor::decode::Error> {
+        let (mut r, mut g, mut b) = (None, None, None);
+        payload::cbor_map(buf, |key, d| {
+            match key {
+                "r" => r = Some(d.u8()?),
+                "g" => g = Some(d.u8()?),
+                "b" => b = Some(d.u8()?),
+                _ => d.skip()?,
+            }
+            Ok(())
+        })?;
+        match (r, g, b) {
+            (Some(r), Some(g), Some(b)) => Ok(RGB { r, g, b }),
+            _ => Err(minicbor::decode::Error::message("expected r, g and b")),
+        }
+    }
+}
+
+/// largest "test/light" payload we accept
+pub const MAX_PAYLOAD: usize = 64;
+
+/// where the colour actually showing is published (retained), and restored from at boot
+pub const LIGHT_STATE_TOPIC: &str = "test/state/light";
+
+/// set once the LED has been given a colour since boot, so a stale state message can't override it
+static LIGHT_SET: AtomicBool = AtomicBool::new(false);
+
+/// shown while the Wi-Fi or link is down (the colour asked for comes back with the network)
+const NET_DOWN: RGB = RGB { r: 16, g: 0, b: 0 };
+/// shown while there's no broker (the colour asked for comes back on reconnecting)
+const BROKER_DOWN: RGB = RGB { r: 0, g: 0, b: 0 };
+
+/// "test/light" handler: {"r": 0-255, "g": 0-255, "b": 0-255} (or the same map in cbor, see payload.rs)
+pub fn handle_light(msg: &Message<'_>) -> Result<(), HandlerError> {
+    let rgb = parse_rgb(msg)?;
+    //a retained command comes round again on every (re)subscribe - only act on it if it changes something
+    if msg.retain && LIGHT_SET.load(Ordering::Relaxed) && read_led_state() == rgb {
+        return Ok(());
+    }
+    set_light(rgb)
+}
+
+/// "test/state/light" handler: puts back the colour we last published, if nothing has set one since boot
+#[cfg_attr(feature = "signed", allow(dead_code))]
+pub fn restore_light(msg: &Message<'_>) -> Result<(), HandlerError> {
+    //only the retained copy delivered on subscribe matters, not live echoes of our own state publishes
+    if !msg.retain || LIGHT_SET.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    println!("restoring led state");
+    set_light(parse_rgb(msg)?)
+}
+
+/// The LED goes dark while we've no broker - without touching the colour kept and published as its state
+pub fn broker_up(up: bool) {
+    LED_BROKER_SIGNAL.signal(up);
+}
+
+fn parse_rgb(msg: &Message<'_>) -> Result<RGB, HandlerError> {
+    payload::decode::<RGB>(msg)
+}
+
+pub fn set_light(rgb: RGB) -> Result<(), HandlerError> {
+    LED_CHANNEL.try_send(rgb).map_err(|_| HandlerError::Busy)?;
+    LIGHT_SET.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Publish `rgb` (retained) as the current LED state
+fn publish_state(rgb: RGB) {
+    let mut buf = [0u8; MAX_PAYLOAD];
+    match serde_json_core::to_slice(&rgb, &mut buf) {
+        Ok(len) => queue_publish(LIGHT_STATE_TOPIC, &buf[..len], payload::JSON_CONTENT_TYPE, MQTT_MAX_QOS, true),
+        Err(e) => println!("led state serialisation error: {}", e),
+    }
+    #[cfg(feature = "homie")]
+    crate::homie::publish_color(rgb);
+}
+
 impl Into<RGB8> for RGB {
     fn into(self) -> RGB8 {
         RGB8::new(self.r, self.g, self.b)
@@ -29,14 +113,38 @@ pub async fn led_task(
 ) {
 
     led.write([RGB8{r:0,g:0,b:0}].into_iter()).unwrap();
+    let mut net_events = NET_CHANNEL.subscriber().expect("too many NET_CHANNEL subscribers");
+
+    //no broker until the first connect
+    let mut broker = false;
 
     loop {
         let receiver = LED_CHANNEL.receiver();
-        let rgb = receiver.receive().await;
-        println!("received: {:?} in led_task",rgb);
-        let color: RGB8 = RGB8{r: rgb.g, g: rgb.r, b: rgb.b}; //RGB8 seems to be wired up wrong!
-        led.write([color].into_iter()).unwrap();
+        match select3(receiver.receive(), net_events.next_message_pure(), LED_BROKER_SIGNAL.wait()).await {
+            //only colours asked for get kept and published - never the network/broker down ones
+            Either3::First(rgb) => {
+                println!("received: {:?} in led_task",rgb);
+                write_led_state(rgb);
+                publish_state(rgb);
+            },
+            Either3::Second(NetEvent::WifiDisconnected | NetEvent::LinkDown | NetEvent::LinkUp) => {},
+            Either3::Second(_) => continue,
+            Either3::Third(up) => broker = up,
+        }
+        //a colour asked for while the network or broker is down shows once it's back
+        let net = read_net_status();
+        let rgb = match (net.wifi && net.link, broker) {
+            (false, _) => NET_DOWN,
+            (true, false) => BROKER_DOWN,
+            (true, true) => read_led_state(),
+        };
+        show(&mut led, rgb);
     }
+}
+
+fn show(led: &mut SmartLedsAdapter<esp_hal::rmt::Channel<esp_hal::Blocking, 0>, 25>, rgb: RGB) {
+    let color: RGB8 = RGB8{r: rgb.g, g: rgb.r, b: rgb.b}; //RGB8 seems to be wired up wrong!
+    led.write([color].into_iter()).unwrap();
 } 
 
 
@@ -8,7 +8,7 @@
 use alloc::format;
 use critical_section::Mutex;
 use eink::{ display_init, eink, DispPins, Msg};
-use embassy_net::{Ipv4Cidr, Ipv4Address};
+use embassy_net::IpAddress;
 use esp_alloc as _;
 use esp_hal::{
     clock::CpuClock, 
@@ -18,26 +18,33 @@ use esp_hal::{
     time::RateExtU32 
 };
 use embassy_executor::Spawner;
-use embassy_time::{Duration, Timer};
-use embassy_sync::{channel::Channel, blocking_mutex::raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant, Timer};
+use embassy_sync::{channel::Channel, blocking_mutex::raw::CriticalSectionRawMutex, pubsub::PubSubChannel};
 use heapless::String;
 use core::cell::RefCell;
 use lazy_static::lazy_static;
 //smartled rgb onboard
 use esp_hal_smartled::{smartLedBuffer, SmartLedsAdapter};
-use rust_mqtt::{
-    client::client_config::MqttVersion, 
-    packet::v5::publish_packet::QualityOfService
-};
+use embassy_sync::signal::Signal;
 extern crate alloc;
 
 //local modules
-mod input; use input::gpio_int_handler;
+mod input; use input::{button_task, gpio_int_handler};
 mod panic;
-mod wireless; use wireless::*;
-mod mqtt;
+mod wireless; use wireless::{*, status::{NetEvent, NetStatus}};
+mod mqtt; use mqtt::{outbox::Outbox, QualityOfService};
+mod mdns;
+mod rpc; use rpc::Screenshot;
+mod telemetry;
+mod report;
+mod payload;
+mod provision;
+#[cfg(feature = "signed")]
+mod signing;
 mod led; use led::*;
 mod eink;
+#[cfg(feature = "homie")]
+mod homie;
 mod mk_static;
 
 
@@ -53,7 +60,8 @@ mod mk_static;
 
 
 lazy_static! {
-    static ref BTN_CHANNEL: Channel<CriticalSectionRawMutex, u8, 1>
+    //when each button edge happened - debouncing and gestures are sorted out in button_task
+    static ref BTN_CHANNEL: Channel<CriticalSectionRawMutex, Instant, 8>
         = embassy_sync::channel::Channel::new();
 }
 lazy_static! {
@@ -61,90 +69,109 @@ lazy_static! {
         = embassy_sync::channel::Channel::new();
 }
 lazy_static! {
-    static ref MSG_CHANNEL: Channel<CriticalSectionRawMutex, Msg, 1>
+    //whether we're connected to a broker - the LED goes dark without one, which isn't a colour anyone asked for
+    static ref LED_BROKER_SIGNAL: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+}
+lazy_static! {
+    static ref MSG_CHANNEL: Channel<CriticalSectionRawMutex, Msg, 2>
         = embassy_sync::channel::Channel::new();
 }
 lazy_static! {
-    static ref IP_UP_CHANNEL: Channel<CriticalSectionRawMutex, bool, 1>= embassy_sync::channel::Channel::new();
+    //network status changes from wireless::monitor_task - to the display, LED and telemetry
+    static ref NET_CHANNEL: PubSubChannel<CriticalSectionRawMutex, NetEvent, 8, 3, 1> = PubSubChannel::new();
+}
+
+lazy_static! {
+    //whether we're connected to a broker - only the latest matters, so a lost-then-regained
+    //connection while the display is busy refreshing can't back up (or fail) the sender
+    static ref MQTT_UP_SIGNAL: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+}
+
+lazy_static! {
+    //an rpc "screenshot" for the eink task to send, as it's the one holding the framebuffer
+    static ref SCREENSHOT_SIGNAL: Signal<CriticalSectionRawMutex, Screenshot> = Signal::new();
 }
 
 lazy_static! {
-    static ref MQTT_UP_CHANNEL: Channel<CriticalSectionRawMutex, bool, 1>= embassy_sync::channel::Channel::new();
+    static ref OUTBOX_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 }
 
 static BUTTON: Mutex<RefCell<Option<Input>>> = Mutex::new(RefCell::new(None));
-static IP_ADDR: Mutex<RefCell<Option<String<21>>>> = Mutex::new(RefCell::new(None));
-static MQTT_ADDR: Mutex<RefCell<Option<(String<18>, String<4>)>>> = Mutex::new(RefCell::new(None));
-static MQTT_PING_TO: u8 = 30;
+static NET_STATUS: Mutex<RefCell<NetStatus>> = Mutex::new(RefCell::new(NetStatus::DOWN));
+//an IPv6 broker's address is kept in brackets, ready to have ":port" put after it
+static MQTT_ADDR: Mutex<RefCell<Option<(String<41>, String<5>)>>> = Mutex::new(RefCell::new(None));
+static LED_STATE: Mutex<RefCell<RGB>> = Mutex::new(RefCell::new(RGB { r: 0, g: 0, b: 0 }));
+static DISPLAY_MSG: Mutex<RefCell<Option<Msg>>> = Mutex::new(RefCell::new(None));
+//everything we publish, held until the broker has it (survives reconnects)
+static OUTBOX: Mutex<RefCell<Outbox<OUTBOX_LEN>>> = Mutex::new(RefCell::new(Outbox::new()));
 
-const MQTT_VER: MqttVersion = MqttVersion::MQTTv5;
-const MQTT_MAX_BUF_SIZE: usize = 240;
+const OUTBOX_LEN: usize = 8;
+const MQTT_KEEP_ALIVE: u16 = 120;
+//seconds between pings - four to a keep alive, so a missed PINGRESP is noticed (and another ping tried)
+//long before the broker would give up on us
+const MQTT_PING_TO: u16 = MQTT_KEEP_ALIVE / 4;
 const MQTT_MAX_QOS: QualityOfService = QualityOfService::QoS1;
 
-fn write_ip_addr(addr: Option<Ipv4Cidr>) {
-   // let sender = IP_UP_CHANNEL.sender();
-    match addr {
-        Some(addr) => {
-            critical_section::with(|cs| {
-            let mut st = String::<21>::new();
-            _ = st.push_str(&format!("{}/{}", addr.address(), addr.prefix_len()));
-            //*IP_ADDR.borrow_ref_mut(cs) = Some(st);
-            IP_ADDR.replace(cs, Some(st));
-            //sender.try_send(true).unwrap();
-            });
-        }
-        None => {
-            critical_section::with(|cs| {
-                //*IP_ADDR.borrow_ref_mut(cs) = None;
-                IP_ADDR.replace(cs, None);
-              //  sender.try_send(false).unwrap();
-            });
-        }       
-    }
+fn write_net_status(status: NetStatus) {
+    critical_section::with(|cs| NET_STATUS.replace(cs, status));
 }
 
-fn read_ip_addr() -> Option<String::<21>> {
-    let mut addr: Option<String::<21>> = None;
-    
-    critical_section::with(|cs| {
-        //addr = IP_ADDR.borrow_ref(cs).clone()
-        addr = IP_ADDR.take(cs);
+/// The network status as the monitor last saw it
+fn read_net_status() -> NetStatus {
+    critical_section::with(|cs| *NET_STATUS.borrow_ref(cs))
+}
 
+fn write_led_state(rgb: RGB) {
+    critical_section::with(|cs| {
+        *LED_STATE.borrow_ref_mut(cs) = rgb;
     });
+}
 
-    addr
+fn read_led_state() -> RGB {
+    critical_section::with(|cs| *LED_STATE.borrow_ref(cs))
 }
 
-fn write_mqtt_addr(addr: Option<(Ipv4Address, u16)>) {
-    let sender = MQTT_UP_CHANNEL.sender();
+fn write_display_msg(msg: Option<Msg>) {
+    critical_section::with(|cs| {
+        *DISPLAY_MSG.borrow_ref_mut(cs) = msg;
+    });
+}
 
+fn read_display_msg() -> Option<Msg> {
+    critical_section::with(|cs| DISPLAY_MSG.borrow_ref(cs).clone())
+}
+
+fn write_mqtt_addr(addr: Option<(IpAddress, u16)>) {
     match addr {
         Some((addr, port)) => {
             critical_section::with(|cs| {
                 //assemble addr
-                let mut addr_st = String::<18>::new();
-                _ = addr_st.push_str(&format!("{}", addr));
+                let mut addr_st = String::<41>::new();
+                _ = match addr {
+                    IpAddress::Ipv6(addr) => addr_st.push_str(&format!("[{}]", addr)),
+                    addr => addr_st.push_str(&format!("{}", addr)),
+                };
                 //assemble port
-                let mut port_st = String::<4>::new();
+                let mut port_st = String::<5>::new();
                 _ = port_st.push_str(&format!("{}", port));
         
                 *MQTT_ADDR.borrow_ref_mut(cs) = Some((addr_st, port_st));
-                sender.try_send(true).unwrap();
+                MQTT_UP_SIGNAL.signal(true);
             });
         }
         None => {
             critical_section::with(|cs| {
                 *MQTT_ADDR.borrow_ref_mut(cs) = None;
-                sender.try_send(false).unwrap();
+                MQTT_UP_SIGNAL.signal(false);
             });
         }
         
     }
 }
 
-fn read_mqtt_addr() -> Option<(String::<18>, String::<4>)> {
+fn read_mqtt_addr() -> Option<(String::<41>, String::<5>)> {
     
-    let mut addr: Option<(String::<18>, String::<4>)> = None;
+    let mut addr: Option<(String::<41>, String::<5>)> = None;
     
     critical_section::with(|cs| {
         addr = MQTT_ADDR.borrow_ref(cs).clone()
@@ -197,11 +224,11 @@ async fn main(spawner: Spawner) {
     let mut io = Io::new(peripherals.IO_MUX);
     io.set_interrupt_handler(gpio_int_handler);
     
-    //puts our input into a static with a falling edge listener.
+    //puts our input into a static with a press & release (any edge) listener.
     //we have designated input::gpio_int_handler as our handler (and tagged fn with #[interrupt])
     //now we can access BUTTON in a cs in the handler to react to events and clear interrupts
     critical_section::with(|cs| {
-        btn.listen(gpio::Event::FallingEdge);
+        btn.listen(gpio::Event::AnyEdge);
         BUTTON.borrow_ref_mut(cs).replace(btn);
     });    
     
@@ -242,11 +269,37 @@ async fn main(spawner: Spawner) {
     * ----------------------------------------------------------------------
     */
     let timg0 = esp_hal::timer::timg::TimerGroup::new(peripherals.TIMG0);
+    //shared by wifi and anything else that needs randomness (websocket masking)
+    let mut rng = esp_hal::rng::Rng::new(peripherals.RNG);
+
+    //no settings yet, or asked for: run the setup access point instead (see provision.rs)
+    if provision::init() {
+        let ap = provision::access_point(&mut rng);
+        let (wifi_controller, stack, runner) = 
+                    start_ap(
+                        peripherals.WIFI, 
+                        peripherals.RADIO_CLK, 
+                        rng, 
+                        timg0
+                    );
+
+        spawner.spawn(eink::provisioning(driver, display, ap)).ok();
+        spawner.spawn(wireless::ap_connection(wifi_controller, ap)).ok();
+        spawner.spawn(wireless::ap_net_task(runner)).ok();
+        spawner.spawn(provision::portal_task(stack)).ok();
+        spawner.spawn(provision::dhcp_task(stack)).ok();
+        spawner.spawn(provision::dns_task(stack)).ok();
+
+        loop {
+            Timer::after(Duration::from_millis(1000)).await;
+        }
+    }
+
     let (wifi_controller, stack, runner) = 
                 start_wifi(
                     peripherals.WIFI, 
                     peripherals.RADIO_CLK, 
-                    peripherals.RNG, 
+                    rng.clone(), 
                     timg0
                 );
     
@@ -260,10 +313,14 @@ async fn main(spawner: Spawner) {
 
     spawner.spawn(eink(driver, display)).ok();
     Timer::after_secs(2).await;
-    spawner.spawn(wireless::connection(wifi_controller)).ok();
+    spawner.spawn(wireless::connection(wifi_controller, stack)).ok();
     spawner.spawn(wireless::net_task(runner)).ok();
-    spawner.spawn(mqtt::mqtt_task(stack)).ok();
+    spawner.spawn(wireless::slaac_task(stack)).ok();
+    spawner.spawn(wireless::monitor_task(stack)).ok();
+    spawner.spawn(mqtt::mqtt_task(stack, rng)).ok();
     spawner.spawn(led_task(led)).ok();
+    spawner.spawn(button_task()).ok();
+    spawner.spawn(telemetry::telemetry_task()).ok();
     
     /* 
     * ----------------------------------------------------
@@ -0,0 +1,83 @@
+use embassy_net::{udp::{PacketMetadata, UdpSocket}, Ipv4Address, Stack};
+use embassy_time::{with_timeout, Duration, Instant};
+use esp_println::println;
+
+pub mod browse;
+use browse::{build_query, Browse, Name, MQTT_SERVICE, TYPE_PTR};
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   mDNS/DNS-SD broker discovery:
+*
+*           1) Send a one-shot PTR query for _mqtt._tcp.local to 224.0.0.251:5353
+*              from an ephemeral port (responders then answer us unicast, RFC 6762 6.7,
+*              so we don't need to join the multicast group)
+*           2) Collect PTR/SRV/A records from the answers for a short window
+*           3) Ask again specifically for any SRV/A records the responders left out
+*           4) Pick the matching instance with the best SRV priority
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+const MDNS_ADDR: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+const MDNS_WINDOW: Duration = Duration::from_millis(1500);
+const MDNS_ROUNDS: u8 = 3;
+
+/// Browse for `_mqtt._tcp.local` and return the address/port of the best instance matching `pattern`
+pub async fn discover_broker(stack: Stack<'static>, pattern: &str) -> Option<(Ipv4Address, u16)> {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; 128];
+
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    if let Err(e) = socket.bind(0) {
+        println!("mDNS bind error: {:?}", e);
+        return None;
+    }
+
+    let mut browse = Browse::default();
+    let mut pkt = [0u8; 1024];
+    let (mut qname, mut qtype) = (MQTT_SERVICE, TYPE_PTR);
+    let mut qname_buf = Name::new();
+
+    for _ in 0..MDNS_ROUNDS {
+        let len = build_query(&mut pkt, qname, qtype)?;
+        if let Err(e) = socket.send_to(&pkt[..len], (MDNS_ADDR, MDNS_PORT)).await {
+            println!("mDNS send error: {:?}", e);
+            return None;
+        }
+
+        //collect whatever answers turn up within the window
+        let deadline = Instant::now() + MDNS_WINDOW;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            match with_timeout(remaining, socket.recv_from(&mut pkt)).await {
+                Ok(Ok((len, _))) => browse.parse(&pkt[..len]),
+                Ok(Err(_)) => continue,
+                Err(_) => break,
+            }
+        }
+
+        if let Some((instance, addr, port)) = browse.best(pattern) {
+            println!("mDNS found MQTT broker {:?} at {}:{}", instance.label(), addr, port);
+            return Some((addr, port));
+        }
+
+        //ask for whatever the responders didn't volunteer, or browse again
+        match browse.missing(pattern) {
+            Some((name, t)) => {
+                qname_buf.clear();
+                _ = qname_buf.push_str(name);
+                qname = &qname_buf;
+                qtype = t;
+            },
+            None => (qname, qtype) = (MQTT_SERVICE, TYPE_PTR),
+        }
+    }
+
+    println!("mDNS found no MQTT broker matching {:?}", pattern);
+    None
+}
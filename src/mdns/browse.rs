@@ -0,0 +1,237 @@
+use core::net::Ipv4Addr;
+
+use heapless::{String, Vec};
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   mDNS/DNS-SD packets:
+*
+*           Building the queries and piecing PTR/SRV/A records together from the
+*           answers. Anyone on the network can multicast these, so every length and
+*           name pointer is checked against the packet, and pointer chains are cut
+*           off before they can loop.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+pub const MQTT_SERVICE: &str = "_mqtt._tcp.local";
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+pub type Name = String<128>;
+
+/// A `_mqtt._tcp` service instance as pieced together from PTR/SRV/A records
+#[derive(Debug, Clone, Default)]
+pub struct Instance {
+    /// full instance name, e.g. `office broker._mqtt._tcp.local`
+    pub name: Name,
+    /// host the SRV record points at, e.g. `pi4.local`
+    pub target: Option<Name>,
+    pub port: Option<u16>,
+    pub priority: u16,
+}
+
+impl Instance {
+    /// instance name without the `._mqtt._tcp.local` service suffix
+    pub fn label(&self) -> &str {
+        strip_suffix_ignore_case(&self.name, MQTT_SERVICE)
+            .map(|label| label.trim_end_matches('.'))
+            .unwrap_or(&self.name)
+    }
+}
+
+/// Everything learned from the responses received so far
+#[derive(Debug, Default)]
+pub struct Browse {
+    pub instances: Vec<Instance, 4>,
+    pub hosts: Vec<(Name, Ipv4Addr), 4>,
+}
+
+impl Browse {
+    /// Feed one received mDNS packet into the browse results.
+    /// Truncated or malformed packets are ignored from the point they stop making sense.
+    pub fn parse(&mut self, pkt: &[u8]) {
+        _ = self.try_parse(pkt);
+    }
+
+    fn try_parse(&mut self, pkt: &[u8]) -> Option<()> {
+        let qd = read_u16(pkt, 4)?;
+        let rr = read_u16(pkt, 6)? as usize + read_u16(pkt, 8)? as usize + read_u16(pkt, 10)? as usize;
+        let mut pos = 12;
+
+        //skip any questions (legacy unicast replies echo ours back)
+        for _ in 0..qd {
+            pos = skip_name(pkt, pos)? + 4;
+        }
+
+        for _ in 0..rr {
+            let mut owner = Name::new();
+            pos = read_name(pkt, pos, &mut owner)?;
+            let rtype = read_u16(pkt, pos)?;
+            let class = read_u16(pkt, pos + 2)? & 0x7fff; //top bit is the mdns cache-flush flag
+            let rdlen = read_u16(pkt, pos + 8)? as usize;
+            let rdata = pos + 10;
+            pos = rdata + rdlen;
+            if pos > pkt.len() || class != CLASS_IN {
+                continue;
+            }
+
+            match rtype {
+                TYPE_PTR if owner.eq_ignore_ascii_case(MQTT_SERVICE) => {
+                    let mut name = Name::new();
+                    read_name(pkt, rdata, &mut name)?;
+                    self.instance_mut(&name);
+                },
+                TYPE_SRV if strip_suffix_ignore_case(&owner, MQTT_SERVICE).is_some() => {
+                    let priority = read_u16(pkt, rdata)?;
+                    let port = read_u16(pkt, rdata + 4)?;
+                    let mut target = Name::new();
+                    read_name(pkt, rdata + 6, &mut target)?;
+                    if let Some(instance) = self.instance_mut(&owner) {
+                        instance.priority = priority;
+                        instance.port = Some(port);
+                        instance.target = Some(target);
+                    }
+                },
+                TYPE_A if rdlen == 4 => {
+                    let addr = Ipv4Addr::new(pkt[rdata], pkt[rdata + 1], pkt[rdata + 2], pkt[rdata + 3]);
+                    if let Some(host) = self.hosts.iter_mut().find(|(host, _)| host.eq_ignore_ascii_case(&owner)) {
+                        host.1 = addr;
+                    } else {
+                        _ = self.hosts.push((owner, addr));
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        Some(())
+    }
+
+    fn instance_mut(&mut self, name: &str) -> Option<&mut Instance> {
+        if let Some(idx) = self.instances.iter().position(|i| i.name.eq_ignore_ascii_case(name)) {
+            return self.instances.get_mut(idx);
+        }
+        let mut instance = Instance::default();
+        instance.name.push_str(name).ok()?;
+        self.instances.push(instance).ok()?;
+        self.instances.last_mut()
+    }
+
+    pub fn host_addr(&self, host: &str) -> Option<Ipv4Addr> {
+        self.hosts.iter().find(|(name, _)| name.eq_ignore_ascii_case(host)).map(|(_, addr)| *addr)
+    }
+
+    /// The matching instance with a known address and port, lowest SRV priority first
+    pub fn best(&self, pattern: &str) -> Option<(&Instance, Ipv4Addr, u16)> {
+        self.instances
+            .iter()
+            .filter(|i| instance_matches(pattern, i.label()))
+            .filter_map(|i| {
+                let addr = self.host_addr(i.target.as_ref()?)?;
+                Some((i, addr, i.port?))
+            })
+            .min_by_key(|(i, _, _)| i.priority)
+    }
+
+    /// Next query worth sending: SRV for a matching instance we have no target for,
+    /// or A for a target we have no address for
+    pub fn missing(&self, pattern: &str) -> Option<(&str, u16)> {
+        self.instances
+            .iter()
+            .filter(|i| instance_matches(pattern, i.label()))
+            .find_map(|i| match &i.target {
+                None => Some((i.name.as_str(), TYPE_SRV)),
+                Some(target) if self.host_addr(target).is_none() => Some((target.as_str(), TYPE_A)),
+                _ => None,
+            })
+    }
+}
+
+/// Instance-name matching for `MQTT_MDNS`:
+/// `""` or `"*"` matches anything, `"office*"` is a prefix match, anything else must match exactly.
+/// All comparisons ignore ascii case.
+pub fn instance_matches(pattern: &str, label: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        _ if pattern.is_empty() => true,
+        Some(prefix) => label.len() >= prefix.len()
+            && label.is_char_boundary(prefix.len())
+            && label[..prefix.len()].eq_ignore_ascii_case(prefix),
+        None => label.eq_ignore_ascii_case(pattern),
+    }
+}
+
+/// Writes a single-question mdns query into `buf`, returning the packet length
+pub fn build_query(buf: &mut [u8], name: &str, qtype: u16) -> Option<usize> {
+    //id 0, no flags, one question
+    let header = [0u8, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    buf.get_mut(..12)?.copy_from_slice(&header);
+    let mut pos = 12;
+
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return None;
+        }
+        *buf.get_mut(pos)? = label.len() as u8;
+        buf.get_mut(pos + 1..pos + 1 + label.len())?.copy_from_slice(label.as_bytes());
+        pos += 1 + label.len();
+    }
+    *buf.get_mut(pos)? = 0;
+    buf.get_mut(pos + 1..pos + 5)?.copy_from_slice(&[(qtype >> 8) as u8, qtype as u8, 0, CLASS_IN as u8]);
+
+    Some(pos + 5)
+}
+
+fn read_u16(pkt: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*pkt.get(pos)?, *pkt.get(pos + 1)?]))
+}
+
+fn skip_name(pkt: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *pkt.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            l if l & 0xc0 == 0xc0 => return Some(pos + 2),
+            l => pos += 1 + l as usize,
+        }
+    }
+}
+
+/// Reads a (possibly compressed) name at `pos` into `out` as dotted text.
+/// Returns the position just after the name in the original record.
+fn read_name(pkt: &[u8], mut pos: usize, out: &mut Name) -> Option<usize> {
+    let mut end = None;
+    //bound the number of pointer jumps so a malicious packet can't loop us forever
+    let mut jumps = 0;
+
+    loop {
+        let len = *pkt.get(pos)?;
+        if len == 0 {
+            return Some(end.unwrap_or(pos + 1));
+        } else if len & 0xc0 == 0xc0 {
+            jumps += 1;
+            if jumps > 16 {
+                return None;
+            }
+            end.get_or_insert(pos + 2);
+            pos = ((len as usize & 0x3f) << 8) | *pkt.get(pos + 1)? as usize;
+        } else {
+            let label = pkt.get(pos + 1..pos + 1 + len as usize)?;
+            if !out.is_empty() {
+                out.push('.').ok()?;
+            }
+            out.push_str(core::str::from_utf8(label).ok()?).ok()?;
+            pos += 1 + len as usize;
+        }
+    }
+}
+
+fn strip_suffix_ignore_case<'a>(s: &'a str, suffix: &str) -> Option<&'a str> {
+    let split = s.len().checked_sub(suffix.len())?;
+    (s.is_char_boundary(split) && s[split..].eq_ignore_ascii_case(suffix)).then(|| &s[..split])
+}
@@ -0,0 +1,302 @@
+use core::ops::Range;
+
+use embedded_io_async::{Read, Write};
+use heapless::Vec;
+
+use super::packet::{
+    decode, encode_ack, encode_connect, encode_disconnect, encode_pingreq, encode_publish, encode_subscribe,
+    encode_unsubscribe, frame, Connect, Error, Packet, Property, Publish, QualityOfService, PUBACK, PUBCOMP,
+    PUBREC, PUBREL,
+};
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   Minimal async MQTT v5 client:
+*
+*           Generic over any embedded-io-async transport (a TcpSocket on the device).
+*           It only deals with the wire - sending packets and handing back whatever
+*           arrives from `poll()`. Acks for *incoming* QoS1/QoS2 publishes are sent
+*           automatically, acks for *outgoing* ones are handed back so whoever owns
+*           the outgoing messages (the outbox) can track them across reconnects.
+*
+*           `poll()` keeps its partial-read state in the client, so it's safe to
+*           drop it mid-way (e.g. when it loses a select against a timer). The ack
+*           for an incoming publish is kept there too, and written before anything
+*           else is read or sent - so one cut short by a dropped `poll()` is finished
+*           rather than lost or left half-written on the wire.
+*
+*           A packet bigger than the rx buffer is reported as `PacketTooLarge` and
+*           its bytes are skipped on the following `poll()`, so the stream stays in
+*           sync and the connection doesn't have to be dropped over it.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+/// packet ids from here up are used by the client for (un)subscribes,
+/// ids below are left to the outbox so the two can never collide
+pub const CLIENT_PID_BASE: u16 = 0x8000;
+
+/// how many QoS1/QoS2 publishes we let the broker have in flight to us (sent as Receive Maximum)
+pub const RECEIVE_MAXIMUM: u16 = 8;
+
+/// room for encode_ack's reserved fixed header, the packet id and a reason code
+const ACK_BUF_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConnAck {
+    pub session_present: bool,
+    /// keep alive the broker wants us to use instead of ours
+    pub server_keep_alive: Option<u16>,
+    /// how many QoS1/QoS2 publishes the broker accepts in flight from us
+    pub receive_maximum: u16,
+    pub maximum_packet_size: Option<u32>,
+}
+
+pub struct MqttClient<'a, T> {
+    transport: T,
+    tx: &'a mut [u8],
+    rx: &'a mut [u8],
+    /// bytes currently held in rx
+    rx_len: usize,
+    /// length of the packet last handed out by poll(), dropped from rx on the next poll()
+    consumed: usize,
+    /// bytes of an oversize packet still to be read and thrown away
+    skip: usize,
+    next_pid: u16,
+    /// incoming QoS2 publishes we've sent PUBREC for but not yet seen PUBREL
+    inbound_qos2: Vec<u16, { RECEIVE_MAXIMUM as usize }>,
+    /// PUBACK/PUBREC/PUBCOMP owed to the broker and not yet all written
+    ack: Option<PendingAck>,
+}
+
+/// An ack for the broker, and how much of it is still to go
+struct PendingAck {
+    buf: [u8; ACK_BUF_LEN],
+    unsent: Range<usize>,
+}
+
+impl<'a, T: Read + Write> MqttClient<'a, T> {
+    pub fn new(transport: T, tx: &'a mut [u8], rx: &'a mut [u8]) -> Self {
+        Self {
+            transport,
+            tx,
+            rx,
+            rx_len: 0,
+            consumed: 0,
+            skip: 0,
+            next_pid: CLIENT_PID_BASE,
+            inbound_qos2: Vec::new(),
+            ack: None,
+        }
+    }
+
+    async fn send(&mut self, range: Range<usize>) -> Result<(), Error> {
+        //nothing can go out in the middle of an ack
+        self.flush_ack().await?;
+        self.transport.write_all(&self.tx[range]).await.map_err(|_| Error::Network)
+    }
+
+    /// Queue an ack, written by the next `flush_ack()`
+    fn owe_ack(&mut self, kind: u8, pid: u16) -> Result<(), Error> {
+        let mut buf = [0u8; ACK_BUF_LEN];
+        let unsent = encode_ack(&mut buf, kind, pid, 0)?;
+        self.ack = Some(PendingAck { buf, unsent });
+        Ok(())
+    }
+
+    /// Write whatever is left of the ack owed to the broker. Progress is kept after every
+    /// write, so if this is dropped part way the next call carries on from there.
+    async fn flush_ack(&mut self) -> Result<(), Error> {
+        while let Some(ack) = self.ack.as_mut() {
+            if ack.unsent.is_empty() {
+                self.ack = None;
+                break;
+            }
+            let written = self.transport.write(&ack.buf[ack.unsent.clone()]).await.map_err(|_| Error::Network)?;
+            if written == 0 {
+                return Err(Error::Closed);
+            }
+            ack.unsent.start += written;
+        }
+        Ok(())
+    }
+
+    fn alloc_pid(&mut self) -> u16 {
+        let pid = self.next_pid;
+        self.next_pid = self.next_pid.checked_add(1).unwrap_or(CLIENT_PID_BASE);
+        pid
+    }
+
+    /// Send CONNECT and wait for the broker's CONNACK
+    pub async fn connect(&mut self, connect: &Connect<'_>) -> Result<ConnAck, Error> {
+        let range = encode_connect(self.tx, connect)?;
+        self.send(range).await?;
+
+        match self.poll().await? {
+            Packet::ConnAck { session_present, reason, properties } => {
+                if reason >= 0x80 {
+                    return Err(Error::Refused(reason));
+                }
+                let mut connack = ConnAck {
+                    session_present,
+                    server_keep_alive: None,
+                    receive_maximum: u16::MAX,
+                    maximum_packet_size: None,
+                };
+                for prop in properties.iter() {
+                    match prop {
+                        Property::ServerKeepAlive(secs) => connack.server_keep_alive = Some(secs),
+                        Property::ReceiveMaximum(max) => connack.receive_maximum = max,
+                        Property::MaximumPacketSize(max) => connack.maximum_packet_size = Some(max),
+                        _ => {},
+                    }
+                }
+                Ok(connack)
+            },
+            _ => Err(Error::Protocol),
+        }
+    }
+
+    /// Write a PUBLISH. For QoS1/QoS2 `pid` must be a free id below `CLIENT_PID_BASE`,
+    /// the matching PUBACK/PUBREC comes back through `poll()`.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QualityOfService,
+        retain: bool,
+        dup: bool,
+        pid: u16,
+        properties: &[Property<'_>],
+    ) -> Result<(), Error> {
+        let range = encode_publish(self.tx, topic, payload, qos, retain, dup, pid, properties)?;
+        self.send(range).await
+    }
+
+    /// Second half of an outgoing QoS2 exchange, sent once the broker has PUBREC'd
+    pub async fn pubrel(&mut self, pid: u16) -> Result<(), Error> {
+        let range = encode_ack(self.tx, PUBREL, pid, 0)?;
+        self.send(range).await
+    }
+
+    /// Subscribe to `filters`, returning the packet id the SUBACK will carry
+    pub async fn subscribe(&mut self, filters: &[(&str, QualityOfService)]) -> Result<u16, Error> {
+        let pid = self.alloc_pid();
+        let range = encode_subscribe(self.tx, pid, filters, &[])?;
+        self.send(range).await?;
+        Ok(pid)
+    }
+
+    pub async fn unsubscribe(&mut self, filters: &[&str]) -> Result<u16, Error> {
+        let pid = self.alloc_pid();
+        let range = encode_unsubscribe(self.tx, pid, filters)?;
+        self.send(range).await?;
+        Ok(pid)
+    }
+
+    pub async fn ping(&mut self) -> Result<(), Error> {
+        let range = encode_pingreq(self.tx)?;
+        self.send(range).await
+    }
+
+    pub async fn disconnect(&mut self) -> Result<(), Error> {
+        let range = encode_disconnect(self.tx, 0)?;
+        self.send(range).await
+    }
+
+    fn discard_consumed(&mut self) {
+        if self.consumed > 0 {
+            self.rx.copy_within(self.consumed..self.rx_len, 0);
+            self.rx_len -= self.consumed;
+            self.consumed = 0;
+        }
+    }
+
+    /// Throw away the rest of an oversize packet
+    async fn drain(&mut self) -> Result<(), Error> {
+        while self.skip > 0 {
+            let len = self.skip.min(self.rx.len());
+            let read = self.transport.read(&mut self.rx[..len]).await.map_err(|_| Error::Network)?;
+            if read == 0 {
+                return Err(Error::Closed);
+            }
+            self.skip -= read;
+        }
+        Ok(())
+    }
+
+    /// Read until rx holds at least one whole packet, returning its length
+    async fn fill(&mut self) -> Result<usize, Error> {
+        loop {
+            if let Some((header_len, len)) = frame(&self.rx[..self.rx_len])? {
+                let total = header_len + len;
+                if total > self.rx.len() {
+                    //it can't all be in rx yet - forget what is and skip the rest as it arrives
+                    self.skip = total - self.rx_len;
+                    self.rx_len = 0;
+                    return Err(Error::PacketTooLarge(total));
+                }
+                if total <= self.rx_len {
+                    return Ok(total);
+                }
+            }
+
+            let read = self.transport.read(&mut self.rx[self.rx_len..]).await.map_err(|_| Error::Network)?;
+            if read == 0 {
+                return Err(Error::Closed);
+            }
+            self.rx_len += read;
+        }
+    }
+
+    /// Wait for the next packet from the broker.
+    ///
+    /// Incoming QoS1/QoS2 publishes are acked (and QoS2 duplicates swallowed), PUBREL is
+    /// answered here and never returned. The ack goes out at the start of the next `poll()`
+    /// (or before the next packet we send), after the publish has been dealt with.
+    /// `PacketTooLarge` isn't fatal, the packet is dropped and polling can carry on.
+    pub async fn poll(&mut self) -> Result<Packet<'_>, Error> {
+        loop {
+            self.flush_ack().await?;
+            self.discard_consumed();
+            self.drain().await?;
+            let total = self.fill().await?;
+            self.consumed = total;
+
+            //nothing is awaited from here on, so a packet can't be taken without its ack being owed
+            let deliver = match decode(&self.rx[..total])? {
+                Packet::Publish(Publish { qos: QualityOfService::QoS1, pid: Some(pid), .. }) => {
+                    self.owe_ack(PUBACK, pid)?;
+                    true
+                },
+                Packet::Publish(Publish { qos: QualityOfService::QoS2, pid: Some(pid), .. }) => {
+                    //already PUBREC'd - the broker is resending, so ack again but don't deliver twice
+                    let deliver = !self.inbound_qos2.contains(&pid);
+                    if deliver && self.inbound_qos2.is_full() {
+                        //broker ignored our receive maximum - forget the oldest rather than refuse
+                        self.inbound_qos2.remove(0);
+                    }
+                    if deliver {
+                        _ = self.inbound_qos2.push(pid);
+                    }
+                    self.owe_ack(PUBREC, pid)?;
+                    deliver
+                },
+                Packet::PubRel { pid, .. } => {
+                    self.inbound_qos2.retain(|p| *p != pid);
+                    self.owe_ack(PUBCOMP, pid)?;
+                    false
+                },
+                _ => true,
+            };
+            if deliver {
+                break;
+            }
+        }
+
+        decode(&self.rx[..self.consumed])
+    }
+}
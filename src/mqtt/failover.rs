@@ -0,0 +1,164 @@
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   Broker failover:
+*
+*           An ordered list of brokers, the first being the primary. When the
+*           current one can't be reached the next usable one is tried, wrapping
+*           round. Once we've been away from the primary for `prefer_primary_after`,
+*           it's tried again.
+*
+*           Brokers are given as URLs: mqtt://[user[:password]@]host[:port]
+*           or, for MQTT over WebSockets, ws://[user[:password]@]host[:port][/path]
+*           - an IPv6 host goes in brackets, as in mqtt://[2001:db8::7]:1883
+*           (mqtts:// and wss:// are understood, but TLS isn't supported yet so
+*           those are skipped)
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+pub const DEFAULT_PORT: u16 = 1883;
+pub const DEFAULT_TLS_PORT: u16 = 8883;
+pub const DEFAULT_WS_PORT: u16 = 80;
+pub const DEFAULT_WSS_PORT: u16 = 443;
+/// where brokers usually serve MQTT over WebSockets
+pub const DEFAULT_WS_PATH: &str = "/mqtt";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Broker<'a> {
+    /// hostname or IP literal (IPv6 without its URL brackets) - empty if the broker is only ever found by mDNS
+    pub host: &'a str,
+    pub port: u16,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a str>,
+    pub tls: bool,
+    /// HTTP path to upgrade to a WebSocket on, None for plain MQTT
+    pub path: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// not mqtt://, mqtts://, ws:// or wss://
+    Scheme,
+    Port,
+    /// no host, or a path/query after it (other than a ws:// path)
+    Host,
+}
+
+impl<'a> Broker<'a> {
+    /// Parse `mqtt://[user[:password]@]host[:port]` or `ws://[user[:password]@]host[:port][/path]`
+    pub fn parse(url: &'a str) -> Result<Self, ParseError> {
+        let url = url.trim();
+        let (scheme, rest) = url.split_once("://").ok_or(ParseError::Scheme)?;
+        let (tls, websocket, default_port) = match scheme {
+            "mqtt" => (false, false, DEFAULT_PORT),
+            "mqtts" => (true, false, DEFAULT_TLS_PORT),
+            "ws" => (false, true, DEFAULT_WS_PORT),
+            "wss" => (true, true, DEFAULT_WSS_PORT),
+            _ => return Err(ParseError::Scheme),
+        };
+
+        let (rest, path) = match rest.find('/') {
+            Some(slash) if websocket => (&rest[..slash], Some(&rest[slash..])),
+            _ if websocket => (rest, Some(DEFAULT_WS_PATH)),
+            _ => (rest, None),
+        };
+
+        //the password may contain '@', the host can't
+        let (userinfo, hostport) = match rest.rsplit_once('@') {
+            Some((userinfo, hostport)) => (Some(userinfo), hostport),
+            None => (None, rest),
+        };
+        let (username, password) = match userinfo.map(|userinfo| userinfo.split_once(':')) {
+            None => (None, None),
+            Some(None) => (userinfo, None),
+            Some(Some((username, password))) => (Some(username), Some(password)),
+        };
+
+        //an IPv6 literal has ':'s of its own
+        let (host, port) = match hostport.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, port) = bracketed.split_once(']').ok_or(ParseError::Host)?;
+                (host, port.strip_prefix(':').unwrap_or(port))
+            },
+            None => hostport.split_once(':').unwrap_or((hostport, "")),
+        };
+        let port = match port {
+            "" if !hostport.ends_with(':') => default_port,
+            port => port.parse::<u16>().map_err(|_| ParseError::Port)?,
+        };
+        if host.is_empty() || host.contains(['/', '?', '#']) {
+            return Err(ParseError::Host);
+        }
+
+        Ok(Self { host, port, username, password, tls, path })
+    }
+}
+
+pub struct Failover<'a, const N: usize> {
+    brokers: Vec<Broker<'a>, N>,
+    current: usize,
+    /// when we last moved off the primary
+    left_primary: Option<Instant>,
+    prefer_primary_after: Duration,
+}
+
+impl<'a, const N: usize> Failover<'a, N> {
+    /// `brokers` in order of preference - TLS ones are kept (so the numbering matches the config) but never used
+    pub fn new(brokers: Vec<Broker<'a>, N>, prefer_primary_after: Duration) -> Self {
+        let mut failover = Self { brokers, current: 0, left_primary: None, prefer_primary_after };
+        if failover.brokers.first().is_some_and(|broker| broker.tls) {
+            failover.advance(Instant::now());
+        }
+        failover
+    }
+
+    /// The broker to connect to, None if none of them are usable
+    pub fn current(&self) -> Option<&Broker<'a>> {
+        self.brokers.get(self.current).filter(|broker| !broker.tls)
+    }
+
+    /// Position of the current broker in the list (0 = primary)
+    pub fn position(&self) -> usize {
+        self.current
+    }
+
+    /// The current broker couldn't be reached - move on to the next usable one
+    pub fn failed(&mut self, now: Instant) {
+        self.advance(now);
+    }
+
+    fn advance(&mut self, now: Instant) {
+        for _ in 0..self.brokers.len() {
+            self.current = (self.current + 1) % self.brokers.len();
+            if !self.brokers[self.current].tls {
+                break;
+            }
+        }
+        self.left_primary = match self.current {
+            0 => None,
+            _ => self.left_primary.or(Some(now)),
+        };
+    }
+
+    /// When to give the primary another go, if we're on a standby
+    pub fn primary_due_at(&self) -> Option<Instant> {
+        self.left_primary
+            .filter(|_| !self.brokers[0].tls)
+            .map(|left| left + self.prefer_primary_after)
+    }
+
+    pub fn primary_due(&self, now: Instant) -> bool {
+        self.primary_due_at().is_some_and(|due| now >= due)
+    }
+
+    /// Go back to the primary (call when `primary_due`)
+    pub fn back_to_primary(&mut self) {
+        self.current = 0;
+        self.left_primary = None;
+    }
+}
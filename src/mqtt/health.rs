@@ -0,0 +1,121 @@
+use embassy_time::{Duration, Instant};
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   Connection health:
+*
+*           A PINGREQ goes out every ping interval whatever else is going on,
+*           and its PINGRESP is timed. A broker that stops answering - most often
+*           a half-open TCP connection, where our writes still "succeed" into the
+*           socket buffer but nothing comes back - shows up as missed PINGRESPs:
+*
+*            1) no PINGRESP within `pong_timeout` counts as a miss, and another
+*               ping goes straight out
+*            2) `max_missed` misses in a row and the connection is given up on
+*
+*           Round trip times (last, smoothed average and worst) are kept for
+*           telemetry.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+/// When to give up on a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HealthPolicy {
+    /// longest a PINGRESP may take before the ping counts as missed
+    pub pong_timeout: Duration,
+    /// missed PINGRESPs in a row before the connection is declared dead
+    pub max_missed: u8,
+}
+
+/// What to do now the health timer has fired
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Due {
+    /// send a PINGREQ (and call `pinged`)
+    Ping,
+    /// too many missed PINGRESPs - the connection is dead
+    Dead,
+}
+
+/// Round trip figures for one connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HealthFigures {
+    /// the latest PINGRESP's round trip
+    pub rtt: Option<Duration>,
+    /// smoothed round trip (each new one counts for an eighth, like TCP's SRTT)
+    pub rtt_avg: Option<Duration>,
+    pub rtt_max: Option<Duration>,
+    pub pings: u32,
+    /// PINGRESPs that didn't turn up in time
+    pub missed: u32,
+    /// missed since the last one that did
+    pub missed_in_row: u8,
+}
+
+pub struct Health {
+    policy: HealthPolicy,
+    ping_interval: Duration,
+    last_ping: Instant,
+    /// when the PINGREQ we're waiting on went out
+    awaiting: Option<Instant>,
+    figures: HealthFigures,
+}
+
+impl Health {
+    /// Start monitoring a connection that came up at `now`
+    pub fn new(policy: HealthPolicy, ping_interval: Duration, now: Instant) -> Self {
+        Self { policy, ping_interval, last_ping: now, awaiting: None, figures: HealthFigures::default() }
+    }
+
+    /// When something next needs doing: a PINGRESP's deadline if one is owed, otherwise the next ping
+    pub fn due_at(&self) -> Instant {
+        match self.awaiting {
+            Some(sent) => sent + self.policy.pong_timeout,
+            None => self.last_ping + self.ping_interval,
+        }
+    }
+
+    /// The timer for `due_at` fired
+    pub fn due(&mut self, now: Instant) -> Due {
+        if self.awaiting.is_some_and(|sent| now >= sent + self.policy.pong_timeout) {
+            self.awaiting = None;
+            self.figures.missed = self.figures.missed.wrapping_add(1);
+            self.figures.missed_in_row = self.figures.missed_in_row.saturating_add(1);
+            if self.figures.missed_in_row >= self.policy.max_missed {
+                return Due::Dead;
+            }
+        }
+        Due::Ping
+    }
+
+    /// A PINGREQ was written at `now`
+    pub fn pinged(&mut self, now: Instant) {
+        self.last_ping = now;
+        //(a straggler for a ping already counted as missed will be timed against this one - near enough)
+        self.awaiting = Some(now);
+        self.figures.pings = self.figures.pings.wrapping_add(1);
+    }
+
+    /// A PINGRESP arrived at `now`
+    pub fn pong(&mut self, now: Instant) {
+        let Some(sent) = self.awaiting.take() else {
+            //one we'd already given up on
+            return;
+        };
+        let rtt = now.saturating_duration_since(sent);
+        let figures = &mut self.figures;
+        figures.rtt = Some(rtt);
+        figures.rtt_avg = Some(match figures.rtt_avg {
+            Some(avg) => Duration::from_ticks((avg.as_ticks() * 7 + rtt.as_ticks()) / 8),
+            None => rtt,
+        });
+        figures.rtt_max = Some(figures.rtt_max.map_or(rtt, |max| max.max(rtt)));
+        figures.missed_in_row = 0;
+    }
+
+    pub fn figures(&self) -> HealthFigures {
+        self.figures
+    }
+}
@@ -0,0 +1,232 @@
+use embassy_time::{Duration, Instant};
+use heapless::{Deque, String, Vec};
+
+use super::{
+    packet::Properties,
+    router::{filter_is_valid, topic_matches, Message, RouterError},
+};
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   Incoming rate limits:
+*
+*           Each limit is a token bucket on a topic filter: `burst` messages
+*           straight away, then one more every `every`. What happens to a
+*           message that arrives with the bucket empty depends on the policy:
+*
+*            Drop       thrown away
+*            Coalesce   held back, replacing anything already held for the same limit -
+*                       only the latest gets through once there's a token
+*            Queue      held back in order (HELD_LEN across all limits, then dropped)
+*
+*           The session hands held messages on as tokens come back.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+/// messages held back across all limits
+pub const HELD_LEN: usize = 4;
+/// held messages have to fit these, anything bigger is dropped instead
+pub const HELD_TOPIC_LEN: usize = 64;
+pub const HELD_PAYLOAD_LEN: usize = 512;
+pub const HELD_PROPERTIES_LEN: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    Drop,
+    Coalesce,
+    Queue,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub filter: &'static str,
+    pub burst: u8,
+    /// how often a token comes back
+    pub every: Duration,
+    pub policy: Policy,
+}
+
+/// What to do with an incoming message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admit {
+    /// dispatch it now
+    Pass,
+    /// kept to be released later
+    Held,
+    /// kept to be released later, and an older held message was thrown away for it
+    Replaced,
+    /// thrown away
+    Dropped,
+}
+
+struct Bucket {
+    limit: Limit,
+    tokens: u8,
+    /// when tokens were last topped up
+    filled: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        if self.tokens >= self.limit.burst {
+            self.filled = now;
+            return;
+        }
+        let every = self.limit.every.as_ticks().max(1);
+        let earned = (now.saturating_duration_since(self.filled).as_ticks() / every).min(self.limit.burst as u64) as u8;
+        if earned > 0 {
+            self.tokens = (self.tokens + earned).min(self.limit.burst);
+            self.filled += Duration::from_ticks(earned as u64 * every);
+        }
+    }
+
+    /// When the next token comes back (now, if there's one already)
+    fn next_token(&self) -> Instant {
+        if self.tokens > 0 { self.filled } else { self.filled + self.limit.every }
+    }
+}
+
+/// A held back message, copied out of the rx buffer
+pub struct Held {
+    limit: usize,
+    topic: String<HELD_TOPIC_LEN>,
+    payload: Vec<u8, HELD_PAYLOAD_LEN>,
+    properties: Vec<u8, HELD_PROPERTIES_LEN>,
+    retain: bool,
+}
+
+impl Held {
+    fn new(limit: usize, msg: &Message<'_>) -> Option<Self> {
+        Some(Self {
+            limit,
+            topic: String::try_from(msg.topic).ok()?,
+            payload: Vec::from_slice(msg.payload).ok()?,
+            properties: Vec::from_slice(msg.properties.raw()).ok()?,
+            retain: msg.retain,
+        })
+    }
+
+    pub fn message(&self) -> Message<'_> {
+        Message {
+            topic: &self.topic,
+            payload: &self.payload,
+            retain: self.retain,
+            properties: Properties::from_raw(&self.properties),
+        }
+    }
+}
+
+pub struct Limiter<const N: usize> {
+    buckets: Vec<Bucket, N>,
+    held: Deque<Held, HELD_LEN>,
+}
+
+impl<const N: usize> Default for Limiter<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Limiter<N> {
+    pub const fn new() -> Self {
+        Self { buckets: Vec::new(), held: Deque::new() }
+    }
+
+    /// Limit messages matching `limit.filter` - the first limit added that matches a topic is the one used
+    pub fn add(&mut self, limit: Limit) -> Result<(), RouterError> {
+        if !filter_is_valid(limit.filter) {
+            return Err(RouterError::InvalidFilter);
+        }
+        let bucket = Bucket { limit, tokens: limit.burst, filled: Instant::now() };
+        self.buckets.push(bucket).map_err(|_| RouterError::Full)
+    }
+
+    /// Take a token for `msg` if its limit has one, otherwise drop or hold it according to the policy
+    pub fn admit(&mut self, msg: &Message<'_>, now: Instant) -> Admit {
+        let Some(index) = self.buckets.iter().position(|bucket| topic_matches(bucket.limit.filter, msg.topic)) else {
+            return Admit::Pass;
+        };
+        let bucket = &mut self.buckets[index];
+        bucket.refill(now);
+
+        //anything already held for this limit goes first
+        let waiting = self.held.iter().any(|held| held.limit == index);
+        if !waiting && bucket.tokens > 0 {
+            bucket.tokens -= 1;
+            return Admit::Pass;
+        }
+
+        let policy = bucket.limit.policy;
+        match policy {
+            Policy::Drop => Admit::Dropped,
+            Policy::Coalesce => {
+                let Some(held) = Held::new(index, msg) else { return Admit::Dropped };
+                match self.held.iter_mut().find(|held| held.limit == index) {
+                    Some(older) => {
+                        *older = held;
+                        Admit::Replaced
+                    },
+                    None => self.hold(held),
+                }
+            },
+            Policy::Queue => match Held::new(index, msg) {
+                Some(held) => self.hold(held),
+                None => Admit::Dropped,
+            },
+        }
+    }
+
+    fn hold(&mut self, held: Held) -> Admit {
+        match self.held.push_back(held) {
+            Ok(()) => Admit::Held,
+            Err(_) => Admit::Dropped,
+        }
+    }
+
+    /// When the next held message can be released, if any are held
+    pub fn next_release(&self) -> Option<Instant> {
+        self.held.iter().map(|held| self.buckets[held.limit].next_token()).min()
+    }
+
+    /// The oldest held message whose limit has a token now (taking the token)
+    pub fn release(&mut self, now: Instant) -> Option<Held> {
+        for bucket in self.buckets.iter_mut() {
+            bucket.refill(now);
+        }
+        //only the first held message of each limit is eligible, so each limit's order is kept
+        let mut seen: Vec<usize, HELD_LEN> = Vec::new();
+        let mut ready = None;
+        for (pos, held) in self.held.iter().enumerate() {
+            if seen.contains(&held.limit) {
+                continue;
+            }
+            if self.buckets[held.limit].tokens > 0 {
+                ready = Some(pos);
+                break;
+            }
+            _ = seen.push(held.limit);
+        }
+
+        let pos = ready?;
+        //take it out from the middle, keeping the others in order
+        let mut kept = Deque::new();
+        let mut released = None;
+        let mut index = 0;
+        while let Some(held) = self.held.pop_front() {
+            if index == pos {
+                released = Some(held);
+            } else {
+                _ = kept.push_back(held);
+            }
+            index += 1;
+        }
+        self.held = kept;
+
+        let held = released?;
+        self.buckets[held.limit].tokens -= 1;
+        Some(held)
+    }
+}
@@ -0,0 +1,305 @@
+use heapless::{Deque, String, Vec};
+
+use super::{client::CLIENT_PID_BASE, packet::{Property, QualityOfService}};
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   Outbound publish queue:
+*
+*           Everything the device publishes (button events, telemetry, acks...) goes in
+*           here rather than straight to the broker, so nothing is lost while Wi-Fi or
+*           the broker are away. The queue lives in a static and outlives any one
+*           connection:
+*
+*           1) publishes are queued in order - when full the oldest one not yet written is
+*              dropped (and counted), never one the broker may still hold state for
+*           2) mqtt_task sends whatever is unsent, oldest first
+*           3) QoS0 is done once written, QoS1 once PUBACK'd, QoS2 once PUBCOMP'd
+*           4) on reconnect `rewind()` marks everything un-acked for resending
+*              (PUBLISH again with DUP set, or just the PUBREL if PUBREC already arrived)
+*           5) no more QoS1/QoS2 publishes are in flight at once than the broker's
+*              Receive Maximum (from CONNACK) - the rest wait, in order, for an ack
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+pub const OUT_TOPIC_LEN: usize = 64;
+//big enough for a full e-ink message echoed back as its state topic
+pub const OUT_PAYLOAD_LEN: usize = 384;
+pub const OUT_CORRELATION_LEN: usize = 32;
+/// user properties one publish can carry (names and values are static strings)
+pub const OUT_USER_PROPERTIES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// PUBLISH needs (re)sending
+    Publish { dup: bool },
+    /// PUBLISH written, waiting on PUBACK (QoS1) or PUBREC (QoS2)
+    AwaitAck,
+    /// QoS2 PUBREC arrived, PUBREL needs (re)sending
+    Release,
+    /// PUBREL written, waiting on PUBCOMP
+    AwaitComp,
+}
+
+#[derive(Debug, Clone)]
+pub struct Outgoing {
+    pub topic: String<OUT_TOPIC_LEN>,
+    pub payload: Vec<u8, OUT_PAYLOAD_LEN>,
+    pub qos: QualityOfService,
+    pub retain: bool,
+    /// echoed back to a request/response caller (empty for everything else)
+    pub correlation_data: Vec<u8, OUT_CORRELATION_LEN>,
+    /// MQTT v5 content type of the payload (empty to leave it out)
+    pub content_type: &'static str,
+    /// seconds the broker should keep it for - None for as long as it likes
+    pub message_expiry: Option<u32>,
+    pub user_properties: Vec<(&'static str, &'static str), OUT_USER_PROPERTIES>,
+    pub pid: u16,
+    state: State,
+}
+
+impl Outgoing {
+    pub fn new(topic: &str, payload: &[u8], qos: QualityOfService, retain: bool) -> Result<Self, OutboxError> {
+        Ok(Self {
+            topic: String::try_from(topic).map_err(|_| OutboxError::TopicTooLong)?,
+            payload: Vec::from_slice(payload).map_err(|_| OutboxError::PayloadTooLong)?,
+            qos,
+            retain,
+            correlation_data: Vec::new(),
+            content_type: "",
+            message_expiry: None,
+            user_properties: Vec::new(),
+            pid: 0,
+            state: State::Publish { dup: false },
+        })
+    }
+
+    pub fn with_correlation_data(mut self, data: &[u8]) -> Result<Self, OutboxError> {
+        self.correlation_data = Vec::from_slice(data).map_err(|_| OutboxError::CorrelationTooLong)?;
+        Ok(self)
+    }
+
+    pub fn with_content_type(mut self, content_type: &'static str) -> Self {
+        self.content_type = content_type;
+        self
+    }
+
+    pub fn with_message_expiry(mut self, secs: u32) -> Self {
+        self.message_expiry = Some(secs);
+        self
+    }
+
+    pub fn with_user_property(mut self, name: &'static str, value: &'static str) -> Result<Self, OutboxError> {
+        self.user_properties.push((name, value)).map_err(|_| OutboxError::TooManyProperties)?;
+        Ok(self)
+    }
+
+    /// Everything above as MQTT v5 properties, ready for the PUBLISH
+    pub fn properties(&self) -> Vec<Property<'_>, { OUT_USER_PROPERTIES + 3 }> {
+        let mut props = Vec::new();
+        //can't fail, there's a slot for each
+        if !self.correlation_data.is_empty() {
+            _ = props.push(Property::CorrelationData(&self.correlation_data));
+        }
+        if !self.content_type.is_empty() {
+            _ = props.push(Property::ContentType(self.content_type));
+        }
+        if let Some(secs) = self.message_expiry {
+            _ = props.push(Property::MessageExpiry(secs));
+        }
+        for (name, value) in self.user_properties.iter() {
+            _ = props.push(Property::UserProperty(name, value));
+        }
+        props
+    }
+}
+
+/// What the session should write next
+//copied out of the outbox on purpose, so the lock isn't held while it's written
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum NextWrite {
+    Publish { msg: Outgoing, dup: bool },
+    Release { pid: u16 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutboxError {
+    TopicTooLong,
+    PayloadTooLong,
+    CorrelationTooLong,
+    TooManyProperties,
+}
+
+pub struct Outbox<const N: usize> {
+    queue: Deque<Outgoing, N>,
+    next_pid: u16,
+    dropped: u32,
+    /// QoS1/QoS2 publishes the broker takes in flight at once
+    receive_maximum: u16,
+}
+
+impl<const N: usize> Default for Outbox<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Outbox<N> {
+    pub const fn new() -> Self {
+        Self {
+            queue: Deque::new(),
+            next_pid: 1,
+            dropped: 0,
+            receive_maximum: u16::MAX,
+        }
+    }
+
+    /// number of messages thrown away because the queue was full
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// how many more can be pushed before messages start being dropped
+    pub fn room(&self) -> usize {
+        self.queue.capacity() - self.queue.len()
+    }
+
+    fn alloc_pid(&mut self) -> u16 {
+        loop {
+            let pid = self.next_pid;
+            self.next_pid = if pid + 1 >= CLIENT_PID_BASE { 1 } else { pid + 1 };
+            if !self.queue.iter().any(|m| m.pid == pid) {
+                return pid;
+            }
+        }
+    }
+
+    /// Queue a publish. If the queue is full the oldest message never written is dropped to make
+    /// room - anything the broker has seen keeps its place (and its packet id) until it's acked,
+    /// so when that's all the queue holds the new message is the one dropped.
+    pub fn push(&mut self, mut msg: Outgoing) {
+        if self.queue.is_full() {
+            self.dropped = self.dropped.wrapping_add(1);
+            match self.queue.iter().position(|m| m.state == State::Publish { dup: false }) {
+                Some(idx) => self.remove(idx),
+                None => return,
+            }
+        }
+
+        //qos0 never puts its id on the wire, but having one still makes every entry easy to find
+        msg.pid = self.alloc_pid();
+        msg.state = State::Publish { dup: false };
+        _ = self.queue.push_back(msg);
+    }
+
+    /// The broker's Receive Maximum, from its CONNACK (65535 if it didn't send one)
+    pub fn set_receive_maximum(&mut self, max: u16) {
+        //0 isn't allowed - don't let it wedge the queue
+        self.receive_maximum = max.max(1);
+    }
+
+    /// QoS1/QoS2 publishes written whose exchange hasn't finished (PUBACK, or PUBCOMP for QoS2)
+    pub fn in_flight(&self) -> usize {
+        self.queue.iter().filter(|m| matches!(m.state, State::AwaitAck | State::Release | State::AwaitComp)).count()
+    }
+
+    /// The oldest message needing something written, copied out so the lock can be released while sending.
+    /// Once the broker's Receive Maximum is in flight, QoS1/QoS2 publishes (and anything queued after
+    /// them, to keep the order) wait for an ack - a PUBREL can always go, it frees a slot.
+    pub fn next(&self) -> Option<NextWrite> {
+        let full = self.in_flight() >= self.receive_maximum as usize;
+        let mut held = false;
+        self.queue.iter().find_map(|m| match m.state {
+            State::Publish { .. } if full && m.qos != QualityOfService::QoS0 => {
+                held = true;
+                None
+            },
+            State::Publish { dup } if !held => Some(NextWrite::Publish { msg: m.clone(), dup }),
+            State::Release => Some(NextWrite::Release { pid: m.pid }),
+            State::Publish { .. } | State::AwaitAck | State::AwaitComp => None,
+        })
+    }
+
+    /// Called once `next()`'s packet was written. QoS0 messages are done at this point.
+    pub fn sent(&mut self, send: &NextWrite) {
+        match send {
+            NextWrite::Publish { msg, .. } if msg.qos == QualityOfService::QoS0 => self.puback(msg.pid),
+            NextWrite::Publish { msg, .. } => self.set_state(msg.pid, State::AwaitAck),
+            NextWrite::Release { pid } => self.set_state(*pid, State::AwaitComp),
+        }
+    }
+
+    /// PUBACK for a QoS1 message
+    pub fn puback(&mut self, pid: u16) {
+        self.discard(pid);
+    }
+
+    /// Give up on a message altogether (e.g. it can never fit the tx buffer)
+    pub fn discard(&mut self, pid: u16) {
+        if let Some(idx) = self.position(pid) {
+            self.remove(idx);
+        }
+    }
+
+    /// PUBREC for a QoS2 message - a failure reason code ends the exchange there
+    pub fn pubrec(&mut self, pid: u16, reason: u8) {
+        if reason >= 0x80 {
+            self.puback(pid);
+        } else {
+            self.set_state(pid, State::Release);
+        }
+    }
+
+    /// PUBCOMP for a QoS2 message
+    pub fn pubcomp(&mut self, pid: u16) {
+        self.puback(pid);
+    }
+
+    /// After a reconnect: everything written but not fully acked needs writing again
+    pub fn rewind(&mut self) {
+        for m in self.queue.iter_mut() {
+            m.state = match m.state {
+                State::AwaitAck => State::Publish { dup: true },
+                State::AwaitComp => State::Release,
+                state => state,
+            };
+        }
+    }
+
+    fn position(&self, pid: u16) -> Option<usize> {
+        self.queue.iter().position(|m| m.pid == pid)
+    }
+
+    fn set_state(&mut self, pid: u16, state: State) {
+        if let Some(m) = self.queue.iter_mut().find(|m| m.pid == pid) {
+            m.state = state;
+        }
+    }
+
+    fn remove(&mut self, idx: usize) {
+        //Deque has no remove(idx), so rotate the entry to the front, pop it and rotate back
+        for _ in 0..idx {
+            if let Some(m) = self.queue.pop_front() {
+                _ = self.queue.push_back(m);
+            }
+        }
+        self.queue.pop_front();
+        for _ in 0..self.queue.len() - idx {
+            if let Some(m) = self.queue.pop_front() {
+                _ = self.queue.push_back(m);
+            }
+        }
+    }
+}
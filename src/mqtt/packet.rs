@@ -0,0 +1,687 @@
+use core::str::from_utf8;
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   MQTT v5 packet encoding/decoding:
+*
+*           Only the packets (and properties) a client needs - CONNECT/SUBSCRIBE/PUBLISH etc.
+*           going out, CONNACK/SUBACK/PUBLISH etc. coming in. Everything works on borrowed
+*           slices so nothing here allocates, and nothing here knows about sockets or embassy
+*           (so it can be exercised on the host).
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+pub const CONNECT: u8 = 1;
+pub const CONNACK: u8 = 2;
+pub const PUBLISH: u8 = 3;
+pub const PUBACK: u8 = 4;
+pub const PUBREC: u8 = 5;
+pub const PUBREL: u8 = 6;
+pub const PUBCOMP: u8 = 7;
+pub const SUBSCRIBE: u8 = 8;
+pub const SUBACK: u8 = 9;
+pub const UNSUBSCRIBE: u8 = 10;
+pub const UNSUBACK: u8 = 11;
+pub const PINGREQ: u8 = 12;
+pub const PINGRESP: u8 = 13;
+pub const DISCONNECT: u8 = 14;
+pub const AUTH: u8 = 15;
+
+/// room reserved at the front of the tx buffer for the fixed header (type byte + up to 4 length bytes)
+const FIXED_HEADER_MAX: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum QualityOfService {
+    QoS0 = 0,
+    QoS1 = 1,
+    QoS2 = 2,
+}
+
+impl QualityOfService {
+    pub fn from_bits(bits: u8) -> Result<Self, Error> {
+        match bits {
+            0 => Ok(Self::QoS0),
+            1 => Ok(Self::QoS1),
+            2 => Ok(Self::QoS2),
+            _ => Err(Error::Malformed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// the transport failed to read/write
+    Network,
+    /// the broker closed the connection
+    Closed,
+    /// the packet being written doesn't fit the tx buffer
+    BufferTooSmall,
+    /// the incoming packet doesn't fit the rx buffer
+    PacketTooLarge(usize),
+    /// the incoming bytes aren't valid MQTT v5
+    Malformed,
+    /// the broker refused CONNECT with this reason code
+    Refused(u8),
+    /// the broker sent something that makes no sense at this point (e.g. a second CONNACK)
+    Protocol,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Network => write!(f, "network error"),
+            Self::Closed => write!(f, "connection closed by broker"),
+            Self::BufferTooSmall => write!(f, "packet too large for tx buffer"),
+            Self::PacketTooLarge(len) => write!(f, "incoming packet of {} bytes too large for rx buffer", len),
+            Self::Malformed => write!(f, "malformed packet"),
+            Self::Refused(code) => write!(f, "connection refused, reason code 0x{:02x}", code),
+            Self::Protocol => write!(f, "protocol error"),
+        }
+    }
+}
+
+/*
+* ----------------------------------------------------------------------
+*
+*                           Properties
+*
+* ----------------------------------------------------------------------
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Property<'a> {
+    PayloadFormat(u8),
+    MessageExpiry(u32),
+    ContentType(&'a str),
+    ResponseTopic(&'a str),
+    CorrelationData(&'a [u8]),
+    SubscriptionId(u32),
+    SessionExpiry(u32),
+    AssignedClientId(&'a str),
+    ServerKeepAlive(u16),
+    AuthMethod(&'a str),
+    AuthData(&'a [u8]),
+    RequestProblemInfo(u8),
+    WillDelay(u32),
+    RequestResponseInfo(u8),
+    ResponseInfo(&'a str),
+    ServerReference(&'a str),
+    ReasonString(&'a str),
+    ReceiveMaximum(u16),
+    TopicAliasMaximum(u16),
+    TopicAlias(u16),
+    MaximumQoS(u8),
+    RetainAvailable(u8),
+    UserProperty(&'a str, &'a str),
+    MaximumPacketSize(u32),
+    WildcardSubAvailable(u8),
+    SubIdAvailable(u8),
+    SharedSubAvailable(u8),
+}
+
+impl<'a> Property<'a> {
+    fn id(&self) -> u8 {
+        match self {
+            Self::PayloadFormat(_) => 0x01,
+            Self::MessageExpiry(_) => 0x02,
+            Self::ContentType(_) => 0x03,
+            Self::ResponseTopic(_) => 0x08,
+            Self::CorrelationData(_) => 0x09,
+            Self::SubscriptionId(_) => 0x0b,
+            Self::SessionExpiry(_) => 0x11,
+            Self::AssignedClientId(_) => 0x12,
+            Self::ServerKeepAlive(_) => 0x13,
+            Self::AuthMethod(_) => 0x15,
+            Self::AuthData(_) => 0x16,
+            Self::RequestProblemInfo(_) => 0x17,
+            Self::WillDelay(_) => 0x18,
+            Self::RequestResponseInfo(_) => 0x19,
+            Self::ResponseInfo(_) => 0x1a,
+            Self::ServerReference(_) => 0x1c,
+            Self::ReasonString(_) => 0x1f,
+            Self::ReceiveMaximum(_) => 0x21,
+            Self::TopicAliasMaximum(_) => 0x22,
+            Self::TopicAlias(_) => 0x23,
+            Self::MaximumQoS(_) => 0x24,
+            Self::RetainAvailable(_) => 0x25,
+            Self::UserProperty(_, _) => 0x26,
+            Self::MaximumPacketSize(_) => 0x27,
+            Self::WildcardSubAvailable(_) => 0x28,
+            Self::SubIdAvailable(_) => 0x29,
+            Self::SharedSubAvailable(_) => 0x2a,
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        1 + match self {
+            Self::PayloadFormat(_) | Self::RequestProblemInfo(_) | Self::RequestResponseInfo(_)
+            | Self::MaximumQoS(_) | Self::RetainAvailable(_) | Self::WildcardSubAvailable(_)
+            | Self::SubIdAvailable(_) | Self::SharedSubAvailable(_) => 1,
+            Self::ServerKeepAlive(_) | Self::ReceiveMaximum(_) | Self::TopicAliasMaximum(_)
+            | Self::TopicAlias(_) => 2,
+            Self::MessageExpiry(_) | Self::SessionExpiry(_) | Self::WillDelay(_)
+            | Self::MaximumPacketSize(_) => 4,
+            Self::SubscriptionId(v) => varint_len(*v as usize),
+            Self::ContentType(s) | Self::ResponseTopic(s) | Self::AssignedClientId(s)
+            | Self::AuthMethod(s) | Self::ResponseInfo(s) | Self::ServerReference(s)
+            | Self::ReasonString(s) => 2 + s.len(),
+            Self::CorrelationData(b) | Self::AuthData(b) => 2 + b.len(),
+            Self::UserProperty(k, v) => 4 + k.len() + v.len(),
+        }
+    }
+
+    fn write(&self, w: &mut Writer) -> Result<(), Error> {
+        w.u8(self.id())?;
+        match *self {
+            Self::PayloadFormat(v) | Self::RequestProblemInfo(v) | Self::RequestResponseInfo(v)
+            | Self::MaximumQoS(v) | Self::RetainAvailable(v) | Self::WildcardSubAvailable(v)
+            | Self::SubIdAvailable(v) | Self::SharedSubAvailable(v) => w.u8(v),
+            Self::ServerKeepAlive(v) | Self::ReceiveMaximum(v) | Self::TopicAliasMaximum(v)
+            | Self::TopicAlias(v) => w.u16(v),
+            Self::MessageExpiry(v) | Self::SessionExpiry(v) | Self::WillDelay(v)
+            | Self::MaximumPacketSize(v) => w.u32(v),
+            Self::SubscriptionId(v) => w.varint(v as usize),
+            Self::ContentType(s) | Self::ResponseTopic(s) | Self::AssignedClientId(s)
+            | Self::AuthMethod(s) | Self::ResponseInfo(s) | Self::ServerReference(s)
+            | Self::ReasonString(s) => w.str(s),
+            Self::CorrelationData(b) | Self::AuthData(b) => w.bin(b),
+            Self::UserProperty(k, v) => {
+                w.str(k)?;
+                w.str(v)
+            },
+        }
+    }
+
+    fn read(r: &mut Reader<'a>) -> Result<Self, Error> {
+        Ok(match r.u8()? {
+            0x01 => Self::PayloadFormat(r.u8()?),
+            0x02 => Self::MessageExpiry(r.u32()?),
+            0x03 => Self::ContentType(r.str()?),
+            0x08 => Self::ResponseTopic(r.str()?),
+            0x09 => Self::CorrelationData(r.bin()?),
+            0x0b => Self::SubscriptionId(r.varint()? as u32),
+            0x11 => Self::SessionExpiry(r.u32()?),
+            0x12 => Self::AssignedClientId(r.str()?),
+            0x13 => Self::ServerKeepAlive(r.u16()?),
+            0x15 => Self::AuthMethod(r.str()?),
+            0x16 => Self::AuthData(r.bin()?),
+            0x17 => Self::RequestProblemInfo(r.u8()?),
+            0x18 => Self::WillDelay(r.u32()?),
+            0x19 => Self::RequestResponseInfo(r.u8()?),
+            0x1a => Self::ResponseInfo(r.str()?),
+            0x1c => Self::ServerReference(r.str()?),
+            0x1f => Self::ReasonString(r.str()?),
+            0x21 => Self::ReceiveMaximum(r.u16()?),
+            0x22 => Self::TopicAliasMaximum(r.u16()?),
+            0x23 => Self::TopicAlias(r.u16()?),
+            0x24 => Self::MaximumQoS(r.u8()?),
+            0x25 => Self::RetainAvailable(r.u8()?),
+            0x26 => Self::UserProperty(r.str()?, r.str()?),
+            0x27 => Self::MaximumPacketSize(r.u32()?),
+            0x28 => Self::WildcardSubAvailable(r.u8()?),
+            0x29 => Self::SubIdAvailable(r.u8()?),
+            0x2a => Self::SharedSubAvailable(r.u8()?),
+            _ => return Err(Error::Malformed),
+        })
+    }
+}
+
+/// Encoded property block of an incoming packet. Iterate it to get at the individual properties.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Properties<'a>(&'a [u8]);
+
+impl<'a> Properties<'a> {
+    pub fn iter(&self) -> PropertyIter<'a> {
+        PropertyIter(Reader::new(self.0))
+    }
+
+    /// The encoded block, for keeping a copy of a message
+    pub fn raw(&self) -> &'a [u8] {
+        self.0
+    }
+
+    /// A block previously taken with `raw()` (it was validated when first decoded)
+    pub fn from_raw(raw: &'a [u8]) -> Self {
+        Self(raw)
+    }
+}
+
+pub struct PropertyIter<'a>(Reader<'a>);
+
+impl<'a> Iterator for PropertyIter<'a> {
+    type Item = Property<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.is_empty() {
+            return None;
+        }
+        //properties are validated when the packet is decoded, so this can't actually fail
+        Property::read(&mut self.0).ok()
+    }
+}
+
+fn properties_len(props: &[Property]) -> usize {
+    props.iter().map(|p| p.encoded_len()).sum()
+}
+
+fn write_properties(w: &mut Writer, props: &[Property]) -> Result<(), Error> {
+    w.varint(properties_len(props))?;
+    props.iter().try_for_each(|p| p.write(w))
+}
+
+fn read_properties<'a>(r: &mut Reader<'a>) -> Result<Properties<'a>, Error> {
+    let len = r.varint()?;
+    let block = r.take(len)?;
+    //validate up front so iterating later can't fail
+    let mut check = Reader::new(block);
+    while !check.is_empty() {
+        Property::read(&mut check)?;
+    }
+    Ok(Properties(block))
+}
+
+/*
+* ----------------------------------------------------------------------
+*
+*                       Primitive readers/writers
+*
+* ----------------------------------------------------------------------
+*/
+
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) -> Result<(), Error> {
+        let end = self.pos + data.len();
+        self.buf.get_mut(self.pos..end).ok_or(Error::BufferTooSmall)?.copy_from_slice(data);
+        self.pos = end;
+        Ok(())
+    }
+
+    pub fn u8(&mut self, v: u8) -> Result<(), Error> {
+        self.bytes(&[v])
+    }
+
+    pub fn u16(&mut self, v: u16) -> Result<(), Error> {
+        self.bytes(&v.to_be_bytes())
+    }
+
+    pub fn u32(&mut self, v: u32) -> Result<(), Error> {
+        self.bytes(&v.to_be_bytes())
+    }
+
+    pub fn varint(&mut self, mut v: usize) -> Result<(), Error> {
+        if v > 268_435_455 {
+            return Err(Error::BufferTooSmall);
+        }
+        loop {
+            let mut byte = (v % 128) as u8;
+            v /= 128;
+            if v > 0 {
+                byte |= 0x80;
+            }
+            self.u8(byte)?;
+            if v == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    pub fn bin(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.u16(u16::try_from(data.len()).map_err(|_| Error::BufferTooSmall)?)?;
+        self.bytes(data)
+    }
+
+    pub fn str(&mut self, s: &str) -> Result<(), Error> {
+        self.bin(s.as_bytes())
+    }
+}
+
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn rest(&mut self) -> &'a [u8] {
+        core::mem::take(&mut self.buf)
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len > self.buf.len() {
+            return Err(Error::Malformed);
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Error> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn varint(&mut self) -> Result<usize, Error> {
+        let mut value = 0;
+        for i in 0..4 {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as usize) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(Error::Malformed)
+    }
+
+    pub fn bin(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+
+    pub fn str(&mut self) -> Result<&'a str, Error> {
+        from_utf8(self.bin()?).map_err(|_| Error::Malformed)
+    }
+}
+
+pub fn varint_len(v: usize) -> usize {
+    match v {
+        0..=127 => 1,
+        128..=16_383 => 2,
+        16_384..=2_097_151 => 3,
+        _ => 4,
+    }
+}
+
+/// Checks whether `buf` starts with a complete fixed header.
+/// Returns `(header_len, remaining_len)` if so, `None` if more bytes are needed.
+pub fn frame(buf: &[u8]) -> Result<Option<(usize, usize)>, Error> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let mut r = Reader::new(&buf[1..]);
+    match r.varint() {
+        Ok(len) => Ok(Some((buf.len() - r.buf.len(), len))),
+        //ran out of bytes part way through the length - wait for more
+        Err(_) if buf.len() < FIXED_HEADER_MAX && buf[1..].iter().all(|b| b & 0x80 != 0) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/*
+* ----------------------------------------------------------------------
+*
+*                           Outgoing packets
+*
+*   Each encoder writes the variable header/payload at buf[5..], then the
+*   fixed header just in front of it, and returns the range to send.
+*
+* ----------------------------------------------------------------------
+*/
+
+fn finish(buf: &mut [u8], first_byte: u8, body_len: usize) -> Result<core::ops::Range<usize>, Error> {
+    let header_len = 1 + varint_len(body_len);
+    let start = FIXED_HEADER_MAX - header_len;
+    let mut w = Writer::new(buf.get_mut(start..FIXED_HEADER_MAX).ok_or(Error::BufferTooSmall)?);
+    w.u8(first_byte)?;
+    w.varint(body_len)?;
+    Ok(start..FIXED_HEADER_MAX + body_len)
+}
+
+fn body(buf: &mut [u8]) -> Result<Writer<'_>, Error> {
+    Ok(Writer::new(buf.get_mut(FIXED_HEADER_MAX..).ok_or(Error::BufferTooSmall)?))
+}
+
+/// Last will, published by the broker if we vanish without a DISCONNECT
+#[derive(Debug, Clone, Copy)]
+pub struct Will<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub qos: QualityOfService,
+    pub retain: bool,
+    pub properties: &'a [Property<'a>],
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Connect<'a> {
+    pub client_id: &'a str,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a [u8]>,
+    pub keep_alive: u16,
+    pub clean_start: bool,
+    pub properties: &'a [Property<'a>],
+    pub will: Option<Will<'a>>,
+}
+
+pub fn encode_connect(buf: &mut [u8], c: &Connect) -> Result<core::ops::Range<usize>, Error> {
+    let mut w = body(buf)?;
+    let mut flags = 0u8;
+    if c.clean_start {
+        flags |= 0x02;
+    }
+    if let Some(will) = &c.will {
+        flags |= 0x04 | (will.qos as u8) << 3;
+        if will.retain {
+            flags |= 0x20;
+        }
+    }
+    if c.password.is_some() {
+        flags |= 0x40;
+    }
+    if c.username.is_some() {
+        flags |= 0x80;
+    }
+
+    w.str("MQTT")?;
+    w.u8(5)?;
+    w.u8(flags)?;
+    w.u16(c.keep_alive)?;
+    write_properties(&mut w, c.properties)?;
+
+    w.str(c.client_id)?;
+    if let Some(will) = &c.will {
+        write_properties(&mut w, will.properties)?;
+        w.str(will.topic)?;
+        w.bin(will.payload)?;
+    }
+    if let Some(username) = c.username {
+        w.str(username)?;
+    }
+    if let Some(password) = c.password {
+        w.bin(password)?;
+    }
+
+    let len = w.pos;
+    finish(buf, CONNECT << 4, len)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Publish<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub qos: QualityOfService,
+    pub retain: bool,
+    pub dup: bool,
+    /// packet identifier - `None` for QoS0
+    pub pid: Option<u16>,
+    pub properties: Properties<'a>,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn encode_publish(
+    buf: &mut [u8],
+    topic: &str,
+    payload: &[u8],
+    qos: QualityOfService,
+    retain: bool,
+    dup: bool,
+    pid: u16,
+    properties: &[Property],
+) -> Result<core::ops::Range<usize>, Error> {
+    let mut w = body(buf)?;
+    w.str(topic)?;
+    if qos != QualityOfService::QoS0 {
+        w.u16(pid)?;
+    }
+    write_properties(&mut w, properties)?;
+    w.bytes(payload)?;
+
+    let first = PUBLISH << 4 | (dup as u8) << 3 | (qos as u8) << 1 | retain as u8;
+    let len = w.pos;
+    finish(buf, first, len)
+}
+
+/// PUBACK/PUBREC/PUBREL/PUBCOMP - all just a packet id and a reason code
+pub fn encode_ack(buf: &mut [u8], kind: u8, pid: u16, reason: u8) -> Result<core::ops::Range<usize>, Error> {
+    let mut w = body(buf)?;
+    w.u16(pid)?;
+    //reason 0 with no properties can be left out entirely
+    if reason != 0 {
+        w.u8(reason)?;
+    }
+    let len = w.pos;
+    //PUBREL has reserved flag bits 0b0010
+    let flags = if kind == PUBREL { 0x02 } else { 0 };
+    finish(buf, kind << 4 | flags, len)
+}
+
+/// Subscribe to `filters` with the given maximum QoS each.
+/// Options other than QoS are left at their defaults (retained messages sent on subscribe, no local etc. off).
+pub fn encode_subscribe(
+    buf: &mut [u8],
+    pid: u16,
+    filters: &[(&str, QualityOfService)],
+    properties: &[Property],
+) -> Result<core::ops::Range<usize>, Error> {
+    let mut w = body(buf)?;
+    w.u16(pid)?;
+    write_properties(&mut w, properties)?;
+    for (filter, qos) in filters {
+        w.str(filter)?;
+        w.u8(*qos as u8)?;
+    }
+    let len = w.pos;
+    finish(buf, SUBSCRIBE << 4 | 0x02, len)
+}
+
+pub fn encode_unsubscribe(buf: &mut [u8], pid: u16, filters: &[&str]) -> Result<core::ops::Range<usize>, Error> {
+    let mut w = body(buf)?;
+    w.u16(pid)?;
+    write_properties(&mut w, &[])?;
+    for filter in filters {
+        w.str(filter)?;
+    }
+    let len = w.pos;
+    finish(buf, UNSUBSCRIBE << 4 | 0x02, len)
+}
+
+pub fn encode_pingreq(buf: &mut [u8]) -> Result<core::ops::Range<usize>, Error> {
+    finish(buf, PINGREQ << 4, 0)
+}
+
+pub fn encode_disconnect(buf: &mut [u8], reason: u8) -> Result<core::ops::Range<usize>, Error> {
+    let mut w = body(buf)?;
+    w.u8(reason)?;
+    let len = w.pos;
+    finish(buf, DISCONNECT << 4, len)
+}
+
+/*
+* ----------------------------------------------------------------------
+*
+*                           Incoming packets
+*
+* ----------------------------------------------------------------------
+*/
+
+#[derive(Debug, Clone, Copy)]
+pub enum Packet<'a> {
+    ConnAck { session_present: bool, reason: u8, properties: Properties<'a> },
+    Publish(Publish<'a>),
+    PubAck { pid: u16, reason: u8 },
+    PubRec { pid: u16, reason: u8 },
+    PubRel { pid: u16, reason: u8 },
+    PubComp { pid: u16, reason: u8 },
+    SubAck { pid: u16, reasons: &'a [u8] },
+    UnsubAck { pid: u16 },
+    PingResp,
+    Disconnect { reason: u8 },
+}
+
+/// Decodes one complete packet (fixed header included). `buf` must hold exactly the packet.
+pub fn decode(buf: &[u8]) -> Result<Packet<'_>, Error> {
+    let (header_len, len) = frame(buf)?.ok_or(Error::Malformed)?;
+    if header_len + len != buf.len() {
+        return Err(Error::Malformed);
+    }
+    let first = buf[0];
+    let mut r = Reader::new(&buf[header_len..]);
+
+    let ack = |r: &mut Reader| -> Result<(u16, u8), Error> {
+        let pid = r.u16()?;
+        let reason = if r.is_empty() { 0 } else { r.u8()? };
+        Ok((pid, reason))
+    };
+
+    Ok(match first >> 4 {
+        CONNACK => {
+            let session_present = r.u8()? & 0x01 != 0;
+            let reason = r.u8()?;
+            let properties = if r.is_empty() { Properties::default() } else { read_properties(&mut r)? };
+            Packet::ConnAck { session_present, reason, properties }
+        },
+        PUBLISH => {
+            let qos = QualityOfService::from_bits((first >> 1) & 0x03)?;
+            let topic = r.str()?;
+            let pid = if qos == QualityOfService::QoS0 { None } else { Some(r.u16()?) };
+            let properties = read_properties(&mut r)?;
+            Packet::Publish(Publish {
+                topic,
+                payload: r.rest(),
+                qos,
+                retain: first & 0x01 != 0,
+                dup: first & 0x08 != 0,
+                pid,
+                properties,
+            })
+        },
+        PUBACK => { let (pid, reason) = ack(&mut r)?; Packet::PubAck { pid, reason } },
+        PUBREC => { let (pid, reason) = ack(&mut r)?; Packet::PubRec { pid, reason } },
+        PUBREL => { let (pid, reason) = ack(&mut r)?; Packet::PubRel { pid, reason } },
+        PUBCOMP => { let (pid, reason) = ack(&mut r)?; Packet::PubComp { pid, reason } },
+        SUBACK => {
+            let pid = r.u16()?;
+            read_properties(&mut r)?;
+            Packet::SubAck { pid, reasons: r.rest() }
+        },
+        UNSUBACK => Packet::UnsubAck { pid: r.u16()? },
+        PINGRESP => Packet::PingResp,
+        DISCONNECT => Packet::Disconnect { reason: if r.is_empty() { 0 } else { r.u8()? } },
+        _ => return Err(Error::Protocol),
+    })
+}
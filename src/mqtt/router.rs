@@ -0,0 +1,145 @@
+use heapless::Vec;
+
+use super::packet::{Properties, QualityOfService};
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   Topic router:
+*
+*           Features register a handler against a topic filter (MQTT wildcards
+*           `+` and `#` allowed). mqtt_task subscribes to every registered filter
+*           and hands each incoming publish to the router, which calls every
+*           handler whose filter matches. Handlers deserialise their own payload
+*           type and forward it to their task's channel - they must not block,
+*           so they use try_send and report `Busy` if the task can't keep up.
+*
+*           Nothing in here knows about sockets or channels, so it can be
+*           exercised on the host.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+/// An incoming publish as seen by a handler
+#[derive(Debug, Clone, Copy)]
+pub struct Message<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub retain: bool,
+    pub properties: Properties<'a>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandlerError {
+    /// the payload couldn't be decoded into the handler's type
+    Malformed,
+    /// the task the handler forwards to is backed up - message dropped
+    Busy,
+    /// decoded fine but not acceptable (the reason is for error reports)
+    Rejected(&'static str),
+}
+
+pub type Handler = fn(&Message<'_>) -> Result<(), HandlerError>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Route {
+    pub filter: &'static str,
+    pub qos: QualityOfService,
+    pub handler: Handler,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouterError {
+    Full,
+    InvalidFilter,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dispatch {
+    /// every matching handler took the message
+    Handled,
+    /// a matching handler failed (later handlers still ran)
+    Failed(HandlerError),
+    /// nothing is registered for this topic
+    NoRoute,
+}
+
+pub struct Router<const N: usize> {
+    routes: Vec<Route, N>,
+}
+
+impl<const N: usize> Default for Router<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Router<N> {
+    pub const fn new() -> Self {
+        Self { routes: Vec::new() }
+    }
+
+    /// Register `handler` for publishes matching `filter`, subscribed at up to `qos`
+    pub fn add(&mut self, filter: &'static str, qos: QualityOfService, handler: Handler) -> Result<(), RouterError> {
+        if !filter_is_valid(filter) {
+            return Err(RouterError::InvalidFilter);
+        }
+        self.routes.push(Route { filter, qos, handler }).map_err(|_| RouterError::Full)
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    /// Call every handler whose filter matches the message's topic
+    pub fn dispatch(&self, msg: &Message<'_>) -> Dispatch {
+        let mut result = Dispatch::NoRoute;
+        for route in self.routes.iter().filter(|route| topic_matches(route.filter, msg.topic)) {
+            match ((route.handler)(msg), result) {
+                (Err(e), Dispatch::NoRoute | Dispatch::Handled) => result = Dispatch::Failed(e),
+                (Ok(()), Dispatch::NoRoute) => result = Dispatch::Handled,
+                _ => {},
+            }
+        }
+        result
+    }
+}
+
+/// `+` and `#` must fill a whole level, and `#` can only be the last one
+pub fn filter_is_valid(filter: &str) -> bool {
+    let mut levels = filter.split('/').peekable();
+    if filter.is_empty() {
+        return false;
+    }
+    while let Some(level) = levels.next() {
+        match level {
+            "#" if levels.peek().is_some() => return false,
+            "#" | "+" => {},
+            level if level.contains(['#', '+']) => return false,
+            _ => {},
+        }
+    }
+    true
+}
+
+/// MQTT topic filter matching: `+` matches exactly one level, a trailing `#` matches
+/// the parent level and everything below it. Wildcards at the start never match `$` topics.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {},
+            (Some(f), Some(t)) if f == t => {},
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use embassy_futures::select::{select4, Either4};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_io_async::{Read, Write};
+use heapless::Vec;
+
+use super::{
+    client::{ConnAck, MqttClient},
+    health::{Due, Health, HealthFigures, HealthPolicy},
+    limit::{Admit, Limiter},
+    outbox::{NextWrite, Outbox},
+    packet::{Connect, Error, Packet, Publish, QualityOfService},
+    router::{Dispatch, HandlerError, Message, Router},
+};
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   One broker session:
+*
+*           Connect, subscribe to everything the router handles, publish the
+*           `birth` messages, replay the outbox, then loop until something ends
+*           the connection:
+*
+*            a) incoming publishes go to the router (unless a rate limit holds them
+*               back until it has a token again), acks for ours to the outbox
+*            b) anything newly queued in the outbox is sent
+*            c) a ping goes out every `ping_interval` (sooner if the broker's keep alive
+*               needs it), and the session ends if PINGRESPs stop coming (see health.rs)
+*
+*           It knows nothing about sockets, statics or the hardware - the transport,
+*           outbox and router are handed in and everything worth reacting to is
+*           passed to `Hooks` - so the same code runs against a fake broker on the host.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+/// Things that happen during a session that the rest of the firmware may care about
+#[derive(Debug, Clone, Copy)]
+pub enum Event<'a> {
+    Connected(ConnAck),
+    Subscribed(&'a [(&'a str, QualityOfService)]),
+    /// the broker refused one of our subscriptions with this reason code
+    SubscriptionRefused(u8),
+    /// a publish arrived and is about to be dispatched
+    Received(&'a Message<'a>),
+    /// an incoming message was thrown away
+    Rejected { topic: Option<&'a str>, reason: Rejection, payload: &'a [u8] },
+    /// a queued publish could never fit the tx buffer and was dropped
+    OutgoingDropped(&'a str),
+    /// an incoming message was held back by its rate limit, to be dispatched later
+    Deferred(&'a str),
+    /// a PINGRESP arrived, or didn't in time - the connection's figures so far
+    Health(HealthFigures),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    NotUtf8,
+    Handler(HandlerError),
+    NoRoute,
+    /// bigger than the rx buffer (the packet was skipped unread, so there's no topic)
+    TooLarge(usize),
+    /// `Hooks::verify` refused it, for this reason
+    Unverified(&'static str),
+    /// over its rate limit (or replaced by a newer message while held back)
+    RateLimited,
+}
+
+pub trait Hooks {
+    fn event(&mut self, event: Event<'_>);
+
+    /// Whether a body that isn't valid UTF-8 should still be dispatched, e.g. it's declared as binary
+    fn binary(&mut self, _msg: &Message<'_>) -> bool {
+        false
+    }
+
+    /// Last check before a message is dispatched, e.g. that it's signed by someone we trust
+    fn verify(&mut self, _msg: &Message<'_>) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    /// Checked after every round of the loop - return true to end the session cleanly
+    fn stop(&mut self) -> bool {
+        false
+    }
+}
+
+/// Why a session finished
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum End {
+    /// CONNECT failed or was refused
+    ConnectFailed(Error),
+    /// the broker sent DISCONNECT with this reason code
+    Disconnected(u8),
+    /// reading or writing failed - the connection can't be trusted any more
+    Failed(Error),
+    /// a keep alive ping couldn't be written
+    PingFailed(Error),
+    /// the broker stopped answering pings (`HealthPolicy::max_missed` in a row) - most likely
+    /// a half-open connection, where writes still succeed but nothing comes back
+    Unresponsive,
+    /// `Hooks::stop` asked for it, the connection is still open
+    Stopped,
+}
+
+pub struct Session<'a, const R: usize, const N: usize> {
+    pub router: &'a Router<R>,
+    pub outbox: &'a Mutex<RefCell<Outbox<N>>>,
+    /// signalled whenever something is pushed to the outbox
+    pub outbox_signal: &'a Signal<CriticalSectionRawMutex, ()>,
+    /// how often to ping - shortened to half the keep alive if that's less
+    pub ping_interval: Duration,
+    /// when to give up on a broker that stops answering pings
+    pub health: HealthPolicy,
+    /// (topic, payload) pairs published retained at QoS0 straight after subscribing, on every connect
+    pub birth: &'a [(&'a str, &'a str)],
+    /// incoming rate limits, and the messages they're holding back
+    pub limiter: &'a RefCell<Limiter<R>>,
+}
+
+impl<const R: usize, const N: usize> Session<'_, R, N> {
+    /// Run a whole session on `client`, returning once the connection has ended (or should end)
+    pub async fn run<T: Read + Write>(
+        &self,
+        client: &mut MqttClient<'_, T>,
+        connect: &Connect<'_>,
+        hooks: &mut impl Hooks,
+    ) -> End {
+        let connack = match client.connect(connect).await {
+            Ok(connack) => connack,
+            Err(e) => return End::ConnectFailed(e),
+        };
+        hooks.event(Event::Connected(connack));
+
+        //the broker gets the last word on keep alive
+        let ping_interval = match connack.server_keep_alive.unwrap_or(connect.keep_alive) {
+            0 => self.ping_interval,
+            secs => self.ping_interval.min(Duration::from_secs(secs as u64) / 2),
+        };
+
+        //subscribe to whatever the router has handlers for
+        let topics: Vec<(&str, QualityOfService), R> =
+            self.router.routes().iter().map(|route| (route.filter, route.qos)).collect();
+        if !topics.is_empty() {
+            if let Err(e) = client.subscribe(&topics).await {
+                return End::Failed(e);
+            }
+            hooks.event(Event::Subscribed(&topics));
+        }
+
+        for (topic, payload) in self.birth {
+            if let Err(e) = client.publish(topic, payload.as_bytes(), QualityOfService::QoS0, true, false, 0, &[]).await {
+                return End::Failed(e);
+            }
+        }
+
+        //anything queued (or left un-acked) while we were away goes out now, in order,
+        //as much at a time as the broker says it can take
+        self.with_outbox(|outbox| {
+            outbox.rewind();
+            outbox.set_receive_maximum(connack.receive_maximum);
+        });
+        if let Err(e) = self.flush_outbox(client, hooks).await {
+            return End::Failed(e);
+        }
+
+        let mut health = Health::new(self.health, ping_interval, Instant::now());
+        loop {
+            let rec_fut = client.poll();
+            let out_fut = self.outbox_signal.wait();
+            let tim_fut = Timer::at(health.due_at());
+            //never fires if nothing is held back
+            let release = self.limiter.borrow().next_release().unwrap_or(Instant::MAX);
+            let lim_fut = Timer::at(release);
+
+            match select4(rec_fut, out_fut, tim_fut, lim_fut).await {
+                Either4::First(packet) => match packet {
+                    Ok(Packet::Publish(publish)) => self.receive(&publish, hooks),
+                    //acks for our own publishes
+                    Ok(Packet::PubAck { pid, .. }) => self.with_outbox(|outbox| outbox.puback(pid)),
+                    Ok(Packet::PubRec { pid, reason }) => self.with_outbox(|outbox| outbox.pubrec(pid, reason)),
+                    Ok(Packet::PubComp { pid, .. }) => self.with_outbox(|outbox| outbox.pubcomp(pid)),
+                    Ok(Packet::SubAck { reasons, .. }) => {
+                        if let Some(code) = reasons.iter().find(|code| **code >= 0x80) {
+                            hooks.event(Event::SubscriptionRefused(*code));
+                        }
+                    },
+                    Ok(Packet::Disconnect { reason }) => return End::Disconnected(reason),
+                    Ok(Packet::PingResp) => {
+                        health.pong(Instant::now());
+                        hooks.event(Event::Health(health.figures()));
+                    },
+                    Ok(_) => {},
+                    //already skipped by the client, the connection is still fine
+                    Err(Error::PacketTooLarge(len)) => hooks.event(Event::Rejected {
+                        topic: None,
+                        reason: Rejection::TooLarge(len),
+                        payload: &[],
+                    }),
+                    Err(e) => return End::Failed(e),
+                },
+                //picked up by the flush below
+                Either4::Second(()) => {},
+                Either4::Third(()) => {
+                    let missed = health.figures().missed;
+                    let due = health.due(Instant::now());
+                    if health.figures().missed != missed {
+                        hooks.event(Event::Health(health.figures()));
+                    }
+                    if due == Due::Dead {
+                        return End::Unresponsive;
+                    }
+                    if let Err(e) = client.ping().await {
+                        return End::PingFailed(e);
+                    }
+                    health.pinged(Instant::now());
+                },
+                Either4::Fourth(()) => {
+                    //copied out, so the limiter isn't borrowed while the handler runs
+                    let released = self.limiter.borrow_mut().release(Instant::now());
+                    if let Some(held) = released {
+                        self.dispatch(&held.message(), hooks);
+                    }
+                },
+            }
+
+            //send anything newly queued (or PUBRELs owed after a PUBREC)
+            if let Err(e) = self.flush_outbox(client, hooks).await {
+                return End::Failed(e);
+            }
+
+            if hooks.stop() {
+                return End::Stopped;
+            }
+        }
+    }
+
+    fn with_outbox<F: FnOnce(&mut Outbox<N>)>(&self, f: F) {
+        critical_section::with(|cs| f(&mut self.outbox.borrow_ref_mut(cs)))
+    }
+
+    /// Hand an incoming publish to whichever handler matches its topic
+    fn receive(&self, publish: &Publish<'_>, hooks: &mut impl Hooks) {
+        let (topic, body) = (publish.topic, publish.payload);
+        let rejected = |reason| Event::Rejected { topic: Some(topic), reason, payload: body };
+
+        let message = Message {
+            topic,
+            payload: body,
+            retain: publish.retain,
+            properties: publish.properties,
+        };
+        if core::str::from_utf8(body).is_err() && !hooks.binary(&message) {
+            hooks.event(rejected(Rejection::NotUtf8));
+            return;
+        }
+
+        hooks.event(Event::Received(&message));
+        if let Err(reason) = hooks.verify(&message) {
+            hooks.event(rejected(Rejection::Unverified(reason)));
+            return;
+        }
+        let admit = self.limiter.borrow_mut().admit(&message, Instant::now());
+        match admit {
+            Admit::Pass => self.dispatch(&message, hooks),
+            Admit::Held => hooks.event(Event::Deferred(topic)),
+            //the older message is the one that's lost (it's gone, hence no payload), this one is held in its place
+            Admit::Replaced => {
+                hooks.event(Event::Rejected { topic: Some(topic), reason: Rejection::RateLimited, payload: &[] });
+                hooks.event(Event::Deferred(topic));
+            },
+            Admit::Dropped => hooks.event(rejected(Rejection::RateLimited)),
+        }
+    }
+
+    fn dispatch(&self, message: &Message<'_>, hooks: &mut impl Hooks) {
+        let rejected = |reason| Event::Rejected { topic: Some(message.topic), reason, payload: message.payload };
+        match self.router.dispatch(message) {
+            Dispatch::Handled => {},
+            Dispatch::Failed(e) => hooks.event(rejected(Rejection::Handler(e))),
+            Dispatch::NoRoute => hooks.event(rejected(Rejection::NoRoute)),
+        }
+    }
+
+    /// Write everything the outbox has waiting, oldest first
+    async fn flush_outbox<T: Read + Write>(
+        &self,
+        client: &mut MqttClient<'_, T>,
+        hooks: &mut impl Hooks,
+    ) -> Result<(), Error> {
+        //copy each message out so the critical section isn't held across the write
+        while let Some(send) = critical_section::with(|cs| self.outbox.borrow_ref(cs).next()) {
+            let result = match &send {
+                NextWrite::Publish { msg, dup } => {
+                    client.publish(&msg.topic, &msg.payload, msg.qos, msg.retain, *dup, msg.pid, &msg.properties()).await
+                },
+                NextWrite::Release { pid } => client.pubrel(*pid).await,
+            };
+
+            match (result, &send) {
+                (Ok(()), _) => self.with_outbox(|outbox| outbox.sent(&send)),
+                //nothing was written, and it'll never fit - drop it rather than wedge the queue
+                (Err(Error::BufferTooSmall), NextWrite::Publish { msg, .. }) => {
+                    hooks.event(Event::OutgoingDropped(&msg.topic));
+                    self.with_outbox(|outbox| outbox.discard(msg.pid));
+                },
+                (Err(e), _) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
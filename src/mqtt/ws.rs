@@ -0,0 +1,384 @@
+use core::fmt::Write as _;
+
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+use heapless::{String, Vec};
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   MQTT over WebSockets (RFC 6455):
+*
+*           Wraps a byte stream (normally a TcpSocket) so MqttClient can run over
+*           it unchanged:
+*
+*            1) `handshake()` sends the HTTP upgrade request (subprotocol "mqtt")
+*               and checks the server's 101 and Sec-WebSocket-Accept
+*            2) every write goes out as one masked binary frame
+*            3) reads hand back the payload of binary/continuation frames as a
+*               plain stream, ending it on close - pings are answered with the
+*               next write (MQTT keep-alive means there's always one soon)
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// longest response header line we look at - longer ones are skipped
+const LINE_LEN: usize = 128;
+/// control frames can't carry more than this
+const CONTROL_LEN: usize = 125;
+/// longest frame header from a server (no mask), or a whole control frame
+const FRAME_LEN: usize = 2 + CONTROL_LEN;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WsError {
+    /// the underlying transport failed
+    Network,
+    /// the server didn't accept the upgrade, for this reason
+    Handshake(&'static str),
+    /// a frame we can't make sense of (masked, reserved bits, bad opcode...)
+    Protocol,
+}
+
+impl embedded_io_async::Error for WsError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Network => ErrorKind::BrokenPipe,
+            Self::Handshake(_) | Self::Protocol => ErrorKind::InvalidData,
+        }
+    }
+}
+
+pub struct WebSocket<T> {
+    transport: T,
+    /// payload bytes of the current data frame still to be read
+    remaining: u64,
+    /// the server sent close (or the stream ended)
+    closed: bool,
+    /// the frame header (or whole control frame) read so far
+    frame: Vec<u8, FRAME_LEN>,
+    /// pong or close to go out before the next write
+    reply: Option<(u8, Vec<u8, CONTROL_LEN>)>,
+    /// xorshift state for masking keys and the handshake nonce
+    rng: u32,
+}
+
+impl<T: Read + Write> WebSocket<T> {
+    /// `seed` should come from a real random source - it's only used for masking
+    /// and the handshake nonce, but mustn't repeat between connections
+    pub fn new(transport: T, seed: u32) -> Self {
+        Self { transport, remaining: 0, closed: false, frame: Vec::new(), reply: None, rng: seed.max(1) }
+    }
+
+    fn random(&mut self) -> u32 {
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng = x;
+        x
+    }
+
+    /// Upgrade the connection: GET `path` on `host` asking for the "mqtt" subprotocol
+    pub async fn handshake(&mut self, host: &str, path: &str) -> Result<(), WsError> {
+        let mut nonce = [0u8; 16];
+        for chunk in nonce.chunks_mut(4) {
+            chunk.copy_from_slice(&self.random().to_le_bytes());
+        }
+        let mut key = [0u8; 24];
+        base64(&nonce, &mut key);
+        let key = core::str::from_utf8(&key).map_err(|_| WsError::Protocol)?;
+
+        let mut request: String<256> = String::new();
+        write!(
+            request,
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: mqtt\r\n\r\n",
+            path, host, key
+        )
+        .map_err(|_| WsError::Handshake("host/path too long"))?;
+        self.transport.write_all(request.as_bytes()).await.map_err(|_| WsError::Network)?;
+
+        let mut expected = [0u8; 28];
+        base64(&sha1(&[key.as_bytes(), GUID.as_bytes()]), &mut expected);
+
+        //read the response a line at a time - byte by byte, so nothing after the headers is swallowed
+        let mut status_seen = false;
+        let mut accepted = false;
+        loop {
+            let line = self.read_line().await?;
+            if line.is_empty() {
+                break;
+            }
+            if !status_seen {
+                status_seen = true;
+                if line.split(' ').nth(1) != Some("101") {
+                    return Err(WsError::Handshake("upgrade refused"));
+                }
+                continue;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("sec-websocket-accept") {
+                    accepted = value.trim().as_bytes() == expected;
+                }
+            }
+        }
+        if !accepted {
+            return Err(WsError::Handshake("bad Sec-WebSocket-Accept"));
+        }
+        Ok(())
+    }
+
+    /// One header line without its CRLF (truncated to LINE_LEN)
+    async fn read_line(&mut self) -> Result<String<LINE_LEN>, WsError> {
+        let mut line: String<LINE_LEN> = String::new();
+        loop {
+            let mut byte = [0u8; 1];
+            self.read_raw(&mut byte).await?;
+            match byte[0] {
+                b'\n' => return Ok(line),
+                b'\r' => {},
+                byte => _ = line.push(byte as char),
+            }
+        }
+    }
+
+    async fn read_raw(&mut self, buf: &mut [u8]) -> Result<(), WsError> {
+        self.transport.read_exact(buf).await.map_err(|_| WsError::Network)
+    }
+
+    /// Write one masked frame
+    async fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<(), WsError> {
+        let mask = self.random().to_be_bytes();
+        let mut header = [0u8; 14];
+        header[0] = 0x80 | opcode;
+        let len = payload.len();
+        let mut header_len = match len {
+            0..=125 => {
+                header[1] = 0x80 | len as u8;
+                2
+            },
+            126..=0xffff => {
+                header[1] = 0x80 | 126;
+                header[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+                4
+            },
+            _ => {
+                header[1] = 0x80 | 127;
+                header[2..10].copy_from_slice(&(len as u64).to_be_bytes());
+                10
+            },
+        };
+        header[header_len..header_len + 4].copy_from_slice(&mask);
+        header_len += 4;
+        self.transport.write_all(&header[..header_len]).await.map_err(|_| WsError::Network)?;
+
+        let mut chunk = [0u8; 64];
+        for (n, part) in payload.chunks(chunk.len()).enumerate() {
+            for (i, byte) in part.iter().enumerate() {
+                chunk[i] = byte ^ mask[(n * 64 + i) % 4];
+            }
+            self.transport.write_all(&chunk[..part.len()]).await.map_err(|_| WsError::Network)?;
+        }
+        Ok(())
+    }
+
+    /// Read frame headers until a data frame with something in it turns up,
+    /// dealing with any control frames on the way.
+    ///
+    /// MqttClient::poll gets raced against timers, so this has to survive being
+    /// dropped at any await: header bytes are collected in `self.frame` rather than
+    /// on the stack, and replies to control frames wait for the next write
+    async fn next_data_frame(&mut self) -> Result<(), WsError> {
+        while self.remaining == 0 && !self.closed {
+            let needed = self.frame_len()?;
+            if self.frame.len() < needed {
+                let mut chunk = [0u8; FRAME_LEN];
+                let chunk = &mut chunk[..needed - self.frame.len()];
+                let read = self.transport.read(chunk).await.map_err(|_| WsError::Network)?;
+                if read == 0 {
+                    self.closed = true;
+                }
+                //can't fail, frame_len() never asks for more than FRAME_LEN
+                _ = self.frame.extend_from_slice(&chunk[..read]);
+                continue;
+            }
+
+            let opcode = self.frame[0] & 0x0f;
+            let header_len = header_len(self.frame[1]);
+            let len = match header_len {
+                4 => u16::from_be_bytes([self.frame[2], self.frame[3]]) as u64,
+                10 => u64::from_be_bytes(self.frame[2..10].try_into().unwrap_or_default()),
+                _ => (self.frame[1] & 0x7f) as u64,
+            };
+            match opcode {
+                OP_CONTINUATION | OP_TEXT | OP_BINARY => self.remaining = len,
+                OP_PING => self.reply = Some((OP_PONG, Vec::from_slice(&self.frame[header_len..]).unwrap_or_default())),
+                OP_CLOSE => {
+                    //echo the status code back, as the closing handshake asks
+                    let status = &self.frame[header_len..(header_len + 2).min(self.frame.len())];
+                    self.reply = Some((OP_CLOSE, Vec::from_slice(status).unwrap_or_default()));
+                    self.closed = true;
+                },
+                _ => {},
+            }
+            self.frame.clear();
+        }
+        Ok(())
+    }
+
+    /// Bytes of the frame in `self.frame` needed before it can be dealt with: the header,
+    /// plus the payload for control frames (data payloads are read straight into the caller's buffer)
+    fn frame_len(&self) -> Result<usize, WsError> {
+        if self.frame.len() < 2 {
+            return Ok(2);
+        }
+        let (head, opcode) = (self.frame[1], self.frame[0] & 0x0f);
+        //no extensions were negotiated, so the reserved bits must be clear, and servers never mask
+        if self.frame[0] & 0x70 != 0 || head & 0x80 != 0 {
+            return Err(WsError::Protocol);
+        }
+        match opcode {
+            OP_CONTINUATION | OP_TEXT | OP_BINARY => Ok(header_len(head)),
+            OP_CLOSE | OP_PING | OP_PONG if head as usize <= CONTROL_LEN => Ok(2 + head as usize),
+            _ => Err(WsError::Protocol),
+        }
+    }
+
+    /// Send any reply to a control frame that came in
+    async fn send_reply(&mut self) -> Result<(), WsError> {
+        if let Some((opcode, payload)) = self.reply.take() {
+            self.send_frame(opcode, &payload).await?;
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+}
+
+impl<T> ErrorType for WebSocket<T> {
+    type Error = WsError;
+}
+
+impl<T: Read + Write> Read for WebSocket<T> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, WsError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.next_data_frame().await?;
+        if self.closed {
+            return Ok(0);
+        }
+        let len = (buf.len() as u64).min(self.remaining) as usize;
+        let read = self.transport.read(&mut buf[..len]).await.map_err(|_| WsError::Network)?;
+        if read == 0 {
+            self.closed = true;
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+impl<T: Read + Write> Write for WebSocket<T> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, WsError> {
+        //a close reply still goes out, best effort
+        let replied = self.send_reply().await;
+        if self.closed {
+            return Err(WsError::Network);
+        }
+        replied?;
+        self.send_frame(OP_BINARY, buf).await?;
+        Ok(buf.len())
+    }
+
+    async fn flush(&mut self) -> Result<(), WsError> {
+        self.transport.flush().await.map_err(|_| WsError::Network)
+    }
+}
+
+/// Length of a server frame header, from its second byte
+fn header_len(head: u8) -> usize {
+    match head & 0x7f {
+        126 => 4,
+        127 => 10,
+        _ => 2,
+    }
+}
+
+/// Standard base64 (with padding) of `input` into `out`, which must be 4 * ceil(len / 3) long
+pub fn base64(input: &[u8], out: &mut [u8]) {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    for (chunk, out) in input.chunks(3).zip(out.chunks_mut(4)) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for (i, out) in out.iter_mut().enumerate() {
+            *out = if i <= chunk.len() { ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3f] } else { b'=' };
+        }
+    }
+}
+
+/// SHA-1 of the concatenation of `parts` - only used for the handshake's accept check
+pub fn sha1(parts: &[&[u8]]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let total: usize = parts.iter().map(|part| part.len()).sum();
+
+    let mut block = [0u8; 64];
+    let mut filled = 0;
+    let mut compress = |block: &[u8; 64]| {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    };
+
+    let bit_len = (total as u64 * 8).to_be_bytes();
+    let padding_len = if total % 64 < 56 { 56 - total % 64 } else { 120 - total % 64 };
+    let padding = parts.iter().flat_map(|part| part.iter().copied())
+        .chain(core::iter::once(0x80))
+        .chain(core::iter::repeat_n(0, padding_len - 1))
+        .chain(bit_len);
+    for byte in padding {
+        block[filled] = byte;
+        filled += 1;
+        if filled == 64 {
+            compress(&block);
+            filled = 0;
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (out, word) in digest.chunks_mut(4).zip(h) {
+        out.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
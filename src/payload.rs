@@ -0,0 +1,102 @@
+//incoming payload encodings: json everywhere, and cbor as well when built with the "cbor" feature
+
+#[cfg(feature = "cbor")]
+use minicbor::{data::Type, decode::Error as CborError, Decoder};
+use esp_println::println;
+use serde::Deserialize;
+
+use crate::mqtt::router::{HandlerError, Message};
+
+/// content types put on everything we publish
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+#[cfg(feature = "homie")]
+pub const TEXT_CONTENT_TYPE: &str = "text/plain";
+
+/// content type (MQTT v5 property) that marks a payload as cbor
+#[cfg(feature = "cbor")]
+pub const CBOR_CONTENT_TYPE: &str = "application/cbor";
+/// ...or publish to the topic with this on the end, for clients that can't set properties
+#[cfg(feature = "cbor")]
+pub const CBOR_SUFFIX: &str = "/cbor";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+/// How `msg` says its payload is encoded - json unless it says otherwise
+#[cfg_attr(not(feature = "cbor"), allow(unused_variables))]
+pub fn encoding(msg: &Message<'_>) -> Encoding {
+    #[cfg(feature = "cbor")]
+    {
+        use crate::mqtt::Property;
+        let content_type = msg.properties.iter().find_map(|prop| match prop {
+            Property::ContentType(content_type) => Some(content_type),
+            _ => None,
+        });
+        if content_type == Some(CBOR_CONTENT_TYPE) || msg.topic.ends_with(CBOR_SUFFIX) {
+            return Encoding::Cbor;
+        }
+    }
+    Encoding::Json
+}
+
+/// A handler's payload type - json through serde, and (with the "cbor" feature) cbor as well
+#[cfg(feature = "cbor")]
+pub trait Payload<'a>: Deserialize<'a> + FromCbor<'a> {}
+#[cfg(feature = "cbor")]
+impl<'a, T: Deserialize<'a> + FromCbor<'a>> Payload<'a> for T {}
+#[cfg(not(feature = "cbor"))]
+pub trait Payload<'a>: Deserialize<'a> {}
+#[cfg(not(feature = "cbor"))]
+impl<'a, T: Deserialize<'a>> Payload<'a> for T {}
+
+/// Decoded from a cbor map with the same text keys as the type's json
+#[cfg(feature = "cbor")]
+pub trait FromCbor<'b>: Sized {
+    fn from_cbor(payload: &'b [u8]) -> Result<Self, CborError>;
+}
+
+/// Walk the cbor map in `payload`, handing `field` each text key with the decoder sitting on its value.
+/// `field` has to read or `skip()` the value. Only definite length strings, which borrow straight from
+/// the payload - the map itself may be either.
+#[cfg(feature = "cbor")]
+pub fn cbor_map<'b, F>(payload: &'b [u8], mut field: F) -> Result<(), CborError>
+where
+    F: FnMut(&'b str, &mut Decoder<'b>) -> Result<(), CborError>,
+{
+    let mut d = Decoder::new(payload);
+    let len = d.map()?;
+    let mut seen = 0;
+    loop {
+        match len {
+            Some(len) if seen == len => break,
+            None if d.datatype()? == Type::Break => {
+                d.set_position(d.position() + 1);
+                break;
+            },
+            _ => {},
+        }
+        let key = d.str()?;
+        field(key, &mut d)?;
+        seen += 1;
+    }
+    Ok(())
+}
+
+/// Decode `msg`'s payload into a handler's type, in whichever encoding it arrived in
+pub fn decode<'a, T: Payload<'a>>(msg: &Message<'a>) -> Result<T, HandlerError> {
+    match encoding(msg) {
+        Encoding::Json => serde_json_core::from_slice::<T>(msg.payload).map(|(value, _)| value).map_err(|e| {
+            println!("malformed json: {}", e);
+            HandlerError::Malformed
+        }),
+        #[cfg(feature = "cbor")]
+        Encoding::Cbor => T::from_cbor(msg.payload).map_err(|e| {
+            println!("malformed cbor: {}", e);
+            HandlerError::Malformed
+        }),
+    }
+}
@@ -0,0 +1,360 @@
+use core::{cell::Cell, fmt::Write as _};
+
+use critical_section::Mutex;
+use embassy_futures::select::{select, Either};
+use embassy_net::{tcp::TcpSocket, udp::{PacketMetadata, UdpSocket}, Ipv4Address, Stack};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_io_async::Write;
+use embedded_storage::{ReadStorage, Storage};
+use esp_hal::{ram, rng::Rng};
+use esp_println::println;
+use esp_storage::FlashStorage;
+use heapless::{String, Vec};
+
+use crate::{mk_static, mqtt::failover::Broker, wireless::networks::{self, Network}};
+
+pub mod dhcp;
+pub mod dns;
+pub mod form;
+pub mod settings;
+
+use form::{Notice, Request};
+use settings::{Settings, SettingsError, MAX_NETWORKS, RECORD_LEN};
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   Provisioning mode:
+*
+*           Instead of joining a network, the device runs its own access point with
+*           a small web page for the Wi-Fi and MQTT settings, which are saved to
+*           flash and used from then on (ahead of anything built in). It's entered:
+*
+*            1) on first boot - nothing saved, and no networks given at compile time
+*            2) when the button is held down for PROVISION_HOLD_MS (see input.rs)
+*            3) after repeated connection failures - none of the Wi-Fi networks
+*               joining (WIFI_MAX_FAILURES rounds in a row) or MQTT giving up
+*
+*           The radio can't be a station and serve the portal at the same time here,
+*           so asking for provisioning sets a flag in RTC memory (it survives a
+*           software reset) and restarts; main() picks the mode at boot.
+*
+*           The access point is "<MQTT_ID>-setup", WPA2 with a fresh random password
+*           each time. Both are shown on the e-ink panel along with a QR code that
+*           joins it. The device hands out addresses itself (dhcp.rs) and answers
+*           every DNS lookup with its own address (dns.rs) so the page pops up on
+*           its own on most phones - or browse to AP_URL.
+*
+*           If there was something to go back to (settings saved, or built in), the
+*           portal gives up after PORTAL_TIMEOUT and restarts normally - so an
+*           access point that was only down for a while doesn't leave the device
+*           stuck in setup.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+/// where the settings record lives: the nvs partition of espflash's default partition table,
+/// which nothing else uses in a no_std build
+const SETTINGS_OFFSET: u32 = 0x9000;
+
+pub const AP_ADDRESS: [u8; 4] = [192, 168, 4, 1];
+pub const AP_URL: &str = "http://192.168.4.1";
+const AP_SSID: &str = concat!(env!("MQTT_ID"), "-setup");
+const AP_PASSWORD_LEN: usize = 8;
+//no 0/o, 1/l/i - it gets typed in from the panel
+const AP_PASSWORD_CHARS: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const PORTAL_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+const HTTP_PORT: u16 = 80;
+/// leases handed out by the access point
+const MAX_CLIENTS: usize = 4;
+const LEASE_SECS: u32 = 60 * 60;
+
+//built in networks, besides SSID/PASSW (see wireless/networks.rs for the format)
+const WIFI_NETWORKS: Option<&str> = option_env!("WIFI_NETWORKS");
+
+/// "PROV" - anything else (like whatever RTC memory holds after power-on) means no request
+const REQUEST_MAGIC: u32 = 0x5052_4f56;
+
+#[ram(rtc_fast, persistent)]
+static mut PROVISION_REQUEST: u32 = 0;
+
+//what was saved, read once at boot
+static STORED: Mutex<Cell<Option<&'static Settings>>> = Mutex::new(Cell::new(None));
+
+/// Read the saved settings, and decide whether this boot is for provisioning
+pub fn init() -> bool {
+    let stored = match load() {
+        Ok(settings) => {
+            println!("Using saved settings ({} Wi-Fi networks)", settings.networks.len());
+            Some(&*mk_static::mk_static!(Settings, settings))
+        },
+        Err(SettingsError::Empty) => None,
+        Err(e) => {
+            println!("Ignoring saved settings: {:?}", e);
+            None
+        },
+    };
+    critical_section::with(|cs| STORED.borrow(cs).set(stored));
+
+    if take_request() {
+        println!("Provisioning requested");
+        true
+    } else if wifi_networks().is_empty() {
+        println!("No Wi-Fi settings yet - provisioning");
+        true
+    } else {
+        false
+    }
+}
+
+/// The settings saved by the portal, if any
+pub fn stored() -> Option<&'static Settings> {
+    critical_section::with(|cs| STORED.borrow(cs).get())
+}
+
+/// The Wi-Fi networks we know: the saved ones, otherwise those built in (`WIFI_NETWORKS`, then `SSID`/`PASSW`)
+pub fn wifi_networks() -> Vec<Network<'static>, MAX_NETWORKS> {
+    if let Some(settings) = stored().filter(|settings| !settings.networks.is_empty()) {
+        return settings.networks.iter()
+            .map(|network| Network { ssid: &network.ssid, password: &network.password, priority: network.priority, ipv4: network.ipv4.as_ref() })
+            .collect();
+    }
+
+    let mut built_in: Vec<Network<'static>, MAX_NETWORKS> = networks::parse_list(WIFI_NETWORKS.unwrap_or_default());
+    if let Some(ssid) = option_env!("SSID") {
+        if built_in.push(Network { ssid, password: option_env!("PASSW").unwrap_or_default(), priority: 0, ipv4: None }).is_err() {
+            println!("Only {} Wi-Fi networks can be used, ignoring {}", MAX_NETWORKS, ssid);
+        }
+    }
+    built_in
+}
+
+/// The broker saved by the portal, if one was given (it replaces any built in)
+pub fn broker() -> Option<Broker<'static>> {
+    let settings = stored().filter(|settings| !settings.mqtt_host.is_empty())?;
+    Some(Broker {
+        host: &settings.mqtt_host,
+        port: settings.mqtt_port,
+        username: Some(settings.mqtt_user.as_str()).filter(|user| !user.is_empty()),
+        password: Some(settings.mqtt_password.as_str()).filter(|pass| !pass.is_empty()),
+        tls: false,
+        path: None,
+    })
+}
+
+/// Restart into provisioning mode
+pub fn request(why: &str) {
+    println!("Restarting into provisioning mode: {}", why);
+    // SAFETY: a plain word written once, just before the reset - nothing else is looking at it
+    unsafe { core::ptr::addr_of_mut!(PROVISION_REQUEST).write_volatile(REQUEST_MAGIC) };
+    esp_hal::reset::software_reset();
+}
+
+fn take_request() -> bool {
+    // SAFETY: only touched from here (at boot) and `request`, never at the same time
+    unsafe {
+        let requested = core::ptr::addr_of!(PROVISION_REQUEST).read_volatile() == REQUEST_MAGIC;
+        core::ptr::addr_of_mut!(PROVISION_REQUEST).write_volatile(0);
+        requested
+    }
+}
+
+fn load() -> Result<Settings, SettingsError> {
+    let mut record = [0u8; RECORD_LEN];
+    FlashStorage::new().read(SETTINGS_OFFSET, &mut record).map_err(|e| {
+        println!("Flash read error: {:?}", e);
+        SettingsError::Corrupt
+    })?;
+    Settings::decode(&record)
+}
+
+fn save(settings: &Settings) -> bool {
+    let mut record = [0u8; RECORD_LEN];
+    let Ok(len) = settings.encode(&mut record) else {
+        return false;
+    };
+    match FlashStorage::new().write(SETTINGS_OFFSET, &record[..len]) {
+        Ok(()) => true,
+        Err(e) => {
+            println!("Flash write error: {:?}", e);
+            false
+        },
+    }
+}
+
+/// The setup network
+pub struct AccessPoint {
+    pub ssid: String<32>,
+    pub password: String<AP_PASSWORD_LEN>,
+}
+
+impl AccessPoint {
+    /// What a "join Wi-Fi" QR code holds: WIFI:T:WPA;S:<ssid>;P:<password>;;
+    pub fn join_code(&self) -> String<128> {
+        let mut code = String::new();
+        _ = code.push_str("WIFI:T:WPA;S:");
+        push_escaped(&mut code, &self.ssid);
+        _ = code.push_str(";P:");
+        push_escaped(&mut code, &self.password);
+        _ = code.push_str(";;");
+        code
+    }
+}
+
+/// The setup network's name, and a new password for it
+pub fn access_point(rng: &mut Rng) -> &'static AccessPoint {
+    let mut ssid = String::new();
+    for c in AP_SSID.chars() {
+        if ssid.push(c).is_err() {
+            break;
+        }
+    }
+    let mut password = String::new();
+    while password.len() < AP_PASSWORD_LEN {
+        let c = AP_PASSWORD_CHARS[rng.random() as usize % AP_PASSWORD_CHARS.len()];
+        _ = password.push(c as char);
+    }
+    mk_static::mk_static!(AccessPoint, AccessPoint { ssid, password })
+}
+
+/// Backslash the characters that mean something in a WIFI: code
+fn push_escaped<const N: usize>(code: &mut String<N>, text: &str) {
+    for c in text.chars() {
+        if matches!(c, '\\' | ';' | ',' | ':' | '"') {
+            _ = code.push('\\');
+        }
+        _ = code.push(c);
+    }
+}
+
+/// Serves the settings page until something is saved (then restarts to use it)
+#[embassy_executor::task]
+pub async fn portal_task(stack: Stack<'static>) {
+    let rx_buffer = mk_static::mk_static!([u8; 1024], [0; 1024]);
+    let tx_buffer = mk_static::mk_static!([u8; 1536], [0; 1536]);
+    let request = mk_static::mk_static!([u8; 1024], [0; 1024]);
+    let page = mk_static::mk_static!(String<{ form::PAGE_LEN }>, String::new());
+    //only worth giving up if there's something to go back to
+    let give_up_at = (!wifi_networks().is_empty()).then(|| Instant::now() + PORTAL_TIMEOUT);
+
+    println!("Settings page at {}", AP_URL);
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer[..], &mut tx_buffer[..]);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+        match select(socket.accept(HTTP_PORT), Timer::at(give_up_at.unwrap_or(Instant::MAX))).await {
+            Either::First(Ok(())) => {},
+            Either::First(Err(e)) => {
+                println!("Portal accept error: {:?}", e);
+                continue;
+            },
+            Either::Second(()) => {
+                println!("Nothing saved in time - restarting with the settings we had");
+                esp_hal::reset::software_reset();
+            },
+        }
+
+        let saved = serve(&mut socket, request, page).await;
+        _ = socket.flush().await;
+        socket.close();
+        Timer::after_millis(100).await;
+
+        if saved {
+            println!("Settings saved - restarting");
+            Timer::after_secs(1).await;
+            esp_hal::reset::software_reset();
+        }
+    }
+}
+
+/// Answer one request, returning whether new settings were saved
+async fn serve(socket: &mut TcpSocket<'_>, buf: &mut [u8], page: &mut String<{ form::PAGE_LEN }>) -> bool {
+    let mut len = 0;
+    while matches!(form::request(&buf[..len]), Request::Incomplete) && len < buf.len() {
+        match socket.read(&mut buf[len..]).await {
+            Ok(0) | Err(_) => return false,
+            Ok(read) => len += read,
+        }
+    }
+
+    let current = stored().cloned().unwrap_or_default();
+    let (saved, notice) = match form::request(&buf[..len]) {
+        Request::Page => (false, Notice::None),
+        Request::Save(body) => match form::parse(body, &current) {
+            Ok(settings) if save(&settings) => (true, Notice::Saved),
+            Ok(_) => (false, Notice::NotStored),
+            Err(e) => (false, Notice::Error(e)),
+        },
+        Request::Incomplete | Request::Invalid => {
+            _ = socket.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+            return false;
+        },
+    };
+
+    page.clear();
+    if form::page(page, env!("MQTT_ID"), &current, notice).is_err() {
+        println!("Settings page didn't fit");
+    }
+    let mut head: String<160> = String::new();
+    _ = write!(
+        head,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        page.len()
+    );
+    if socket.write_all(head.as_bytes()).await.is_err() || socket.write_all(page.as_bytes()).await.is_err() {
+        println!("Couldn't send the settings page");
+    }
+    saved
+}
+
+/// Hands out addresses on the setup network
+#[embassy_executor::task]
+pub async fn dhcp_task(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_buffer = [0u8; 1024];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    if let Err(e) = socket.bind(dhcp::SERVER_PORT) {
+        println!("DHCP server bind error: {:?}", e);
+        return;
+    }
+
+    let mut server = dhcp::Server::<MAX_CLIENTS>::new(AP_ADDRESS, AP_ADDRESS[3] + 1, LEASE_SECS);
+    let mut request = [0u8; 576];
+    let mut reply = [0u8; dhcp::MAX_REPLY_LEN];
+    loop {
+        let Ok((len, _)) = socket.recv_from(&mut request).await else {
+            continue;
+        };
+        if let Some(len) = server.reply(&request[..len], &mut reply) {
+            //the client has no address yet - broadcast it
+            _ = socket.send_to(&reply[..len], (Ipv4Address::BROADCAST, dhcp::CLIENT_PORT)).await;
+        }
+    }
+}
+
+/// Answers every DNS lookup on the setup network with our own address
+#[embassy_executor::task]
+pub async fn dns_task(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_buffer = [0u8; 1024];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    if let Err(e) = socket.bind(dns::PORT) {
+        println!("DNS server bind error: {:?}", e);
+        return;
+    }
+
+    let mut query = [0u8; dns::MAX_MESSAGE_LEN];
+    let mut answer = [0u8; dns::MAX_MESSAGE_LEN];
+    loop {
+        let Ok((len, from)) = socket.recv_from(&mut query).await else {
+            continue;
+        };
+        if let Some(len) = dns::answer(&query[..len], AP_ADDRESS, &mut answer) {
+            _ = socket.send_to(&answer[..len], from).await;
+        }
+    }
+}
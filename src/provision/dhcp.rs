@@ -0,0 +1,174 @@
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   DHCP server (just enough for the provisioning access point):
+*
+*           embassy-net only has a DHCP client, and a phone joining the access point
+*           won't do anything without an address. So: answer DISCOVER with an OFFER
+*           and REQUEST with an ACK (or a NAK if it asks for an address that isn't
+*           its own), handing each client a lease from a small pool and pointing its
+*           router and DNS at us, so every lookup lands on the portal.
+*
+*           Leases are per hardware address. When the pool is full the least
+*           recently used one is handed out again - only a handful of devices are
+*           ever going to join.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+/// replies are padded to the minimum BOOTP message size
+pub const MAX_REPLY_LEN: usize = 300;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const COOKIE: [u8; 4] = [99, 130, 83, 99];
+const OPTIONS_AT: usize = 240;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS: u8 = 6;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_END: u8 = 255;
+
+pub struct Server<const N: usize> {
+    /// our own address - also the router and DNS server we hand out
+    address: [u8; 4],
+    /// first address in the pool (the pool is N addresses from here, in the last octet)
+    pool_start: u8,
+    lease_secs: u32,
+    leases: [Option<Lease>; N],
+    /// bumped on every lease handed out, to find the least recently used
+    clock: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Lease {
+    mac: [u8; 6],
+    used: u32,
+}
+
+impl<const N: usize> Server<N> {
+    /// A server at `address` (netmask 255.255.255.0), leasing out `.pool_start` onwards
+    pub const fn new(address: [u8; 4], pool_start: u8, lease_secs: u32) -> Self {
+        Self { address, pool_start, lease_secs, leases: [None; N], clock: 0 }
+    }
+
+    /// Answer a client's message, writing the reply (to broadcast to the client port) into `out`
+    pub fn reply(&mut self, request: &[u8], out: &mut [u8; MAX_REPLY_LEN]) -> Option<usize> {
+        if request.len() < OPTIONS_AT || request[0] != BOOTREQUEST || request[1] != 1 || request[2] != 6 {
+            return None;
+        }
+        if request[236..OPTIONS_AT] != COOKIE {
+            return None;
+        }
+        let mut mac = [0u8; 6];
+        mac.copy_from_slice(&request[28..34]);
+
+        let message_type = option(request, OPT_MESSAGE_TYPE).and_then(|value| value.first().copied())?;
+        let (reply_type, yiaddr) = match message_type {
+            DISCOVER => (OFFER, self.lease(mac)),
+            REQUEST => {
+                //someone else's offer was taken
+                if option(request, OPT_SERVER_ID).is_some_and(|server| server != self.address) {
+                    return None;
+                }
+                let yiaddr = self.lease(mac);
+                //in the option when selecting/rebooting, in ciaddr when renewing
+                let wanted = match option(request, OPT_REQUESTED_IP) {
+                    Some([a, b, c, d]) => [*a, *b, *c, *d],
+                    _ => [request[12], request[13], request[14], request[15]],
+                };
+                if wanted == yiaddr { (ACK, yiaddr) } else { (NAK, [0; 4]) }
+            },
+            //releases, declines and informs need nothing from us
+            _ => return None,
+        };
+
+        out.fill(0);
+        out[0] = BOOTREPLY;
+        out[1] = 1;
+        out[2] = 6;
+        //xid, and the broadcast flag
+        out[4..8].copy_from_slice(&request[4..8]);
+        out[10..12].copy_from_slice(&request[10..12]);
+        out[16..20].copy_from_slice(&yiaddr);
+        out[20..24].copy_from_slice(&self.address);
+        out[28..44].copy_from_slice(&request[28..44]);
+        out[236..OPTIONS_AT].copy_from_slice(&COOKIE);
+
+        let mut options = Options { out, at: OPTIONS_AT };
+        options.put(OPT_MESSAGE_TYPE, &[reply_type]);
+        options.put(OPT_SERVER_ID, &self.address);
+        if reply_type != NAK {
+            options.put(OPT_LEASE_TIME, &self.lease_secs.to_be_bytes());
+            options.put(OPT_SUBNET_MASK, &[255, 255, 255, 0]);
+            options.put(OPT_ROUTER, &self.address);
+            options.put(OPT_DNS, &self.address);
+        }
+        options.put(OPT_END, &[]);
+        Some(MAX_REPLY_LEN)
+    }
+
+    /// The address for `mac`: the one it already has, a free one, or the least recently used
+    fn lease(&mut self, mac: [u8; 6]) -> [u8; 4] {
+        self.clock = self.clock.wrapping_add(1);
+        let slot = self.leases.iter().position(|lease| lease.is_some_and(|lease| lease.mac == mac))
+            .or_else(|| self.leases.iter().position(Option::is_none))
+            .unwrap_or_else(|| {
+                (0..N).min_by_key(|slot| self.leases[*slot].map_or(0, |lease| lease.used)).unwrap_or(0)
+            });
+        self.leases[slot] = Some(Lease { mac, used: self.clock });
+        let [a, b, c, _] = self.address;
+        [a, b, c, self.pool_start.wrapping_add(slot as u8)]
+    }
+}
+
+/// The value of option `code` in a DHCP message
+fn option(message: &[u8], code: u8) -> Option<&[u8]> {
+    let mut rest = message.get(OPTIONS_AT..)?;
+    loop {
+        match rest {
+            [OPT_END, ..] | [] => return None,
+            [OPT_PAD, tail @ ..] => rest = tail,
+            [found, len, tail @ ..] => {
+                let value = tail.get(..*len as usize)?;
+                if *found == code {
+                    return Some(value);
+                }
+                rest = &tail[*len as usize..];
+            },
+            [_] => return None,
+        }
+    }
+}
+
+/// Appends options to a reply
+struct Options<'a> {
+    out: &'a mut [u8; MAX_REPLY_LEN],
+    at: usize,
+}
+
+impl Options<'_> {
+    fn put(&mut self, code: u8, value: &[u8]) {
+        self.out[self.at] = code;
+        self.at += 1;
+        if code != OPT_END {
+            self.out[self.at] = value.len() as u8;
+            self.out[self.at + 1..self.at + 1 + value.len()].copy_from_slice(value);
+            self.at += 1 + value.len();
+        }
+    }
+}
@@ -0,0 +1,79 @@
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   Captive DNS:
+*
+*           While provisioning, every name resolves to us. Whatever a phone tries
+*           to look up (its connectivity check included) ends up at the portal.
+*           Only A queries get an answer, anything else (AAAA...) gets an
+*           empty one so the client falls back to IPv4.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+pub const PORT: u16 = 53;
+/// plenty for one question and its answer
+pub const MAX_MESSAGE_LEN: usize = 512;
+
+const HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+const TTL_SECS: u32 = 60;
+
+/// Answer a query with `address`, writing the response into `out`
+pub fn answer(query: &[u8], address: [u8; 4], out: &mut [u8; MAX_MESSAGE_LEN]) -> Option<usize> {
+    let header = query.get(..HEADER_LEN)?;
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    //responses, and anything but a standard query
+    if flags & 0x8000 != 0 || flags & 0x7800 != 0 {
+        return None;
+    }
+    if u16::from_be_bytes([header[4], header[5]]) != 1 {
+        return None;
+    }
+
+    //step over the name's labels to the question's type and class
+    let mut at = HEADER_LEN;
+    loop {
+        let len = *query.get(at)? as usize;
+        at += 1;
+        match len {
+            0 => break,
+            //compression has no business in a question
+            len if len & 0xc0 != 0 => return None,
+            len => at += len,
+        }
+    }
+    let question_end = at + 4;
+    let question = query.get(HEADER_LEN..question_end)?;
+    let qtype = u16::from_be_bytes([query[at], query[at + 1]]);
+    let qclass = u16::from_be_bytes([query[at + 2], query[at + 3]]);
+    let answers: u16 = if qtype == TYPE_A && qclass == CLASS_IN { 1 } else { 0 };
+
+    let answer_len = 2 + 2 + 2 + 4 + 2 + 4;
+    let len = HEADER_LEN + question.len() + answers as usize * answer_len;
+    if len > out.len() {
+        return None;
+    }
+
+    //same id, a response, recursion desired copied over and available
+    out[..2].copy_from_slice(&header[..2]);
+    out[2..4].copy_from_slice(&(0x8080 | (flags & 0x0100)).to_be_bytes());
+    out[4..6].copy_from_slice(&1u16.to_be_bytes());
+    out[6..8].copy_from_slice(&answers.to_be_bytes());
+    out[8..12].fill(0);
+    out[HEADER_LEN..HEADER_LEN + question.len()].copy_from_slice(question);
+
+    if answers == 1 {
+        let at = HEADER_LEN + question.len();
+        //the name, as a pointer back to the question's
+        out[at..at + 2].copy_from_slice(&[0xc0, HEADER_LEN as u8]);
+        out[at + 2..at + 4].copy_from_slice(&TYPE_A.to_be_bytes());
+        out[at + 4..at + 6].copy_from_slice(&CLASS_IN.to_be_bytes());
+        out[at + 6..at + 10].copy_from_slice(&TTL_SECS.to_be_bytes());
+        out[at + 10..at + 12].copy_from_slice(&4u16.to_be_bytes());
+        out[at + 12..at + 16].copy_from_slice(&address);
+    }
+    Some(len)
+}
@@ -0,0 +1,314 @@
+use core::fmt::{self, Write};
+use core::net::Ipv4Addr;
+
+use heapless::String;
+
+use super::settings::{NetworkSettings, Settings, StaticIpv4, MAX_DNS_SERVERS, MAX_NETWORKS, PASSWORD_LEN, SSID_LEN};
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   Provisioning web page:
+*
+*           Just enough HTTP/1.1 for one form. Every GET gets the form (which is
+*           what makes phones and laptops pop it up as a captive portal - their
+*           "am I online?" probes get a page back instead of what they expected),
+*           and a POST to /save carries it back, urlencoded.
+*
+*           The form adds one Wi-Fi network at a time (replacing a saved one with
+*           the same name) and can forget saved ones. Passwords are never sent
+*           back out: a blank MQTT password keeps the saved one.
+*
+*           A network uses DHCP unless it's given a static address (as
+*           a.b.c.d/prefix), with optionally a gateway and DNS servers.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+pub const SAVE_PATH: &str = "/save";
+pub const DEFAULT_MQTT_PORT: u16 = 1883;
+/// room the page can take up (with every field as long as it can be)
+pub const PAGE_LEN: usize = 5120;
+
+/// What came in over the connection so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request<'a> {
+    /// not all of it yet - read some more
+    Incomplete,
+    /// not HTTP we can deal with
+    Invalid,
+    /// any GET: show the form
+    Page,
+    /// the form coming back
+    Save(&'a [u8]),
+}
+
+/// Parse a (possibly partial) HTTP request
+pub fn request(buf: &[u8]) -> Request<'_> {
+    let Some(head_len) = buf.windows(4).position(|w| w == b"\r\n\r\n").map(|end| end + 4) else {
+        return Request::Incomplete;
+    };
+    let Ok(head) = core::str::from_utf8(&buf[..head_len]) else {
+        return Request::Invalid;
+    };
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (method, path) = (request_line.next().unwrap_or_default(), request_line.next().unwrap_or_default());
+
+    match method {
+        "GET" => Request::Page,
+        "POST" if path == SAVE_PATH => {
+            let content_length = lines
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+                .and_then(|(_, value)| value.trim().parse::<usize>().ok());
+            match content_length {
+                Some(len) if buf.len() >= head_len + len => Request::Save(&buf[head_len..head_len + len]),
+                Some(_) => Request::Incomplete,
+                None => Request::Invalid,
+            }
+        },
+        _ => Request::Invalid,
+    }
+}
+
+/// Why the form couldn't be saved - shown above it when it's sent back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormError {
+    /// not valid application/x-www-form-urlencoded (or not UTF-8)
+    Encoding,
+    /// no Wi-Fi networks would be left
+    NoSsid,
+    TooManyNetworks,
+    /// a field is longer than there's room for
+    TooLong(&'static str),
+    /// WPA2 passwords are 8 to 63 characters
+    Password,
+    Port,
+    Priority,
+    /// the static address isn't a.b.c.d/prefix
+    Address,
+    /// the gateway isn't an address on the static address' network
+    Gateway,
+    Dns,
+}
+
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Encoding => write!(f, "the form didn't arrive intact - please try again"),
+            Self::NoSsid => write!(f, "at least one Wi-Fi network is needed"),
+            Self::TooManyNetworks => write!(f, "only {} Wi-Fi networks can be saved - forget one first", MAX_NETWORKS),
+            Self::TooLong(field) => write!(f, "{} is too long", field),
+            Self::Password => write!(f, "Wi-Fi passwords are 8 to {} characters (or none for an open network)", PASSWORD_LEN),
+            Self::Port => write!(f, "the MQTT port should be a number from 1 to 65535"),
+            Self::Priority => write!(f, "the priority should be a number from 0 to 255"),
+            Self::Address => write!(f, "the static address should look like 192.168.1.50/24 (or be blank for DHCP)"),
+            Self::Gateway => write!(f, "the gateway should be an address on the same network as the static one"),
+            Self::Dns => write!(f, "give up to {} DNS server addresses, separated by spaces or commas", MAX_DNS_SERVERS),
+        }
+    }
+}
+
+/// Apply a submitted form to the `current` settings, checking the result on the way
+pub fn parse(body: &[u8], current: &Settings) -> Result<Settings, FormError> {
+    let body = core::str::from_utf8(body).map_err(|_| FormError::Encoding)?;
+    let mut settings = Settings { networks: current.networks.clone(), ..Settings::default() };
+    let mut network = NetworkSettings::default();
+    let mut port: String<8> = String::new();
+    let mut priority: String<8> = String::new();
+    let mut address: String<24> = String::new();
+    let mut gateway: String<24> = String::new();
+    let mut dns: String<64> = String::new();
+
+    for pair in body.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match name {
+            "ssid" => network.ssid = decode(value, "The network name")?,
+            "password" => network.password = decode(value, "The Wi-Fi password")?,
+            "priority" => priority = decode(value, "The priority")?,
+            "ip_address" => address = decode(value, "The static address")?,
+            "ip_gateway" => gateway = decode(value, "The gateway")?,
+            "ip_dns" => dns = decode(value, "The DNS servers")?,
+            "forget" => {
+                let ssid: String<SSID_LEN> = decode(value, "The network name")?;
+                settings.networks.retain(|saved| saved.ssid != ssid);
+            },
+            "mqtt_host" => settings.mqtt_host = decode(value, "The MQTT broker")?,
+            "mqtt_port" => port = decode(value, "The MQTT port")?,
+            "mqtt_user" => settings.mqtt_user = decode(value, "The MQTT username")?,
+            "mqtt_password" => settings.mqtt_password = decode(value, "The MQTT password")?,
+            //anything else (a submit button...) doesn't matter
+            _ => {},
+        }
+    }
+
+    if !network.ssid.is_empty() {
+        if !network.password.is_empty() && network.password.len() < 8 {
+            return Err(FormError::Password);
+        }
+        network.priority = match priority.trim() {
+            "" => 0,
+            priority => priority.parse().map_err(|_| FormError::Priority)?,
+        };
+        network.ipv4 = static_ipv4(&address, &gateway, &dns)?;
+        settings.networks.retain(|saved| saved.ssid != network.ssid);
+        settings.networks.push(network).map_err(|_| FormError::TooManyNetworks)?;
+    }
+    if settings.networks.is_empty() {
+        return Err(FormError::NoSsid);
+    }
+
+    settings.mqtt_host = String::try_from(settings.mqtt_host.trim()).map_err(|_| FormError::Encoding)?;
+    settings.mqtt_port = match port.trim() {
+        "" => DEFAULT_MQTT_PORT,
+        port => port.parse().ok().filter(|port| *port != 0).ok_or(FormError::Port)?,
+    };
+    //it's never filled in on the page, so blank means "as it was" (unless there's no user for it now)
+    if settings.mqtt_password.is_empty() && !settings.mqtt_user.is_empty() {
+        settings.mqtt_password = current.mqtt_password.clone();
+    }
+    Ok(settings)
+}
+
+/// The static addressing given for a network, None (DHCP) if there's no address
+fn static_ipv4(address: &str, gateway: &str, dns: &str) -> Result<Option<StaticIpv4>, FormError> {
+    let address = address.trim();
+    if address.is_empty() {
+        return Ok(None);
+    }
+    let (address, prefix_len) = address.split_once('/').ok_or(FormError::Address)?;
+    let address: Ipv4Addr = address.trim().parse().map_err(|_| FormError::Address)?;
+    let prefix_len: u8 = prefix_len.trim().parse().ok().filter(|len| (1..=32).contains(len)).ok_or(FormError::Address)?;
+
+    let mask = u32::MAX << (32 - prefix_len as u32);
+    let gateway = match gateway.trim() {
+        "" => None,
+        gateway => {
+            let gateway: Ipv4Addr = gateway.parse().map_err(|_| FormError::Gateway)?;
+            if u32::from(gateway) & mask != u32::from(address) & mask || gateway == address {
+                return Err(FormError::Gateway);
+            }
+            Some(gateway.octets())
+        },
+    };
+    let mut dns_servers = heapless::Vec::new();
+    for server in dns.split([' ', ',']).filter(|server| !server.is_empty()) {
+        let server: Ipv4Addr = server.parse().map_err(|_| FormError::Dns)?;
+        dns_servers.push(server.octets()).map_err(|_| FormError::Dns)?;
+    }
+    Ok(Some(StaticIpv4 { address: address.octets(), prefix_len, gateway, dns_servers }))
+}
+
+/// Undo application/x-www-form-urlencoded ('+' for space, %XX for anything else)
+pub fn decode<const N: usize>(value: &str, field: &'static str) -> Result<String<N>, FormError> {
+    let mut bytes = heapless::Vec::<u8, N>::new();
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let (byte, tail) = match byte {
+            b'+' => (b' ', tail),
+            b'%' => {
+                let hex = tail.get(..2).and_then(|hex| core::str::from_utf8(hex).ok()).ok_or(FormError::Encoding)?;
+                (u8::from_str_radix(hex, 16).map_err(|_| FormError::Encoding)?, &tail[2..])
+            },
+            byte => (byte, tail),
+        };
+        bytes.push(byte).map_err(|_| FormError::TooLong(field))?;
+        rest = tail;
+    }
+    String::from_utf8(bytes).map_err(|_| FormError::Encoding)
+}
+
+/// What to say above the form
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Notice {
+    None,
+    Error(FormError),
+    /// fine, but writing it to flash failed
+    NotStored,
+    /// saved - the device is about to restart and join the network
+    Saved,
+}
+
+/// The page: the form, filled in with `current` (apart from passwords), and `notice` above it
+pub fn page(out: &mut impl Write, device: &str, current: &Settings, notice: Notice) -> fmt::Result {
+    out.write_str("<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+        <meta name=\"viewport\" content=\"width=device-width,initial-scale=1\"><title>")?;
+    Html(device).fmt(out)?;
+    out.write_str(" setup</title><style>body{font-family:sans-serif;max-width:28em;margin:1em auto;padding:0 1em}\
+        label{display:block;margin-top:.8em}input{width:100%;box-sizing:border-box;padding:.4em}\
+        input[type=checkbox]{width:auto}.err{color:#b00}.ok{color:#070}</style></head><body><h1>")?;
+    Html(device).fmt(out)?;
+    out.write_str("</h1>")?;
+
+    match notice {
+        Notice::None => {},
+        Notice::Error(e) => write!(out, "<p class=\"err\">Not saved: {}.</p>", e)?,
+        Notice::NotStored => out.write_str("<p class=\"err\">Not saved: the settings couldn't be written to flash.</p>")?,
+        Notice::Saved => {
+            return out.write_str("<p class=\"ok\">Saved. The device is restarting to join the network - \
+                you can reconnect to your usual Wi-Fi now.</p></body></html>");
+        },
+    }
+
+    write!(out, "<form method=\"post\" action=\"{}\"><h2>Wi-Fi</h2>", SAVE_PATH)?;
+    if !current.networks.is_empty() {
+        out.write_str("<p>Saved networks - the highest priority one in range is used (the strongest, if they tie):</p><ul>")?;
+        for network in &current.networks {
+            out.write_str("<li>")?;
+            Html(&network.ssid).fmt(out)?;
+            write!(out, " (priority {}, ", network.priority)?;
+            match &network.ipv4 {
+                None => out.write_str("DHCP")?,
+                Some(ipv4) => write!(out, "static {}/{}", Ipv4Addr::from(ipv4.address), ipv4.prefix_len)?,
+            }
+            out.write_str(") <label style=\"display:inline\"><input type=\"checkbox\" name=\"forget\" value=\"")?;
+            Html(&network.ssid).fmt(out)?;
+            out.write_str("\"> forget</label></li>")?;
+        }
+        out.write_str("</ul><h3>Add a network</h3><p>Adding one that's already saved replaces it.</p>")?;
+    }
+    field(out, "Network name", "ssid", "text", "")?;
+    field(out, "Password (blank for an open network)", "password", "password", "")?;
+    field(out, "Priority (higher is preferred, blank for 0)", "priority", "number", "")?;
+    out.write_str("<p>Leave the address blank to use DHCP.</p>")?;
+    field(out, "Static address (e.g. 192.168.1.50/24)", "ip_address", "text", "")?;
+    field(out, "Gateway (optional)", "ip_gateway", "text", "")?;
+    field(out, "DNS servers (optional, up to 3)", "ip_dns", "text", "")?;
+    out.write_str("<h2>MQTT</h2><p>Leave the broker blank to keep the one the firmware was built with.</p>")?;
+    field(out, "Broker (hostname or IP address)", "mqtt_host", "text", &current.mqtt_host)?;
+    let mut port: String<5> = String::new();
+    if current.mqtt_port != 0 {
+        write!(port, "{}", current.mqtt_port)?;
+    }
+    field(out, "Port (blank for 1883)", "mqtt_port", "number", &port)?;
+    field(out, "Username", "mqtt_user", "text", &current.mqtt_user)?;
+    field(out, "Password (blank to keep the saved one)", "mqtt_password", "password", "")?;
+    out.write_str("<p><input type=\"submit\" value=\"Save and restart\"></p></form></body></html>")
+}
+
+fn field(out: &mut impl Write, label: &str, name: &str, kind: &str, value: &str) -> fmt::Result {
+    write!(out, "<label>{}<input name=\"{}\" type=\"{}\" value=\"", label, name, kind)?;
+    Html(value).fmt(out)?;
+    out.write_str("\"></label>")
+}
+
+/// Text escaped for putting in HTML (element content or a quoted attribute)
+struct Html<'a>(&'a str);
+
+impl Html<'_> {
+    fn fmt(&self, out: &mut impl Write) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '&' => out.write_str("&amp;")?,
+                '<' => out.write_str("&lt;")?,
+                '>' => out.write_str("&gt;")?,
+                '"' => out.write_str("&quot;")?,
+                '\'' => out.write_str("&#39;")?,
+                c => out.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
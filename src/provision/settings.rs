@@ -0,0 +1,237 @@
+use heapless::{String, Vec};
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   Stored settings:
+*
+*           What the provisioning portal saves to flash: the Wi-Fi networks to join
+*           and, optionally, the MQTT broker to use instead of the one built in.
+*
+*           On flash it's one record:
+*
+*               "PRV1" | payload length (u16 LE) | crc32 of payload (u32 LE) | payload
+*
+*           where the payload is the number of networks, then for each its ssid,
+*           password (strings as a length byte and their bytes), priority byte and
+*           addressing: 0 for DHCP, or 1 then a static address, prefix length,
+*           gateway (0.0.0.0 for none), number of DNS servers and each server.
+*           Then the mqtt host, user and password, then the mqtt port (u16 LE).
+*           Erased flash (all 0xff) reads back as `Empty`.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+pub const SSID_LEN: usize = 32;
+/// WPA2 passphrases are 8..=63 characters
+pub const PASSWORD_LEN: usize = 63;
+pub const HOST_LEN: usize = 64;
+pub const USER_LEN: usize = 32;
+pub const MQTT_PASSWORD_LEN: usize = 64;
+/// Wi-Fi networks that can be saved
+pub const MAX_NETWORKS: usize = 4;
+/// as many as embassy-net takes in a static configuration
+pub const MAX_DNS_SERVERS: usize = 3;
+
+const MAGIC: &[u8; 4] = b"PRV1";
+const HEADER_LEN: usize = MAGIC.len() + 2 + 4;
+const IPV4_LEN: usize = 1 + 4 + 1 + 4 + 1 + MAX_DNS_SERVERS * 4;
+const NETWORK_LEN: usize = 1 + SSID_LEN + 1 + PASSWORD_LEN + 1 + IPV4_LEN;
+/// the most a record can take up on flash
+pub const RECORD_LEN: usize = HEADER_LEN + 1 + MAX_NETWORKS * NETWORK_LEN + 3 + HOST_LEN + USER_LEN + MQTT_PASSWORD_LEN + 2;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Settings {
+    pub networks: Vec<NetworkSettings, MAX_NETWORKS>,
+    /// empty to keep the broker(s) the firmware was built with
+    pub mqtt_host: String<HOST_LEN>,
+    pub mqtt_port: u16,
+    pub mqtt_user: String<USER_LEN>,
+    pub mqtt_password: String<MQTT_PASSWORD_LEN>,
+}
+
+/// One saved Wi-Fi network
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkSettings {
+    pub ssid: String<SSID_LEN>,
+    /// empty for an open network
+    pub password: String<PASSWORD_LEN>,
+    /// higher is preferred
+    pub priority: u8,
+    /// None to use DHCP
+    pub ipv4: Option<StaticIpv4>,
+}
+
+/// Fixed addressing, for networks without DHCP
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StaticIpv4 {
+    pub address: [u8; 4],
+    pub prefix_len: u8,
+    pub gateway: Option<[u8; 4]>,
+    pub dns_servers: Vec<[u8; 4], MAX_DNS_SERVERS>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettingsError {
+    /// nothing has been saved (erased flash)
+    Empty,
+    /// something is there, but not a record we wrote (or it got damaged)
+    Corrupt,
+    /// the output buffer is too small
+    TooLong,
+}
+
+impl Settings {
+    /// Write the flash record for these settings into `buf`, returning its length
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, SettingsError> {
+        let mut out = Out { buf, len: HEADER_LEN };
+        out.bytes(&[self.networks.len() as u8])?;
+        for network in &self.networks {
+            out.string(&network.ssid)?;
+            out.string(&network.password)?;
+            out.bytes(&[network.priority])?;
+            match &network.ipv4 {
+                None => out.bytes(&[0])?,
+                Some(ipv4) => {
+                    out.bytes(&[1])?;
+                    out.bytes(&ipv4.address)?;
+                    out.bytes(&[ipv4.prefix_len])?;
+                    out.bytes(&ipv4.gateway.unwrap_or_default())?;
+                    out.bytes(&[ipv4.dns_servers.len() as u8])?;
+                    for server in &ipv4.dns_servers {
+                        out.bytes(server)?;
+                    }
+                },
+            }
+        }
+        out.string(&self.mqtt_host)?;
+        out.string(&self.mqtt_user)?;
+        out.string(&self.mqtt_password)?;
+        out.bytes(&self.mqtt_port.to_le_bytes())?;
+
+        let Out { buf, len } = out;
+        let payload_len = (len - HEADER_LEN) as u16;
+        let crc = crc32(&buf[HEADER_LEN..len]);
+        buf[..4].copy_from_slice(MAGIC);
+        buf[4..6].copy_from_slice(&payload_len.to_le_bytes());
+        buf[6..10].copy_from_slice(&crc.to_le_bytes());
+        Ok(len)
+    }
+
+    /// Read back a record written by `encode`
+    pub fn decode(buf: &[u8]) -> Result<Self, SettingsError> {
+        if buf.len() < HEADER_LEN {
+            return Err(SettingsError::Corrupt);
+        }
+        if buf[..HEADER_LEN].iter().all(|b| *b == 0xff) {
+            return Err(SettingsError::Empty);
+        }
+        if &buf[..4] != MAGIC {
+            return Err(SettingsError::Corrupt);
+        }
+        let payload_len = u16::from_le_bytes([buf[4], buf[5]]) as usize;
+        let crc = u32::from_le_bytes([buf[6], buf[7], buf[8], buf[9]]);
+        let payload = buf.get(HEADER_LEN..HEADER_LEN + payload_len).ok_or(SettingsError::Corrupt)?;
+        if crc32(payload) != crc {
+            return Err(SettingsError::Corrupt);
+        }
+
+        let mut fields = Fields { rest: payload };
+        let mut networks = Vec::new();
+        for _ in 0..fields.byte()? {
+            let network = NetworkSettings {
+                ssid: fields.string()?,
+                password: fields.string()?,
+                priority: fields.byte()?,
+                ipv4: fields.ipv4()?,
+            };
+            networks.push(network).map_err(|_| SettingsError::Corrupt)?;
+        }
+        let settings = Self {
+            networks,
+            mqtt_host: fields.string()?,
+            mqtt_user: fields.string()?,
+            mqtt_password: fields.string()?,
+            mqtt_port: match fields.rest {
+                [lo, hi] => u16::from_le_bytes([*lo, *hi]),
+                _ => return Err(SettingsError::Corrupt),
+            },
+        };
+        Ok(settings)
+    }
+}
+
+/// Appends to a record being written
+struct Out<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Out<'_> {
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), SettingsError> {
+        let end = self.len + bytes.len();
+        self.buf.get_mut(self.len..end).ok_or(SettingsError::TooLong)?.copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    fn string(&mut self, text: &str) -> Result<(), SettingsError> {
+        self.bytes(&[text.len() as u8])?;
+        self.bytes(text.as_bytes())
+    }
+}
+
+/// Walks the fields of a record's payload
+struct Fields<'a> {
+    rest: &'a [u8],
+}
+
+impl Fields<'_> {
+    fn byte(&mut self) -> Result<u8, SettingsError> {
+        let (&byte, rest) = self.rest.split_first().ok_or(SettingsError::Corrupt)?;
+        self.rest = rest;
+        Ok(byte)
+    }
+
+    fn address(&mut self) -> Result<[u8; 4], SettingsError> {
+        Ok([self.byte()?, self.byte()?, self.byte()?, self.byte()?])
+    }
+
+    fn ipv4(&mut self) -> Result<Option<StaticIpv4>, SettingsError> {
+        match self.byte()? {
+            0 => Ok(None),
+            1 => {
+                let address = self.address()?;
+                let prefix_len = self.byte()?;
+                let gateway = Some(self.address()?).filter(|gateway| *gateway != [0; 4]);
+                let mut dns_servers = Vec::new();
+                for _ in 0..self.byte()? {
+                    dns_servers.push(self.address()?).map_err(|_| SettingsError::Corrupt)?;
+                }
+                Ok(Some(StaticIpv4 { address, prefix_len, gateway, dns_servers }))
+            },
+            _ => Err(SettingsError::Corrupt),
+        }
+    }
+
+    fn string<const N: usize>(&mut self) -> Result<String<N>, SettingsError> {
+        let len = self.byte()? as usize;
+        let bytes = self.rest.get(..len).ok_or(SettingsError::Corrupt)?;
+        self.rest = &self.rest[len..];
+        let text = core::str::from_utf8(bytes).map_err(|_| SettingsError::Corrupt)?;
+        String::try_from(text).map_err(|_| SettingsError::Corrupt)
+    }
+}
+
+/// CRC-32 (the zip/ethernet one), bit at a time - records are small and rarely read
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
@@ -0,0 +1,97 @@
+use core::str::from_utf8;
+
+use esp_println::println;
+use serde::Serialize;
+
+use crate::{mqtt::{outbox::OUT_PAYLOAD_LEN, queue_publish, router::HandlerError, QualityOfService}, payload::JSON_CONTENT_TYPE};
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   Error reports on "test/errors":
+*
+*           When an incoming message is thrown away, say so on the broker rather
+*           than only on the serial console, so whoever sent it can find out why
+*           nothing happened:
+*
+*           {"topic":"test/light","kind":"malformed","payload":"{\"r\": 5","truncated":false}
+*
+*           Only the start of the offending payload is echoed back.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+pub const ERRORS_TOPIC: &str = "test/errors";
+/// how much of a rejected payload gets echoed back
+const PAYLOAD_EXCERPT_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// the payload didn't decode into what the topic expects
+    Malformed,
+    /// the payload isn't valid UTF-8
+    NotUtf8,
+    /// the feature was still busy with earlier messages
+    Busy,
+    /// decoded fine but refused by the handler
+    Rejected,
+    /// bigger than the receive buffer - the topic isn't known as the packet was skipped unread
+    TooLarge,
+    /// not signed, badly signed or replayed (signed builds only), `detail` says which
+    Unauthorized,
+}
+
+impl From<HandlerError> for ErrorKind {
+    fn from(e: HandlerError) -> Self {
+        match e {
+            HandlerError::Malformed => Self::Malformed,
+            HandlerError::Busy => Self::Busy,
+            HandlerError::Rejected(_) => Self::Rejected,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+    topic: Option<&'a str>,
+    kind: ErrorKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'a str>,
+    payload: &'a str,
+    truncated: bool,
+}
+
+/// Report a handler's failure to deal with a message on `topic`
+pub fn handler_failed(topic: &str, e: HandlerError, payload: &[u8]) {
+    let detail = match e {
+        HandlerError::Rejected(reason) => Some(reason),
+        _ => None,
+    };
+    report(Some(topic), e.into(), detail, payload);
+}
+
+/// Publish an error report (QoS0, not retained - it's only of interest to whoever is listening now)
+pub fn report(topic: Option<&str>, kind: ErrorKind, detail: Option<&str>, payload: &[u8]) {
+    let (excerpt, truncated) = excerpt(payload);
+    let report = Report { topic, kind, detail, payload: excerpt, truncated };
+
+    let mut buf = [0u8; OUT_PAYLOAD_LEN];
+    match serde_json_core::to_slice(&report, &mut buf) {
+        Ok(len) => queue_publish(ERRORS_TOPIC, &buf[..len], JSON_CONTENT_TYPE, QualityOfService::QoS0, false),
+        Err(e) => println!("error report serialisation error: {}", e),
+    }
+}
+
+/// The readable start of `payload`: at most PAYLOAD_EXCERPT_LEN bytes, cut at the first
+/// invalid UTF-8 sequence (or mid-character boundary), and whether anything was left off
+fn excerpt(payload: &[u8]) -> (&str, bool) {
+    let head = &payload[..payload.len().min(PAYLOAD_EXCERPT_LEN)];
+    let text = match from_utf8(head) {
+        Ok(text) => text,
+        //valid_up_to is always a char boundary, so this can't fail
+        Err(e) => from_utf8(&head[..e.valid_up_to()]).unwrap_or_default(),
+    };
+    (text, text.len() < payload.len())
+}
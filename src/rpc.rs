@@ -0,0 +1,227 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_time::{Duration, Instant, Timer};
+use esp_println::println;
+use heapless::{String, Vec};
+use serde::{Deserialize, Serialize};
+
+use crate::{eink::Msg, led::RGB, mqtt::{outbox::{OUT_CORRELATION_LEN, OUT_PAYLOAD_LEN, OUT_TOPIC_LEN}, outbox_room, queue_response, router::{HandlerError, Message}, Property}, read_display_msg, read_led_state, read_mqtt_addr, read_net_status, LED_CHANNEL, MSG_CHANNEL, SCREENSHOT_SIGNAL};
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   Request/response commands on "test/rpc":
+*
+*           A client publishes {"cmd": "get_state"} with the MQTT v5 Response Topic
+*           (and usually Correlation Data) properties set. We run the command and
+*           publish {"ok": true, "result": ...} or {"ok": false, "error": "..."} to
+*           the response topic, echoing the correlation data so the caller can
+*           match reply to request. Without a response topic the command still
+*           runs, there's just nobody to answer.
+*
+*           "screenshot" is too big for one reply: it answers with the frame's size
+*           and how many chunks follow, then the eink task publishes the framebuffer
+*           CHUNK_LEN bytes (hex) at a time to the same response topic, as fast as
+*           the outbox drains.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+pub const RPC_TOPIC: &str = "test/rpc";
+
+/// largest "test/rpc" payload we accept (the response topic and correlation data are properties)
+pub const MAX_PAYLOAD: usize = 64;
+
+/// the panel's framebuffer as the controller takes it: rows FRAME_WIDTH pixels across (it's mounted
+/// on its side), one bit a pixel, a black/white plane (1 = white) and then a red one (1 = red)
+const FRAME_WIDTH: usize = 128;
+const FRAME_HEIGHT: usize = 296;
+const PLANE_LEN: usize = FRAME_WIDTH * FRAME_HEIGHT / 8;
+/// framebuffer bytes to a screenshot reply - twice that in hex, plus the json, has to fit OUT_PAYLOAD_LEN
+const CHUNK_LEN: usize = 128;
+const CHUNKS: usize = 2 * PLANE_LEN.div_ceil(CHUNK_LEN);
+/// outbox slots left free while a screenshot goes out, so it doesn't push out anything queued meanwhile
+const SCREENSHOT_ROOM: usize = 2;
+/// a screenshot is abandoned if the outbox hasn't made room for its next chunk in this long
+const SCREENSHOT_STALL: Duration = Duration::from_secs(10);
+
+/// set by "reboot" - mqtt_task resets the chip once the reply has gone out
+pub static REBOOT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    cmd: String<16>,
+}
+
+#[derive(Debug, Serialize)]
+struct Reply<'a, T: Serialize> {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+/// Where a screenshot's chunks go - the request's response topic and correlation data
+#[derive(Debug, Clone)]
+pub struct Screenshot {
+    topic: String<OUT_TOPIC_LEN>,
+    correlation_data: Vec<u8, OUT_CORRELATION_LEN>,
+}
+
+impl Screenshot {
+    /// `None` if either is too long to publish anyway
+    fn new(topic: &str, correlation_data: &[u8]) -> Option<Self> {
+        Some(Self { topic: String::try_from(topic).ok()?, correlation_data: Vec::from_slice(correlation_data).ok()? })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Frame {
+    width: usize,
+    height: usize,
+    chunks: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct Chunk<'a> {
+    index: usize,
+    plane: &'a str,
+    /// where `data` starts in its plane
+    offset: usize,
+    data: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+struct State<'a> {
+    ip: Option<&'a str>,
+    ip6: Option<&'a str>,
+    mqtt: Option<&'a str>,
+    led: RGB,
+    message: &'a str,
+}
+
+/// "test/rpc" handler: run the command, replying on the message's response topic if it has one
+pub fn handle(msg: &Message<'_>) -> Result<(), HandlerError> {
+    let mut response_topic = None;
+    let mut correlation_data: &[u8] = &[];
+    for prop in msg.properties.iter() {
+        match prop {
+            Property::ResponseTopic(topic) => response_topic = Some(topic),
+            Property::CorrelationData(data) => correlation_data = data,
+            _ => {},
+        }
+    }
+
+    let mut buf = [0u8; OUT_PAYLOAD_LEN];
+    let (reply, result) = match serde_json_core::from_slice::<Request>(msg.payload) {
+        Ok((request, _)) => {
+            println!("rpc command: {}", request.cmd);
+            let reply_to = response_topic.map(|topic| (topic, correlation_data));
+            (run(&request.cmd, reply_to, &mut buf), Ok(()))
+        },
+        Err(e) => {
+            println!("malformed rpc json: {}", e);
+            (error(&mut buf, "malformed_request"), Err(HandlerError::Malformed))
+        },
+    };
+
+    match (response_topic, reply) {
+        (Some(topic), Some(len)) => queue_response(topic, &buf[..len], correlation_data),
+        (Some(topic), None) => println!("rpc reply for {} didn't fit {} bytes", topic, OUT_PAYLOAD_LEN),
+        (None, _) => {},
+    }
+    result
+}
+
+/// Runs `cmd`, leaving the serialised reply in `buf` and returning its length.
+/// `reply_to` is the response topic and correlation data, for commands that answer more than once.
+fn run(cmd: &str, reply_to: Option<(&str, &[u8])>, buf: &mut [u8]) -> Option<usize> {
+    match cmd {
+        "get_state" => {
+            let net = read_net_status();
+            let ip = net.ipv4.map(|ipv4| ipv4.text());
+            let ip6 = net.ipv6.map(|ipv6| ipv6.text());
+            let mqtt = read_mqtt_addr().map(|(addr, port)| {
+                let mut st = String::<47>::new();
+                _ = core::fmt::write(&mut st, format_args!("{}:{}", addr, port));
+                st
+            });
+            let msg = read_display_msg();
+            let state = State {
+                ip: ip.as_deref(),
+                ip6: ip6.as_deref(),
+                mqtt: mqtt.as_deref(),
+                led: read_led_state(),
+                message: msg.as_ref().map(|msg| msg.text()).unwrap_or(""),
+            };
+            //a long message can push the reply over the outbox payload size
+            ok(buf, Some(state)).or_else(|| error(buf, "reply_too_large"))
+        },
+        "clear" => {
+            match (MSG_CHANNEL.try_send(Msg::empty()), LED_CHANNEL.try_send(RGB { r: 0, g: 0, b: 0 })) {
+                (Ok(()), Ok(())) => ok::<()>(buf, None),
+                _ => error(buf, "busy"),
+            }
+        },
+        "reboot" => {
+            REBOOT_REQUESTED.store(true, Ordering::Relaxed);
+            ok::<()>(buf, None)
+        },
+        //the frame itself follows from the eink task, after this reply
+        "screenshot" => match reply_to.and_then(|(topic, data)| Screenshot::new(topic, data)) {
+            None => error(buf, "no_response_topic"),
+            Some(_) if SCREENSHOT_SIGNAL.signaled() => error(buf, "busy"),
+            Some(screenshot) => {
+                SCREENSHOT_SIGNAL.signal(screenshot);
+                ok(buf, Some(Frame { width: FRAME_WIDTH, height: FRAME_HEIGHT, chunks: CHUNKS }))
+            },
+        },
+        _ => error(buf, "unknown_command"),
+    }
+}
+
+fn ok<T: Serialize>(buf: &mut [u8], result: Option<T>) -> Option<usize> {
+    serde_json_core::to_slice(&Reply { ok: true, result, error: None }, buf).ok()
+}
+
+fn error(buf: &mut [u8], error: &str) -> Option<usize> {
+    serde_json_core::to_slice(&Reply::<()> { ok: false, result: None, error: Some(error) }, buf).ok()
+}
+
+/// Publish the framebuffer `planes` (black/white, then red) to whoever asked for `screenshot`,
+/// a chunk to a reply, waiting for the outbox to make room for each
+pub async fn send_screenshot(screenshot: &Screenshot, planes: [(&str, &[u8]); 2]) {
+    let mut buf = [0u8; OUT_PAYLOAD_LEN];
+    let mut hex = [0u8; 2 * CHUNK_LEN];
+    let mut index = 0;
+    for (plane, bytes) in planes {
+        for (n, chunk) in bytes.chunks(CHUNK_LEN).enumerate() {
+            if !wait_for_room().await {
+                println!("screenshot abandoned after {} of {} chunks, the outbox isn't draining", index, CHUNKS);
+                return;
+            }
+            let data = &mut hex[..2 * chunk.len()];
+            _ = hex::encode_to_slice(chunk, data);
+            let chunk = Chunk { index, plane, offset: n * CHUNK_LEN, data: core::str::from_utf8(data).unwrap_or("") };
+            match ok(&mut buf, Some(chunk)) {
+                Some(len) => queue_response(&screenshot.topic, &buf[..len], &screenshot.correlation_data),
+                None => println!("screenshot chunk {} didn't fit {} bytes", index, OUT_PAYLOAD_LEN),
+            }
+            index += 1;
+        }
+    }
+}
+
+/// Wait for SCREENSHOT_ROOM free outbox slots - false if it takes longer than SCREENSHOT_STALL
+async fn wait_for_room() -> bool {
+    let deadline = Instant::now() + SCREENSHOT_STALL;
+    while outbox_room() < SCREENSHOT_ROOM {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        Timer::after(Duration::from_millis(50)).await;
+    }
+    true
+}
@@ -0,0 +1,104 @@
+//signed commands (cargo feature "signed"): every incoming message must carry a valid HMAC-SHA256
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use esp_hal::ram;
+use esp_println::println;
+use heapless::String;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::mqtt::{router::Message, Property};
+
+pub mod replay;
+use replay::{Seen, NONCE_LEN};
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   Signed commands:
+*
+*           A command carries three MQTT v5 user properties:
+*
+*               ts      sender's unix time in seconds
+*               nonce   anything unique per message (up to 32 chars)
+*               sig     hex HMAC-SHA256, keyed with CMD_KEY, over
+*                       "<topic>\n<ts>\n<nonce>\n" followed by the payload
+*
+*           Freshness is judged against the newest command accepted (see replay.rs).
+*           Its timestamp lives in RTC memory, which a restart leaves alone, so a
+*           captured command can't be replayed after every reboot. A power cut does
+*           clear it - then the first command sets the reference point again.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+/// 32 byte key as 64 hex characters, shared with whoever sends commands
+const CMD_KEY: &str = env!("CMD_KEY");
+
+/// The newest accepted timestamp and its complement - a pair that doesn't match (like whatever
+/// RTC memory holds after power-on) means there's nothing to go on
+#[ram(rtc_fast, persistent)]
+static mut NEWEST_ACCEPTED: [u64; 2] = [0; 2];
+
+//None until the first signed command after boot
+static SEEN: Mutex<RefCell<Option<Seen>>> = Mutex::new(RefCell::new(None));
+
+/// Check `msg` is signed with CMD_KEY and isn't stale or a replay
+pub fn verify(msg: &Message<'_>) -> Result<(), &'static str> {
+    let (mut ts, mut nonce, mut sig) = (None, None, None);
+    for prop in msg.properties.iter() {
+        match prop {
+            Property::UserProperty("ts", value) => ts = Some(value),
+            Property::UserProperty("nonce", value) => nonce = Some(value),
+            Property::UserProperty("sig", value) => sig = Some(value),
+            _ => {},
+        }
+    }
+    let (Some(ts_text), Some(nonce), Some(sig)) = (ts, nonce, sig) else {
+        return Err("unsigned");
+    };
+    let ts = ts_text.parse::<u64>().map_err(|_| "bad timestamp")?;
+    let nonce: String<NONCE_LEN> = String::try_from(nonce).map_err(|_| "nonce too long")?;
+
+    let mut sig_bytes = [0u8; 32];
+    hex::decode_to_slice(sig, &mut sig_bytes).map_err(|_| "bad signature")?;
+    let mut mac = hmac()?;
+    for part in [msg.topic.as_bytes(), b"\n", ts_text.as_bytes(), b"\n", nonce.as_bytes(), b"\n", msg.payload] {
+        mac.update(part);
+    }
+    //constant time comparison
+    mac.verify_slice(&sig_bytes).map_err(|_| "bad signature")?;
+
+    //only a genuine command gets to move the replay window on
+    critical_section::with(|cs| {
+        let mut seen = SEEN.borrow_ref_mut(cs);
+        let seen = seen.get_or_insert_with(|| Seen::restarted(newest_accepted()));
+        seen.check(ts, &nonce)?;
+        seen.remember(ts, nonce);
+        set_newest_accepted(seen.newest());
+        Ok(())
+    })
+}
+
+fn newest_accepted() -> u64 {
+    // SAFETY: only touched from `verify`, inside a critical section
+    let [ts, check] = unsafe { core::ptr::addr_of!(NEWEST_ACCEPTED).read_volatile() };
+    if check == !ts { ts } else { 0 }
+}
+
+fn set_newest_accepted(ts: u64) {
+    // SAFETY: as above
+    unsafe { core::ptr::addr_of_mut!(NEWEST_ACCEPTED).write_volatile([ts, !ts]) };
+}
+
+fn hmac() -> Result<Hmac<Sha256>, &'static str> {
+    let mut key = [0u8; 32];
+    if hex::decode_to_slice(CMD_KEY.trim(), &mut key).is_err() {
+        println!("CMD_KEY must be 64 hex characters - refusing all commands");
+        return Err("no key");
+    }
+    Hmac::<Sha256>::new_from_slice(&key).map_err(|_| "no key")
+}
@@ -0,0 +1,66 @@
+use heapless::{Deque, String};
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   Replay protection:
+*
+*           No hardware (or crypto) in here - signing.rs hands over the timestamp and
+*           nonce of a command whose signature checked out. Timestamps are the
+*           sender's unix time in seconds.
+*
+*           The device has no clock of its own, so "fresh" is relative to the newest
+*           command it has accepted: anything more than REPLAY_WINDOW older is stale,
+*           and a nonce seen recently is a replay. The newest timestamp is kept
+*           through a restart, and nothing at or before it is accepted afterwards.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+/// how far (seconds) a command may lag behind the newest one accepted
+pub const REPLAY_WINDOW: u64 = 300;
+pub const NONCE_LEN: usize = 32;
+/// nonces remembered - once full, the oldest is forgotten and nothing as old as it is accepted any more
+pub const NONCES: usize = 16;
+
+pub struct Seen {
+    newest: u64,
+    /// anything at or before this is refused outright (its nonce may have been forgotten)
+    floor: u64,
+    nonces: Deque<(u64, String<NONCE_LEN>), NONCES>,
+}
+
+impl Seen {
+    /// Start (again) from the newest timestamp accepted before - 0 if there's none
+    pub const fn restarted(newest: u64) -> Self {
+        //the nonces went with the restart, so everything up to here has to be refused
+        Self { newest, floor: newest, nonces: Deque::new() }
+    }
+
+    pub fn check(&self, ts: u64, nonce: &str) -> Result<(), &'static str> {
+        if ts <= self.floor || ts + REPLAY_WINDOW < self.newest {
+            return Err("stale");
+        }
+        if self.nonces.iter().any(|(_, seen)| seen == nonce) {
+            return Err("replayed");
+        }
+        Ok(())
+    }
+
+    pub fn remember(&mut self, ts: u64, nonce: String<NONCE_LEN>) {
+        if self.nonces.is_full() {
+            if let Some((forgotten, _)) = self.nonces.pop_front() {
+                self.floor = self.floor.max(forgotten);
+            }
+        }
+        //can't fail, there's room now
+        _ = self.nonces.push_back((ts, nonce));
+        self.newest = self.newest.max(ts);
+    }
+
+    /// The newest timestamp accepted - what to start from after a restart
+    pub fn newest(&self) -> u64 {
+        self.newest
+    }
+}
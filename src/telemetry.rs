@@ -0,0 +1,155 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Instant, Timer};
+use esp_println::println;
+use serde::Serialize;
+
+use crate::{mqtt::{health::HealthFigures, outbox::OUT_PAYLOAD_LEN, queue_publish, QualityOfService}, payload::JSON_CONTENT_TYPE, read_mqtt_addr, read_net_status, wireless::{sta_rssi, status::NetEvent}, NET_CHANNEL, OUTBOX};
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   Telemetry:
+*
+*           Counters bumped from around the firmware, plus a task that publishes
+*           them (with uptime, heap, rssi and addresses) as JSON on "test/telemetry"
+*           every TELEMETRY_SECS seconds (default 60). Wi-Fi drops are counted
+*           from the network status events.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+pub const TELEMETRY_TOPIC: &str = "test/telemetry";
+const DEFAULT_INTERVAL_SECS: u64 = 60;
+
+/// successful broker connections after the first
+pub static MQTT_RECONNECTS: AtomicU32 = AtomicU32::new(0);
+/// publishes received on any topic
+pub static MSGS_RECEIVED: AtomicU32 = AtomicU32::new(0);
+/// received publishes that were thrown away (bad json, unknown topic...)
+pub static MSGS_DROPPED: AtomicU32 = AtomicU32::new(0);
+/// received publishes dropped for being over their topic's rate limit (also counted in MSGS_DROPPED)
+pub static MSGS_LIMITED: AtomicU32 = AtomicU32::new(0);
+/// received publishes held back by a rate limit to be handled later
+pub static MSGS_DEFERRED: AtomicU32 = AtomicU32::new(0);
+/// connections given up on because the broker stopped answering pings
+pub static MQTT_UNRESPONSIVE: AtomicU32 = AtomicU32::new(0);
+/// times the access point was lost
+static WIFI_DROPS: AtomicU32 = AtomicU32::new(0);
+//the current connection's ping figures (see mqtt/health.rs), NO_RTT until a PINGRESP has been timed
+static PING_RTT_MS: AtomicU32 = AtomicU32::new(NO_RTT);
+static PING_RTT_AVG_MS: AtomicU32 = AtomicU32::new(NO_RTT);
+static PING_RTT_MAX_MS: AtomicU32 = AtomicU32::new(NO_RTT);
+static PINGS_MISSED: AtomicU32 = AtomicU32::new(0);
+const NO_RTT: u32 = u32::MAX;
+pub static DISPLAY_REFRESHES: AtomicU32 = AtomicU32::new(0);
+pub static LAST_REFRESH_MS: AtomicU32 = AtomicU32::new(0);
+
+pub fn count(counter: &AtomicU32) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Call after a display refresh that began at `started`
+pub fn record_refresh(started: Instant) {
+    count(&DISPLAY_REFRESHES);
+    LAST_REFRESH_MS.store(started.elapsed().as_millis() as u32, Ordering::Relaxed);
+}
+
+/// Call whenever the connection's ping figures change
+pub fn record_health(figures: &HealthFigures) {
+    let store = |counter: &AtomicU32, rtt: Option<Duration>| {
+        counter.store(rtt.map_or(NO_RTT, |rtt| rtt.as_millis().min(NO_RTT as u64 - 1) as u32), Ordering::Relaxed);
+    };
+    store(&PING_RTT_MS, figures.rtt);
+    store(&PING_RTT_AVG_MS, figures.rtt_avg);
+    store(&PING_RTT_MAX_MS, figures.rtt_max);
+    PINGS_MISSED.store(figures.missed, Ordering::Relaxed);
+}
+
+fn load_rtt(counter: &AtomicU32) -> Option<u32> {
+    Some(counter.load(Ordering::Relaxed)).filter(|ms| *ms != NO_RTT)
+}
+
+#[derive(Debug, Serialize)]
+struct Telemetry<'a> {
+    uptime: u64,
+    heap_free: usize,
+    rssi: Option<i8>,
+    ip: Option<&'a str>,
+    ip6: Option<&'a str>,
+    wifi_drops: u32,
+    reconnects: u32,
+    unresponsive: u32,
+    ping_ms: Option<u32>,
+    ping_avg_ms: Option<u32>,
+    ping_max_ms: Option<u32>,
+    pings_missed: u32,
+    rx: u32,
+    rx_dropped: u32,
+    rx_limited: u32,
+    rx_deferred: u32,
+    tx_dropped: u32,
+    refreshes: u32,
+    refresh_ms: u32,
+}
+
+fn interval_secs() -> u64 {
+    //0 would never yield to the executor
+    option_env!("TELEMETRY_SECS")
+        .and_then(|secs| secs.parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(DEFAULT_INTERVAL_SECS)
+}
+
+#[embassy_executor::task]
+pub async fn telemetry_task() {
+    let interval = interval_secs();
+    let mut buf = [0u8; OUT_PAYLOAD_LEN];
+    let mut net_events = NET_CHANNEL.subscriber().expect("too many NET_CHANNEL subscribers");
+
+    loop {
+        let due = Instant::now() + Duration::from_secs(interval);
+        while let Either::Second(event) = select(Timer::at(due), net_events.next_message_pure()).await {
+            if event == NetEvent::WifiDisconnected {
+                count(&WIFI_DROPS);
+            }
+        }
+
+        //stale telemetry isn't worth keeping - don't let it crowd button events out of the outbox
+        if read_mqtt_addr().is_none() {
+            continue;
+        }
+
+        let net = read_net_status();
+        let ip = net.ipv4.map(|ipv4| ipv4.text());
+        let ip6 = net.ipv6.map(|ipv6| ipv6.text());
+        let telemetry = Telemetry {
+            uptime: Instant::now().as_secs(),
+            heap_free: esp_alloc::HEAP.free(),
+            rssi: sta_rssi(),
+            ip: ip.as_deref(),
+            ip6: ip6.as_deref(),
+            wifi_drops: WIFI_DROPS.load(Ordering::Relaxed),
+            reconnects: MQTT_RECONNECTS.load(Ordering::Relaxed),
+            unresponsive: MQTT_UNRESPONSIVE.load(Ordering::Relaxed),
+            ping_ms: load_rtt(&PING_RTT_MS),
+            ping_avg_ms: load_rtt(&PING_RTT_AVG_MS),
+            ping_max_ms: load_rtt(&PING_RTT_MAX_MS),
+            pings_missed: PINGS_MISSED.load(Ordering::Relaxed),
+            rx: MSGS_RECEIVED.load(Ordering::Relaxed),
+            rx_dropped: MSGS_DROPPED.load(Ordering::Relaxed),
+            rx_limited: MSGS_LIMITED.load(Ordering::Relaxed),
+            rx_deferred: MSGS_DEFERRED.load(Ordering::Relaxed),
+            tx_dropped: critical_section::with(|cs| OUTBOX.borrow_ref(cs).dropped()),
+            refreshes: DISPLAY_REFRESHES.load(Ordering::Relaxed),
+            refresh_ms: LAST_REFRESH_MS.load(Ordering::Relaxed),
+        };
+
+        match serde_json_core::to_slice(&telemetry, &mut buf) {
+            Ok(len) => queue_publish(TELEMETRY_TOPIC, &buf[..len], JSON_CONTENT_TYPE, QualityOfService::QoS0, false),
+            Err(e) => println!("telemetry serialisation error: {}", e),
+        }
+    }
+}
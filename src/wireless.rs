@@ -1,41 +1,110 @@
-use embassy_net::{Runner, Stack, StackResources};
-use embassy_time::{Duration, Timer};
-use esp_hal::{peripherals::{RADIO_CLK, RNG, TIMG0, WIFI}, rng::Rng, timer::timg::TimerGroup};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_futures::select::{select, Either};
+use embassy_net::{raw::{PacketMetadata, RawSocket}, ConfigV4, ConfigV6, HardwareAddress, Ipv4Address, Ipv4Cidr, Ipv6Cidr, Runner, Stack, StackResources, StaticConfigV4, StaticConfigV6};
+use heapless::Vec;
+use embassy_time::{Duration, Instant, Timer};
+use smoltcp::wire::{IpProtocol, IpVersion};
+use esp_hal::{peripherals::{RADIO_CLK, TIMG0, WIFI}, rng::Rng, timer::timg::TimerGroup};
 use esp_println::{print, println};
-use esp_wifi::{init, wifi::{ClientConfiguration, Configuration, WifiController, WifiDevice, WifiEvent, WifiStaDevice, WifiState}, EspWifiController};
+use esp_wifi::{init, wifi::{AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration, WifiApDevice, WifiController, WifiDevice, WifiEvent, WifiStaDevice, WifiState}, EspWifiController};
 use embassy_net::Config as EmbassyNetConfig;
 
 //#[macro_use]
 use super::mk_static;
+use crate::{read_net_status, write_net_status, NET_CHANNEL};
+use crate::provision::{self, settings::MAX_NETWORKS};
 
+pub mod ipv6;
+pub mod networks;
+pub mod status;
+use networks::Candidate;
+use status::{Addressing, Cidr, NetStatus};
 
-pub fn start_wifi<'a>(
-    wifi: WIFI, 
-    clock: RADIO_CLK , 
-    rng: RNG, 
-    timg0:  TimerGroup<TIMG0>
-) -> (WifiController<'static>, Stack<'static>, Runner<'static, WifiDevice<'static, WifiStaDevice>>) {
+//rounds of trying every known network that can fail in a row before the settings are assumed wrong
+//and we go back to provisioning
+const WIFI_MAX_FAILURES: u32 = 12;
+//access points a scan reports (the strongest)
+const SCAN_MAX: usize = 16;
 
-    let mut rng = Rng::new(rng);
+//whether the network we joined last has a static address (otherwise DHCP)
+static STATIC_IP: AtomicBool = AtomicBool::new(false);
 
-    let init = &*mk_static::mk_static!(
+//ipv6: "slaac" (or unset), "off", or a static address/prefix - see wireless/ipv6.rs
+const IPV6: Option<&str> = option_env!("IPV6");
+const IPV6_GATEWAY: Option<&str> = option_env!("IPV6_GATEWAY");
+const IPV6_DNS: Option<&str> = option_env!("IPV6_DNS");
+//how often to ask for a router advertisement until one turns up
+const SOLICIT_INTERVAL: Duration = Duration::from_secs(30);
+//how often the monitor looks at the radio and the stack
+const MONITOR_INTERVAL: Duration = Duration::from_millis(250);
+
+fn wifi_init(clock: RADIO_CLK, rng: Rng, timg0: TimerGroup<TIMG0>) -> &'static EspWifiController<'static> {
+    &*mk_static::mk_static!(
         EspWifiController<'static>,
         init(
             timg0.timer0, 
-            rng.clone(),  
+            rng,  
             clock,
         ).unwrap()
-    );
+    )
+}
+
+pub fn start_wifi<'a>(
+    wifi: WIFI, 
+    clock: RADIO_CLK , 
+    mut rng: Rng, 
+    timg0:  TimerGroup<TIMG0>
+) -> (WifiController<'static>, Stack<'static>, Runner<'static, WifiDevice<'static, WifiStaDevice>>) {
+
+    let init = wifi_init(clock, rng.clone(), timg0);
     let (wifi_interface, wifi_controller) = esp_wifi::wifi::new_with_mode(&init, wifi, WifiStaDevice).unwrap();
     
     /*
         network stack
      */
-    let net_config = EmbassyNetConfig::dhcpv4(Default::default());
+    let mut net_config = EmbassyNetConfig::dhcpv4(Default::default());
+    if let Ok(ipv6::Mode::Static(ipv6)) = ipv6_mode() {
+        net_config.ipv6 = ConfigV6::Static(StaticConfigV6 {
+            address: Ipv6Cidr::new(ipv6.address, ipv6.prefix_len),
+            gateway: ipv6.gateway,
+            dns_servers: ipv6.dns_servers,
+        });
+    }
+    let seed = (rng.random() as u64) << 32 | rng.random() as u64;
+    let (stack, runner) = embassy_net::new(
+        wifi_interface,
+        net_config,
+        //dhcp + dns + mqtt tcp + mdns udp + slaac raw sockets
+        mk_static::mk_static!(StackResources<5>, StackResources::<5>::new()),
+        seed
+    );
+
+    (wifi_controller, stack, runner)
+}
+
+/// The radio as the provisioning access point, at `provision::AP_ADDRESS`
+pub fn start_ap(
+    wifi: WIFI, 
+    clock: RADIO_CLK , 
+    mut rng: Rng, 
+    timg0:  TimerGroup<TIMG0>
+) -> (WifiController<'static>, Stack<'static>, Runner<'static, WifiDevice<'static, WifiApDevice>>) {
+
+    let init = wifi_init(clock, rng.clone(), timg0);
+    let (wifi_interface, wifi_controller) = esp_wifi::wifi::new_with_mode(&init, wifi, WifiApDevice).unwrap();
+
+    let [a, b, c, d] = provision::AP_ADDRESS;
+    let net_config = EmbassyNetConfig::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(Ipv4Address::new(a, b, c, d), 24),
+        gateway: None,
+        dns_servers: Default::default(),
+    });
     let seed = (rng.random() as u64) << 32 | rng.random() as u64;
     let (stack, runner) = embassy_net::new(
         wifi_interface,
         net_config,
+        //portal tcp + dhcp server udp + dns server udp sockets
         mk_static::mk_static!(StackResources<3>, StackResources::<3>::new()),
         seed
     );
@@ -44,11 +113,16 @@ pub fn start_wifi<'a>(
 }
 
 #[embassy_executor::task]
-pub async fn connection(mut controller: WifiController<'static>) {
+pub async fn connection(mut controller: WifiController<'static>, stack: Stack<'static>) {
    // println!("start connection task");
    // println!("Device capabilities: {:?}", controller.capabilities());
-    let ssid = env!("SSID");
-    let passw = env!("PASSW");
+    //main() only starts us when there's something to join
+    let known = provision::wifi_networks();
+    if known.is_empty() {
+        println!("No Wi-Fi networks configured");
+        return;
+    }
+    let mut failures = 0;
     loop {
         //if connected spin here for ever
         match esp_wifi::wifi::wifi_state() {
@@ -59,33 +133,220 @@ pub async fn connection(mut controller: WifiController<'static>) {
             }
             _ => {}
         }
-        //assemble wifi config and start wifi
+        //start wifi as a station (nothing to join yet - scanning comes first)
         if !matches!(controller.is_started(), Ok(true)) {
-            let client_config = Configuration::Client(ClientConfiguration {
-                ssid: ssid.try_into().unwrap(),
-                password: passw.try_into().unwrap(),
-                ..Default::default()
-            });
-            controller.set_configuration(&client_config).unwrap();
+            controller.set_configuration(&Configuration::Client(ClientConfiguration::default())).unwrap();
             println!("Starting wifi");
             controller.start_async().await.unwrap();
             println!("Wifi started!");
         }
-        print!("About to connect...");
 
-        //try establishing connection - if fails
-        //wait 5 seconds and test again
-        match controller.connect_async().await {
-            Ok(_) => println!(" Wifi connected!"),
+        //see which of the networks we know are about - best first, then the rest in case they're hidden
+        let candidates: Vec<Candidate<'static>, MAX_NETWORKS> = match controller.scan_n_async::<SCAN_MAX>().await {
+            Ok((seen, _)) => networks::rank(&known, seen.iter().map(|ap| (ap.ssid.as_str(), ap.signal_strength))),
             Err(e) => {
-                println!(" Wifi connect failed: {e:?}");
-                Timer::after(Duration::from_millis(5000)).await
+                println!("Wifi scan failed: {e:?}");
+                networks::rank(&known, core::iter::empty())
+            }
+        };
+
+        //try each in turn - if fails, move on to the next
+        let mut connected = false;
+        for Candidate { network, rssi } in candidates {
+            match rssi {
+                Some(rssi) => print!("About to connect to {} ({} dBm)...", network.ssid, rssi),
+                None => print!("About to connect to {} (not seen in scan)...", network.ssid),
+            }
+            let (Ok(ssid), Ok(password)) = (network.ssid.try_into(), network.password.try_into()) else {
+                println!(" name or password too long");
+                continue;
+            };
+            let client_config = Configuration::Client(ClientConfiguration { ssid, password, ..Default::default() });
+            if let Err(e) = controller.set_configuration(&client_config) {
+                println!(" Wifi config error: {e:?}");
+                continue;
+            }
+            //each network can have its own addressing - set it before joining so DHCP starts afresh
+            stack.set_config_v4(ipv4_config(network.ipv4));
+            STATIC_IP.store(network.ipv4.is_some(), Ordering::Relaxed);
+            match controller.connect_async().await {
+                Ok(_) => {
+                    println!(" Wifi connected!");
+                    connected = true;
+                    break;
+                },
+                Err(e) => println!(" Wifi connect failed: {e:?}"),
+            }
+        }
+
+        //none of them - wait 5 seconds and scan again
+        if connected {
+            failures = 0;
+        } else {
+            failures += 1;
+            if failures >= WIFI_MAX_FAILURES {
+                provision::request("can't join any of the Wi-Fi networks");
+            }
+            Timer::after(Duration::from_millis(5000)).await
+        }
+    }
+}
+
+fn ipv4_config(ipv4: Option<&provision::settings::StaticIpv4>) -> ConfigV4 {
+    match ipv4 {
+        None => ConfigV4::Dhcp(Default::default()),
+        Some(ipv4) => ConfigV4::Static(StaticConfigV4 {
+            address: Ipv4Cidr::new(Ipv4Address::from(ipv4.address), ipv4.prefix_len),
+            gateway: ipv4.gateway.map(Ipv4Address::from),
+            dns_servers: ipv4.dns_servers.iter().copied().map(Ipv4Address::from).collect(),
+        }),
+    }
+}
+
+/// Where the station's IPv4 address comes from on the network we joined last
+fn addressing() -> Addressing {
+    if STATIC_IP.load(Ordering::Relaxed) { Addressing::Static } else { Addressing::Dhcp }
+}
+
+/// Watches the radio, link and addresses, publishing each change on `NET_CHANNEL`
+#[embassy_executor::task]
+pub async fn monitor_task(stack: Stack<'static>) {
+    let publisher = NET_CHANNEL.immediate_publisher();
+    loop {
+        let now = NetStatus {
+            wifi: matches!(esp_wifi::wifi::wifi_state(), WifiState::StaConnected),
+            link: stack.is_link_up(),
+            ipv4: stack.config_v4().map(|config| Cidr { address: config.address.address(), prefix_len: config.address.prefix_len() }),
+            addressing: addressing(),
+            ipv6: stack.config_v6().map(|config| Cidr { address: config.address.address(), prefix_len: config.address.prefix_len() }),
+        };
+        let events = read_net_status().changes(&now);
+        if !events.is_empty() {
+            //the latest status is in place before anyone hears about the change
+            write_net_status(now);
+            for event in events {
+                println!("Network: {:?}", event);
+                publisher.publish_immediate(event);
+            }
+        }
+        Timer::after(MONITOR_INTERVAL).await;
+    }
+}
+
+fn ipv6_mode() -> Result<ipv6::Mode, ipv6::ModeError> {
+    ipv6::Mode::parse(IPV6, IPV6_GATEWAY, IPV6_DNS)
+}
+
+/// Keeps an IPv6 address from router advertisements, when IPV6 asks for SLAAC
+#[embassy_executor::task]
+pub async fn slaac_task(stack: Stack<'static>) {
+    match ipv6_mode() {
+        Ok(ipv6::Mode::Slaac) => {},
+        Ok(_) => return,
+        Err(e) => {
+            println!("Invalid IPV6 setting ({e:?}) - IPv6 is off");
+            return;
+        }
+    }
+    let mac = match stack.hardware_address() {
+        HardwareAddress::Ethernet(mac) => mac.0,
+        #[allow(unreachable_patterns)]
+        _ => return,
+    };
+
+    let rx_meta = mk_static::mk_static!([PacketMetadata; 2], [PacketMetadata::EMPTY; 2]);
+    let rx_buffer = mk_static::mk_static!([u8; 512], [0; 512]);
+    let tx_meta = mk_static::mk_static!([PacketMetadata; 1], [PacketMetadata::EMPTY; 1]);
+    let tx_buffer = mk_static::mk_static!([u8; ipv6::SOLICITATION_LEN], [0; ipv6::SOLICITATION_LEN]);
+    let socket = RawSocket::new(stack, IpVersion::Ipv6, IpProtocol::Icmpv6, rx_meta, rx_buffer, tx_meta, tx_buffer);
+    let mut packet = [0u8; 512];
+    //when the address we were given runs out
+    let mut expires: Option<Instant> = None;
+
+    loop {
+        //a new network (or none) - start over
+        if !stack.is_link_up() {
+            if expires.take().is_some() {
+                stack.set_config_v6(ConfigV6::None);
+                println!("IPv6 address dropped: link down");
             }
+            stack.wait_link_up().await;
         }
+        if expires.is_none() {
+            _ = socket.send(&ipv6::router_solicitation()).await;
+        }
+
+        let wake = match expires {
+            Some(at) => at.min(Instant::now() + SOLICIT_INTERVAL),
+            None => Instant::now() + SOLICIT_INTERVAL,
+        };
+        let len = match select(socket.recv(&mut packet), Timer::at(wake)).await {
+            Either::First(Ok(len)) => len,
+            Either::First(Err(_)) => continue,
+            Either::Second(_) => {
+                if expires.is_some_and(|at| at <= Instant::now()) {
+                    expires = None;
+                    stack.set_config_v6(ConfigV6::None);
+                    println!("IPv6 address expired");
+                }
+                continue;
+            }
+        };
+        let Some(advert) = ipv6::RouterAdvert::parse(&packet[..len]) else {
+            continue;
+        };
+        let Some((prefix, valid_secs)) = advert.prefix else {
+            continue;
+        };
+
+        let config = StaticConfigV6 {
+            address: Ipv6Cidr::new(ipv6::slaac_address(prefix, mac), ipv6::SLAAC_PREFIX_LEN),
+            gateway: (advert.router_lifetime > 0).then_some(advert.router),
+            dns_servers: advert.dns_servers,
+        };
+        if stack.config_v6().as_ref() != Some(&config) {
+            println!("IPv6 address from router advertisement: {}", config.address);
+            stack.set_config_v6(ConfigV6::Static(config));
+        }
+        expires = Some(Instant::now() + Duration::from_secs(valid_secs as u64));
     }
 }
 
 #[embassy_executor::task]
 pub async fn net_task(mut runner: Runner<'static, WifiDevice<'static, WifiStaDevice>>) {
     runner.run().await
-}
\ No newline at end of file
+}
+
+/// Runs the provisioning access point
+#[embassy_executor::task]
+pub async fn ap_connection(mut controller: WifiController<'static>, ap: &'static provision::AccessPoint) {
+    let ap_config = Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: ap.ssid.clone(),
+        password: ap.password.as_str().try_into().unwrap(),
+        auth_method: AuthMethod::WPA2Personal,
+        ..Default::default()
+    });
+    controller.set_configuration(&ap_config).unwrap();
+    println!("Starting setup access point {}", ap.ssid);
+    controller.start_async().await.unwrap();
+    println!("Setup access point started!");
+    loop {
+        controller.wait_for_event(WifiEvent::ApStaconnected).await;
+        println!("Someone joined the setup network");
+    }
+}
+
+#[embassy_executor::task]
+pub async fn ap_net_task(mut runner: Runner<'static, WifiDevice<'static, WifiApDevice>>) {
+    runner.run().await
+}
+
+/// Signal strength of the AP we're associated with, `None` if we aren't
+pub fn sta_rssi() -> Option<i8> {
+    //esp-wifi doesn't expose this, so ask the driver directly
+    let mut info: esp_wifi_sys::include::wifi_ap_record_t = unsafe { core::mem::zeroed() };
+    match unsafe { esp_wifi_sys::include::esp_wifi_sta_get_ap_info(&mut info) } {
+        0 => Some(info.rssi),
+        _ => None,
+    }
+}
@@ -0,0 +1,202 @@
+use core::net::Ipv6Addr;
+
+use heapless::Vec;
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   IPv6 addressing:
+*
+*           Alongside IPv4, the station can have one IPv6 address, set by IPV6
+*           at compile time:
+*
+*               unset or "slaac"    - stateless autoconfiguration (the default)
+*               "off"               - IPv4 only
+*               "2001:db8::50/64"   - static, with optionally IPV6_GATEWAY and
+*                                     IPV6_DNS (up to 3, comma or space separated)
+*
+*           SLAAC: we ask for a router advertisement (router solicitation to
+*           ff02::2) and listen for them. The first /64 prefix marked for
+*           autonomous configuration plus an interface id made from the MAC
+*           (modified EUI-64) is our address, the router is the gateway and
+*           any RDNSS option gives the DNS servers. The address is dropped when
+*           its valid lifetime runs out without another advertisement.
+*
+*           embassy-net takes a single IPv6 address, so there's no link-local
+*           one once the global address is set - routers find us by that.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+/// as many as embassy-net takes in a static configuration
+pub const MAX_DNS_SERVERS: usize = 3;
+/// SLAAC only works with /64 prefixes
+pub const SLAAC_PREFIX_LEN: u8 = 64;
+/// a router solicitation, IPv6 header and all
+pub const SOLICITATION_LEN: usize = IPV6_HEADER_LEN + 8;
+
+const IPV6_HEADER_LEN: usize = 40;
+const NEXT_HEADER_ICMPV6: u8 = 58;
+/// neighbour discovery must come from the link itself
+const ND_HOP_LIMIT: u8 = 255;
+const ROUTER_SOLICITATION: u8 = 133;
+const ROUTER_ADVERTISEMENT: u8 = 134;
+const OPTION_PREFIX_INFORMATION: u8 = 3;
+const OPTION_RDNSS: u8 = 25;
+const PREFIX_AUTONOMOUS: u8 = 0x40;
+const ALL_ROUTERS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2);
+
+/// How the station gets its IPv6 address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mode {
+    Off,
+    Slaac,
+    Static(StaticIpv6),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaticIpv6 {
+    pub address: Ipv6Addr,
+    pub prefix_len: u8,
+    pub gateway: Option<Ipv6Addr>,
+    pub dns_servers: Vec<Ipv6Addr, MAX_DNS_SERVERS>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModeError {
+    /// IPV6 isn't "off", "slaac" or an address/prefix
+    Address,
+    Gateway,
+    Dns,
+}
+
+impl Mode {
+    /// The mode given by IPV6 (and for a static address IPV6_GATEWAY and IPV6_DNS)
+    pub fn parse(ipv6: Option<&str>, gateway: Option<&str>, dns: Option<&str>) -> Result<Self, ModeError> {
+        let address = match ipv6.map(str::trim) {
+            None | Some("") => return Ok(Self::Slaac),
+            Some(mode) if mode.eq_ignore_ascii_case("slaac") => return Ok(Self::Slaac),
+            Some(mode) if mode.eq_ignore_ascii_case("off") => return Ok(Self::Off),
+            Some(address) => address,
+        };
+        let (address, prefix_len) = address.split_once('/').ok_or(ModeError::Address)?;
+        let address: Ipv6Addr = address.trim().parse().map_err(|_| ModeError::Address)?;
+        let prefix_len: u8 = prefix_len.trim().parse().ok().filter(|len| (1..=128).contains(len)).ok_or(ModeError::Address)?;
+
+        let gateway = match gateway.map(str::trim).unwrap_or_default() {
+            "" => None,
+            gateway => Some(gateway.parse().map_err(|_| ModeError::Gateway)?),
+        };
+        let mut dns_servers = Vec::new();
+        for server in dns.unwrap_or_default().split([' ', ',']).filter(|server| !server.is_empty()) {
+            let server = server.parse().map_err(|_| ModeError::Dns)?;
+            dns_servers.push(server).map_err(|_| ModeError::Dns)?;
+        }
+        Ok(Self::Static(StaticIpv6 { address, prefix_len, gateway, dns_servers }))
+    }
+}
+
+/// The modified EUI-64 interface id for `mac`
+pub fn interface_id(mac: [u8; 6]) -> [u8; 8] {
+    [mac[0] ^ 0x02, mac[1], mac[2], 0xff, 0xfe, mac[3], mac[4], mac[5]]
+}
+
+/// Our address on a /64 `prefix`
+pub fn slaac_address(prefix: Ipv6Addr, mac: [u8; 6]) -> Ipv6Addr {
+    let mut octets = prefix.octets();
+    octets[8..].copy_from_slice(&interface_id(mac));
+    Ipv6Addr::from(octets)
+}
+
+/// The parts of a router advertisement SLAAC cares about
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouterAdvert {
+    /// the router's (link-local) address
+    pub router: Ipv6Addr,
+    /// seconds it can be a default router for, 0 if it isn't one
+    pub router_lifetime: u16,
+    /// the first /64 prefix we may configure an address on, and for how many seconds
+    pub prefix: Option<(Ipv6Addr, u32)>,
+    pub dns_servers: Vec<Ipv6Addr, MAX_DNS_SERVERS>,
+}
+
+impl RouterAdvert {
+    /// Read a router advertisement out of a whole IPv6 packet, None if it isn't a valid one
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() < IPV6_HEADER_LEN || packet[0] >> 4 != 6
+            || packet[6] != NEXT_HEADER_ICMPV6 || packet[7] != ND_HOP_LIMIT {
+            return None;
+        }
+        let payload_len = u16::from_be_bytes([packet[4], packet[5]]) as usize;
+        let router = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[8..24]).ok()?);
+        let icmp = packet.get(IPV6_HEADER_LEN..IPV6_HEADER_LEN + payload_len)?;
+        if icmp.len() < 16 || icmp[0] != ROUTER_ADVERTISEMENT || icmp[1] != 0 || !is_link_local(router) {
+            return None;
+        }
+
+        let mut advert = Self {
+            router,
+            router_lifetime: u16::from_be_bytes([icmp[6], icmp[7]]),
+            prefix: None,
+            dns_servers: Vec::new(),
+        };
+        let mut options = &icmp[16..];
+        while let [kind, len, ..] = *options {
+            let len = len as usize * 8;
+            let option = options.get(..len).filter(|_| len > 0)?;
+            match kind {
+                OPTION_PREFIX_INFORMATION if len == 32 && advert.prefix.is_none() => {
+                    let valid = u32::from_be_bytes([option[4], option[5], option[6], option[7]]);
+                    let prefix = Ipv6Addr::from(<[u8; 16]>::try_from(&option[16..32]).ok()?);
+                    if option[2] == SLAAC_PREFIX_LEN && option[3] & PREFIX_AUTONOMOUS != 0 && valid > 0 && !is_link_local(prefix) {
+                        advert.prefix = Some((prefix, valid));
+                    }
+                },
+                OPTION_RDNSS if len >= 24 => {
+                    for server in option[8..].chunks_exact(16) {
+                        let server = Ipv6Addr::from(<[u8; 16]>::try_from(server).ok()?);
+                        _ = advert.dns_servers.push(server);
+                    }
+                },
+                _ => {},
+            }
+            options = &options[len..];
+        }
+        Some(advert)
+    }
+}
+
+/// A router solicitation from the unspecified address, so routers advertise without waiting
+pub fn router_solicitation() -> [u8; SOLICITATION_LEN] {
+    let mut packet = [0u8; SOLICITATION_LEN];
+    packet[0] = 0x60;
+    packet[4..6].copy_from_slice(&8u16.to_be_bytes());
+    packet[6] = NEXT_HEADER_ICMPV6;
+    packet[7] = ND_HOP_LIMIT;
+    packet[24..40].copy_from_slice(&ALL_ROUTERS.octets());
+    packet[IPV6_HEADER_LEN] = ROUTER_SOLICITATION;
+    let checksum = icmp_checksum(&packet[8..24], &packet[24..40], &packet[IPV6_HEADER_LEN..]);
+    packet[IPV6_HEADER_LEN + 2..IPV6_HEADER_LEN + 4].copy_from_slice(&checksum.to_be_bytes());
+    packet
+}
+
+fn is_link_local(address: Ipv6Addr) -> bool {
+    address.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// The ICMPv6 checksum: over a pseudo-header of the addresses, length and next header, then the message
+fn icmp_checksum(source: &[u8], destination: &[u8], message: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    let len = (message.len() as u32).to_be_bytes();
+    let pseudo = [0, 0, 0, NEXT_HEADER_ICMPV6];
+    for chunk in [source, destination, &len, &pseudo, message] {
+        for word in chunk.chunks(2) {
+            sum += u16::from_be_bytes([word[0], word.get(1).copied().unwrap_or(0)]) as u32;
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
@@ -0,0 +1,92 @@
+use heapless::Vec;
+
+use crate::provision::settings::StaticIpv4;
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   Known Wi-Fi networks:
+*
+*           Which of the networks we know about to try joining, and in what order.
+*           A scan says what's about; those we know come first - highest priority,
+*           then strongest signal - followed by any known ones the scan didn't turn
+*           up (hidden networks don't answer scans), by priority.
+*
+*           Built in with WIFI_NETWORKS, one per line or comma separated:
+*
+*               ssid[:password[:priority]]
+*
+*           priority defaults to 0 (higher is preferred). If the password itself
+*           has a ':' in it, give a priority as well. These use DHCP - static
+*           addressing is only set up through provisioning mode.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network<'a> {
+    pub ssid: &'a str,
+    /// empty for an open network
+    pub password: &'a str,
+    /// higher is preferred
+    pub priority: u8,
+    /// None to use DHCP
+    pub ipv4: Option<&'a StaticIpv4>,
+}
+
+/// A network worth trying, and its signal strength if the scan saw it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate<'a> {
+    pub network: Network<'a>,
+    pub rssi: Option<i8>,
+}
+
+impl<'a> Network<'a> {
+    /// Parse one `ssid[:password[:priority]]` entry
+    pub fn parse(entry: &'a str) -> Option<Self> {
+        let entry = entry.trim_matches(['\r', '\n']);
+        let (ssid, rest) = entry.split_once(':').unwrap_or((entry, ""));
+        if ssid.trim().is_empty() {
+            return None;
+        }
+        let (password, priority) = match rest.rsplit_once(':') {
+            Some((password, priority)) => match priority.trim().parse() {
+                Ok(priority) => (password, priority),
+                Err(_) => (rest, 0),
+            },
+            None => (rest, 0),
+        };
+        Some(Self { ssid: ssid.trim(), password, priority, ipv4: None })
+    }
+}
+
+/// The known networks in a WIFI_NETWORKS list (as many as fit, the rest are left out)
+pub fn parse_list<const N: usize>(list: &str) -> Vec<Network<'_>, N> {
+    list.split([',', '\n']).filter_map(Network::parse).take(N).collect()
+}
+
+/// The order to try `known` networks in, given what a scan `seen` (ssid and rssi of each access point)
+pub fn rank<'a, 's, const N: usize>(known: &[Network<'a>], seen: impl IntoIterator<Item = (&'s str, i8)>) -> Vec<Candidate<'a>, N> {
+    let mut candidates: Vec<(usize, Candidate<'a>), N> = known
+        .iter()
+        .take(N)
+        .enumerate()
+        .map(|(at, network)| (at, Candidate { network: *network, rssi: None }))
+        .collect();
+    //a network with several access points counts as its strongest
+    for (ssid, rssi) in seen {
+        for (_, candidate) in candidates.iter_mut().filter(|(_, candidate)| candidate.network.ssid == ssid) {
+            candidate.rssi = Some(candidate.rssi.map_or(rssi, |best| best.max(rssi)));
+        }
+    }
+
+    //seen before unseen, then priority, then signal, then the order they were given in
+    candidates.sort_unstable_by(|(a_at, a), (b_at, b)| {
+        b.rssi.is_some().cmp(&a.rssi.is_some())
+            .then(b.network.priority.cmp(&a.network.priority))
+            .then(b.rssi.cmp(&a.rssi))
+            .then(a_at.cmp(b_at))
+    });
+    candidates.into_iter().map(|(_, candidate)| candidate).collect()
+}
@@ -0,0 +1,145 @@
+use core::{fmt::{self, Write}, net::{Ipv4Addr, Ipv6Addr}};
+
+use heapless::{String, Vec};
+
+/*
+* -------------------------------------------------------------------------------------------------
+*
+*
+*                   Network status:
+*
+*           What the monitor task last saw of the radio and the network stack -
+*           whether we're associated with an access point, whether the link is
+*           up, and the IPv4 (DHCP lease or static) and IPv6 addresses.
+*
+*           Each time it looks, the differences from last time go out as
+*           events, in order: Wi-Fi, link, IPv4, IPv6. The latest status can be
+*           read at any time without taking it from anyone else.
+*
+* --------------------------------------------------------------------------------------------------
+*/
+
+/// at most one event per part of the status
+pub const MAX_EVENTS: usize = 4;
+/// room for the longest address/prefix (IPv6)
+pub const CIDR_TEXT_LEN: usize = 43;
+/// room for the display's address line
+pub const SUMMARY_LEN: usize = 48;
+
+/// An address and its prefix length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr<A> {
+    pub address: A,
+    pub prefix_len: u8,
+}
+
+impl<A: fmt::Display> fmt::Display for Cidr<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+impl<A: fmt::Display> Cidr<A> {
+    /// As "address/prefix", for JSON
+    pub fn text(&self) -> String<CIDR_TEXT_LEN> {
+        let mut text = String::new();
+        _ = write!(text, "{}", self);
+        text
+    }
+}
+
+/// Where the IPv4 address came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Addressing {
+    #[default]
+    Dhcp,
+    Static,
+}
+
+impl fmt::Display for Addressing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Dhcp => write!(f, "DHCP"),
+            Self::Static => write!(f, "static"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NetStatus {
+    /// associated with an access point
+    pub wifi: bool,
+    /// the stack sees the link as up
+    pub link: bool,
+    pub ipv4: Option<Cidr<Ipv4Addr>>,
+    pub addressing: Addressing,
+    pub ipv6: Option<Cidr<Ipv6Addr>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetEvent {
+    WifiConnected,
+    WifiDisconnected,
+    LinkUp,
+    LinkDown,
+    /// an IPv4 address arrived (a DHCP lease, or a static address) or changed
+    Ipv4Up(Cidr<Ipv4Addr>, Addressing),
+    /// the IPv4 address went (the lease lapsed, or the link did)
+    Ipv4Down,
+    Ipv6Up(Cidr<Ipv6Addr>),
+    Ipv6Down,
+}
+
+impl NetEvent {
+    /// Whether the event changes an address (rather than the radio or link underneath)
+    pub fn is_address(&self) -> bool {
+        matches!(self, Self::Ipv4Up(..) | Self::Ipv4Down | Self::Ipv6Up(_) | Self::Ipv6Down)
+    }
+}
+
+impl NetStatus {
+    /// Nothing connected - how things are at boot
+    pub const DOWN: Self = Self { wifi: false, link: false, ipv4: None, addressing: Addressing::Dhcp, ipv6: None };
+
+    /// The events that get from this status to `now`
+    pub fn changes(&self, now: &Self) -> Vec<NetEvent, MAX_EVENTS> {
+        let mut events = Vec::new();
+        if self.wifi != now.wifi {
+            _ = events.push(if now.wifi { NetEvent::WifiConnected } else { NetEvent::WifiDisconnected });
+        }
+        if self.link != now.link {
+            _ = events.push(if now.link { NetEvent::LinkUp } else { NetEvent::LinkDown });
+        }
+        if (self.ipv4, self.addressing) != (now.ipv4, now.addressing) {
+            match now.ipv4 {
+                Some(ipv4) => _ = events.push(NetEvent::Ipv4Up(ipv4, now.addressing)),
+                None if self.ipv4.is_some() => _ = events.push(NetEvent::Ipv4Down),
+                None => {},
+            }
+        }
+        if self.ipv6 != now.ipv6 {
+            _ = events.push(match now.ipv6 {
+                Some(ipv6) => NetEvent::Ipv6Up(ipv6),
+                None => NetEvent::Ipv6Down,
+            });
+        }
+        events
+    }
+
+    /// Usable: the link is up with an address on it
+    pub fn is_up(&self) -> bool {
+        self.link && (self.ipv4.is_some() || self.ipv6.is_some())
+    }
+
+    /// The display's address line: the IPv4 address and where it came from, else the IPv6 one
+    pub fn summary(&self) -> String<SUMMARY_LEN> {
+        let mut text = String::new();
+        _ = match (self.ipv4, self.ipv6) {
+            (Some(ipv4), _) => write!(text, "{} {}", ipv4, self.addressing),
+            (None, Some(ipv6)) => write!(text, "{}", ipv6),
+            (None, None) if self.wifi => write!(text, "None"),
+            (None, None) => write!(text, "No Wi-Fi"),
+        };
+        text
+    }
+}